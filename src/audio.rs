@@ -1,37 +1,49 @@
 use anyhow::{Context, anyhow};
 use indicatif::ProgressBar;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     fs, io,
     path::{Path, PathBuf},
-    thread::sleep,
     time::Duration,
 };
 
-use crate::{file_utils, progress};
-
-static SUPPORTED_AUDIO_EXTENSIONS: &[&str] = &[
-    "flac", // Free Lossless Audio Codec
-];
+use crate::{collision, file_utils, manifest, mirror, plan, progress, tags, template};
 
 static OTHER_METADATA_TRACK_NUMBER_KEY_NAMES: &[&str] = &["TRACK"];
+static DATE_KEY_NAMES: &[&str] = &["DATE", "ORIGINALDATE", "YEAR"];
+static TOTAL_TRACKS_KEY_NAMES: &[&str] = &["TRACKTOTAL", "TOTALTRACKS"];
+static TOTAL_DISCS_KEY_NAMES: &[&str] = &["DISCTOTAL", "TOTALDISCS"];
+static COMPILATION_VALUES: &[&str] = &["1", "true", "yes"];
+const VARIOUS_ARTISTS: &str = "Various Artists";
 
 #[derive(Serialize)]
 struct SongsAnalysis {
     artists: Vec<String>,
     albums: Vec<String>,
+    /// Artists, albums, titles and paths spelled in more than one Unicode normalization form,
+    /// e.g. composed in some files and decomposed (as written by macOS) in others
+    mixed_normalization: Vec<String>,
     missing_song_info: MissingSongInfo,
     misc: MiscSongInfo,
+    audio_quality: AudioQualityInfo,
     song_metadata: Vec<SongMetadata>,
 }
 
 impl SongsAnalysis {
-    fn from_song_metadata(song_metadata: Vec<SongMetadata>) -> Self {
+    fn from_song_metadata(
+        song_metadata: Vec<SongMetadata>,
+        normalization: file_utils::Normalization,
+    ) -> Self {
         let (artist, title, album, disc_number, track_number) =
             song_metadata.iter().fold((0, 0, 0, 0, 0), |acc, metadata| {
                 (
-                    acc.0 + u32::from(metadata.artist.is_none()),
+                    // The album artist falls back to "Various Artists", count the missing tags
+                    acc.0
+                        + u32::from(
+                            metadata.track_artist.is_none()
+                                && metadata.first_tag("ALBUMARTIST").is_none(),
+                        ),
                     acc.1 + u32::from(metadata.title.is_none()),
                     acc.2 + u32::from(metadata.album.is_none()),
                     acc.3 + u32::from(metadata.disc_number.is_none()),
@@ -43,11 +55,8 @@ impl SongsAnalysis {
             .iter()
             .filter(|metadata| metadata.album.is_some())
             .map(|metadata| {
-                let album = metadata.album.clone().unwrap();
-                let artist = metadata
-                    .artist
-                    .clone()
-                    .unwrap_or_else(|| "Unknown artist".to_string());
+                let album = normalization.apply(metadata.album.as_deref().unwrap());
+                let artist = normalization.apply(&metadata.artist);
                 (artist, album)
             })
             .collect::<BTreeSet<(String, String)>>()
@@ -58,11 +67,12 @@ impl SongsAnalysis {
         Self {
             artists: song_metadata
                 .iter()
-                .filter_map(|val| val.artist.clone())
+                .map(|val| normalization.apply(&val.artist))
                 .collect::<BTreeSet<_>>()
                 .into_iter()
                 .collect(),
             albums,
+            mixed_normalization: find_mixed_normalization(&song_metadata, normalization),
             missing_song_info: MissingSongInfo {
                 artist,
                 title,
@@ -81,11 +91,48 @@ impl SongsAnalysis {
                     .max()
                     .unwrap_or(0),
             },
+            audio_quality: AudioQualityInfo::from_song_metadata(&song_metadata, normalization),
             song_metadata,
         }
     }
 }
 
+/// Finds the values that are spelled in more than one normalization form in the library, or
+/// that mix composed and decomposed characters themselves. They are listed in `normalization`.
+fn find_mixed_normalization(
+    song_metadata: &[SongMetadata],
+    normalization: file_utils::Normalization,
+) -> Vec<String> {
+    let mut spellings: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for metadata in song_metadata {
+        let filepath = metadata.filepath.to_string_lossy();
+        let values = [
+            Some(metadata.artist.as_str()),
+            metadata.track_artist.as_deref(),
+            metadata.album.as_deref(),
+            metadata.title.as_deref(),
+            Some(filepath.as_ref()),
+        ];
+        for value in values.into_iter().flatten() {
+            spellings
+                .entry(normalization.apply(value))
+                .or_default()
+                .insert(value.to_string());
+        }
+    }
+
+    spellings
+        .into_iter()
+        .filter(|(_, values)| {
+            values.len() > 1
+                || values.iter().any(|value| {
+                    !unicode_normalization::is_nfc(value) && !unicode_normalization::is_nfd(value)
+                })
+        })
+        .map(|(key, _)| key)
+        .collect()
+}
+
 #[derive(Serialize)]
 struct MissingSongInfo {
     artist: u32,
@@ -100,44 +147,210 @@ struct MiscSongInfo {
     most_digits_in_track_number: u32,
 }
 
+#[derive(Serialize, Default)]
+struct AudioQualityInfo {
+    total_duration_secs: f64,
+    total_file_size: u64,
+    /// Lossless songs above 16 bit / 44.1 kHz
+    hi_res: u32,
+    /// Lossless songs at exactly 16 bit / 44.1 kHz
+    cd_quality: u32,
+    below_cd_quality: u32,
+    lossy: u32,
+    unknown: u32,
+    hi_res_albums: Vec<String>,
+}
+
+impl AudioQualityInfo {
+    fn from_song_metadata(
+        song_metadata: &[SongMetadata],
+        normalization: file_utils::Normalization,
+    ) -> Self {
+        let mut info = Self::default();
+        let mut hi_res_albums = BTreeSet::new();
+
+        for metadata in song_metadata {
+            info.total_duration_secs += metadata.duration_secs.unwrap_or(0.0);
+            info.total_file_size += metadata.file_size;
+            match metadata.quality() {
+                AudioQuality::HiRes => {
+                    info.hi_res += 1;
+                    hi_res_albums.extend(
+                        metadata
+                            .album_label()
+                            .map(|label| normalization.apply(&label)),
+                    );
+                }
+                AudioQuality::CdQuality => info.cd_quality += 1,
+                AudioQuality::BelowCdQuality => info.below_cd_quality += 1,
+                AudioQuality::Lossy => info.lossy += 1,
+                AudioQuality::Unknown => info.unknown += 1,
+            }
+        }
+        info.hi_res_albums = hi_res_albums.into_iter().collect();
+
+        info
+    }
+}
+
+enum AudioQuality {
+    HiRes,
+    CdQuality,
+    BelowCdQuality,
+    Lossy,
+    Unknown,
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Clone)]
 struct SongMetadata {
-    artist: Option<String>,
+    /// The album artist: `ALBUMARTIST`, "Various Artists" for compilations, or `ARTIST`, and
+    /// "Various Artists" for songs without either tag
+    artist: String,
+    track_artist: Option<String>,
+    compilation: bool,
     title: Option<String>,
     album: Option<String>,
     disc_number: Option<u32>,
     track_number: Option<u32>,
+    duration_secs: Option<f64>,
+    sample_rate: Option<u32>,
+    bit_depth: Option<u8>,
+    channels: Option<u8>,
+    file_size: u64,
     filepath: PathBuf,
+    /// Every tag field keyed by its uppercase name, for the `tags.KEY` template variables
+    #[serde(skip)]
+    tags: tags::TagMap,
 }
 
 impl SongMetadata {
     fn from_file(filepath: &Path) -> anyhow::Result<Self> {
-        let tag = metaflac::Tag::read_from_path(filepath).with_context(|| {
-            anyhow!(
-                "Unable to read '{}' metadata",
-                filepath.to_str().unwrap_or("unknown")
-            )
-        })?;
+        let tag = tags::read_from_path(filepath)?;
+        let properties = tag.properties();
+        let mut all_tags = tags::TagMap::new();
+        for (key, values) in tag.to_map() {
+            all_tags
+                .entry(key.to_uppercase())
+                .or_default()
+                .extend(values);
+        }
 
-        let get_entry_from_tag = |key: &str| -> Option<String> {
-            tag.get_vorbis(key)
-                .and_then(|mut entries| entries.next())
-                .map(str::to_string)
-        };
+        let track_artist = tag.get_first("ARTIST");
+        let compilation = tag.get_first("COMPILATION").is_some_and(|val| {
+            COMPILATION_VALUES
+                .iter()
+                .any(|value| val.trim().eq_ignore_ascii_case(value))
+        });
+        let artist = tag
+            .get_first("ALBUMARTIST")
+            .or_else(|| compilation.then(|| VARIOUS_ARTISTS.to_string()))
+            .or_else(|| track_artist.clone())
+            .unwrap_or_else(|| VARIOUS_ARTISTS.to_string());
 
         Ok(Self {
             filepath: filepath.to_path_buf(),
-            artist: get_entry_from_tag("ALBUMARTIST"),
-            title: get_entry_from_tag("TITLE"),
-            album: get_entry_from_tag("ALBUM"),
-            disc_number: get_entry_from_tag("DISCNUMBER").and_then(|val| val.parse::<u32>().ok()),
-            track_number: get_entry_from_tag("TRACKNUMBER")
-                .and_then(|val| Self::parse_track_number(&val)),
+            artist,
+            track_artist,
+            compilation,
+            title: tag.get_first("TITLE"),
+            album: tag.get_first("ALBUM"),
+            disc_number: tag
+                .get_first("DISCNUMBER")
+                .and_then(|val| Self::parse_leading_number(&val)),
+            track_number: tag
+                .get_first("TRACKNUMBER")
+                .and_then(|val| Self::parse_leading_number(&val)),
+            duration_secs: properties.duration_secs,
+            sample_rate: properties.sample_rate,
+            bit_depth: properties.bit_depth,
+            channels: properties.channels,
+            file_size: fs::metadata(filepath)?.len(),
+            tags: all_tags,
         })
     }
 
-    fn parse_track_number(val: &str) -> Option<u32> {
+    /// Marks the albums of `songs` without an album artist whose tracks have different
+    /// artists as compilations, so they stay together under "Various Artists" instead of being
+    /// split per track artist. `songs` are the songs of one directory.
+    fn detect_compilations(songs: &mut [Self]) {
+        let mut track_artists_by_album: HashMap<&str, BTreeSet<&str>> = HashMap::new();
+        for song in songs.iter() {
+            if let (None, Some(album), Some(track_artist)) = (
+                song.first_tag("ALBUMARTIST"),
+                song.album.as_deref(),
+                song.track_artist.as_deref(),
+            ) {
+                track_artists_by_album
+                    .entry(album)
+                    .or_default()
+                    .insert(track_artist);
+            }
+        }
+        let compilations: BTreeSet<String> = track_artists_by_album
+            .into_iter()
+            .filter(|(_, track_artists)| track_artists.len() > 1)
+            .map(|(album, _)| album.to_string())
+            .collect();
+
+        for song in songs {
+            if song.first_tag("ALBUMARTIST").is_none()
+                && song
+                    .album
+                    .as_ref()
+                    .is_some_and(|album| compilations.contains(album))
+            {
+                song.artist = VARIOUS_ARTISTS.to_string();
+                song.compilation = true;
+            }
+        }
+    }
+
+    fn first_tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .get(key)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+
+    /// The year of the first date tag that starts with one.
+    fn year(&self) -> Option<String> {
+        DATE_KEY_NAMES.iter().find_map(|key| {
+            let year: String = self.first_tag(key)?.chars().take(4).collect();
+            (year.len() == 4 && year.chars().all(|c| c.is_ascii_digit())).then_some(year)
+        })
+    }
+
+    /// The total from the first of `total_keys`, or from a `number/total` value of `number_key`.
+    fn total(&self, total_keys: &[&str], number_key: &str) -> Option<u32> {
+        total_keys
+            .iter()
+            .find_map(|key| Self::parse_leading_number(self.first_tag(key)?))
+            .or_else(|| {
+                let (_, total) = self.first_tag(number_key)?.split_once('/')?;
+                Self::parse_leading_number(total.trim())
+            })
+    }
+
+    fn album_label(&self) -> Option<String> {
+        let album = self.album.as_deref()?;
+
+        Some(format!("{album} ({})", self.artist))
+    }
+
+    const fn quality(&self) -> AudioQuality {
+        match (self.sample_rate, self.bit_depth) {
+            (None, _) => AudioQuality::Unknown,
+            (Some(_), None) => AudioQuality::Lossy,
+            (Some(44_100), Some(16)) => AudioQuality::CdQuality,
+            (Some(sample_rate), Some(bit_depth)) if sample_rate > 44_100 || bit_depth > 16 => {
+                AudioQuality::HiRes
+            }
+            _ => AudioQuality::BelowCdQuality,
+        }
+    }
+
+    fn parse_leading_number(val: &str) -> Option<u32> {
         let num_part_len = val.chars().take_while(char::is_ascii_digit).count();
         if num_part_len == 0 {
             return None;
@@ -148,35 +361,31 @@ impl SongMetadata {
     }
 }
 
-type AllSongMetadata = HashMap<String, Vec<String>>;
+type AllSongMetadata = tags::TagMap;
 
 fn get_all_song_metadata_from_file(filepath: &Path) -> anyhow::Result<AllSongMetadata> {
-    let tag = metaflac::Tag::read_from_path(filepath).with_context(|| {
-        anyhow!(
-            "Unable to read '{}' metadata",
-            filepath.to_str().unwrap_or("unknown")
-        )
-    })?;
+    let tag = tags::read_from_path(filepath)?;
 
-    Ok(tag
-        .vorbis_comments()
-        .map(|c| c.comments.clone())
-        .unwrap_or_default())
+    Ok(tag.to_map())
 }
 
-pub fn start_analyze_music(src: &Path, output: &Path) -> anyhow::Result<()> {
+pub fn start_analyze_music(
+    src: &Path,
+    output: &Path,
+    normalization: file_utils::Normalization,
+) -> anyhow::Result<()> {
     if src.is_file() {
         let song_metadata = SongMetadata::from_file(src)?;
-        store_song_metadata(vec![song_metadata], output)?;
+        store_song_metadata(vec![song_metadata], output, normalization)?;
         return Ok(());
     }
 
-    let file_count = file_utils::count_files_by_extension(src, SUPPORTED_AUDIO_EXTENSIONS)?;
+    let file_count = file_utils::count_files_by_extension(src, &tags::supported_extensions())?;
     let bar = progress::get_progress_bar(file_count);
 
     let results: Vec<_> = analyze_music(src, &bar)?;
     bar.finish();
-    store_song_metadata(results, output)?;
+    store_song_metadata(results, output, normalization)?;
 
     Ok(())
 }
@@ -184,13 +393,14 @@ pub fn start_analyze_music(src: &Path, output: &Path) -> anyhow::Result<()> {
 fn analyze_music(dir: &Path, bar: &ProgressBar) -> anyhow::Result<Vec<SongMetadata>> {
     let mut results = vec![];
 
-    let (audio_files, dirs) = file_utils::walk_directory(dir, SUPPORTED_AUDIO_EXTENSIONS)?;
+    let (audio_files, dirs) = file_utils::walk_directory(dir, &tags::supported_extensions())?;
 
     for f in audio_files {
         let song_metadata = SongMetadata::from_file(&f)?;
         results.push(song_metadata);
         bar.inc(1);
     }
+    SongMetadata::detect_compilations(&mut results);
 
     for d in &dirs {
         let dir_results = analyze_music(d, bar)?;
@@ -200,8 +410,12 @@ fn analyze_music(dir: &Path, bar: &ProgressBar) -> anyhow::Result<Vec<SongMetada
     Ok(results)
 }
 
-fn store_song_metadata(results: Vec<SongMetadata>, output: &Path) -> anyhow::Result<()> {
-    let analysis: SongsAnalysis = SongsAnalysis::from_song_metadata(results);
+fn store_song_metadata(
+    results: Vec<SongMetadata>,
+    output: &Path,
+    normalization: file_utils::Normalization,
+) -> anyhow::Result<()> {
+    let analysis: SongsAnalysis = SongsAnalysis::from_song_metadata(results, normalization);
     let json_data = serde_json::to_string(&analysis)?;
 
     file_utils::store_data(output, &json_data)
@@ -214,7 +428,7 @@ pub fn start_get_all_metadata(src: &Path, output: &Path) -> anyhow::Result<()> {
         return Ok(());
     }
 
-    let file_count: u64 = file_utils::count_files_by_extension(src, SUPPORTED_AUDIO_EXTENSIONS)?;
+    let file_count: u64 = file_utils::count_files_by_extension(src, &tags::supported_extensions())?;
     let bar = progress::get_progress_bar(file_count);
 
     let results: Vec<_> = get_all_metadata(src, &bar)?;
@@ -227,7 +441,7 @@ pub fn start_get_all_metadata(src: &Path, output: &Path) -> anyhow::Result<()> {
 fn get_all_metadata(dir: &Path, bar: &ProgressBar) -> anyhow::Result<Vec<AllSongMetadata>> {
     let mut results = vec![];
 
-    let (audio_files, dirs) = file_utils::walk_directory(dir, SUPPORTED_AUDIO_EXTENSIONS)?;
+    let (audio_files, dirs) = file_utils::walk_directory(dir, &tags::supported_extensions())?;
 
     for f in audio_files {
         let song_metadata = get_all_song_metadata_from_file(&f)?;
@@ -249,6 +463,9 @@ fn store_all_song_metadata(results: &[AllSongMetadata], output: &Path) -> anyhow
     file_utils::store_data(output, &json_data)
 }
 
+/// The options are stored in the state file, so only the ones that change the plan are
+/// serialized.
+#[derive(Serialize)]
 pub struct StartCopyFileOptions<'a> {
     pub filename_template: &'a str,
     pub dir_template: &'a str,
@@ -256,42 +473,80 @@ pub struct StartCopyFileOptions<'a> {
     pub override_files: bool,
     pub pad_width: usize,
     pub fat_32: bool,
+    pub ascii: Option<AsciiMode>,
+    pub normalization: Option<file_utils::Normalization>,
+    pub verify_retries: Option<u32>,
+    #[serde(skip)]
+    pub dry_run: bool,
+    #[serde(skip)]
+    pub plan_out: Option<&'a Path>,
+    pub image: Option<&'a Path>,
+    #[serde(skip)]
+    pub journal: Option<&'a Path>,
+    #[serde(skip)]
+    pub state_file: Option<&'a Path>,
+    pub sync: SyncOptions,
+    pub collision_strategy: collision::CollisionStrategy,
+    pub collision_report: Option<&'a Path>,
+    /// Gives every destination an 8.3 name and saves the mapping to this path
+    pub short_names_map: Option<&'a Path>,
+}
+
+/// How the destination is kept in sync with the source across runs.
+#[derive(Serialize, Default)]
+pub struct SyncOptions {
+    pub incremental: bool,
+    pub mirror: Option<mirror::MirrorOptions>,
 }
 
 struct CopyFileOptions {
-    filename_template: mustache::Template,
-    dir_template: mustache::Template,
-    delay_ms: u64,
-    override_files: bool,
+    filename_template: template::Template,
+    dir_template: template::Template,
     pad_width: usize,
     fat_32: bool,
+    ascii: bool,
+    normalization: Option<file_utils::Normalization>,
 }
 
-impl<'a> From<&StartCopyFileOptions<'a>> for CopyFileOptions {
-    fn from(options: &StartCopyFileOptions<'a>) -> Self {
-        let filename_template = mustache::compile_str(options.filename_template)
-            .expect("Failed to compile filename template");
-        let dir_template = mustache::compile_str(options.dir_template)
-            .expect("Failed to compile directory template");
+impl<'a> TryFrom<&StartCopyFileOptions<'a>> for CopyFileOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(options: &StartCopyFileOptions<'a>) -> anyhow::Result<Self> {
+        let filename_template =
+            template::Template::compile("--filename-template", options.filename_template)?;
+        let dir_template = template::Template::compile("--dir-template", options.dir_template)?;
+        if let Some(variables) = filename_template.empty_render_variables()? {
+            eprintln!(
+                "Warning: --filename-template renders an empty filename for songs without any of these tags: {}",
+                variables.join(", ")
+            );
+        }
 
-        Self {
+        Ok(Self {
             filename_template,
             dir_template,
-            delay_ms: options.delay_ms,
-            override_files: options.override_files,
             pad_width: options.pad_width,
-            fat_32: options.fat_32,
-        }
+            // Names written to an image must be valid on FAT32
+            fat_32: options.fat_32 || options.image.is_some(),
+            ascii: options.ascii.is_some(),
+            normalization: options.normalization,
+        })
     }
 }
 
-impl<'a> From<StartCopyFileOptions<'a>> for CopyFileOptions {
-    fn from(options: StartCopyFileOptions<'a>) -> Self {
-        Self::from(&options)
+impl<'a> From<&StartCopyFileOptions<'a>> for plan::ExecutionOptions {
+    fn from(options: &StartCopyFileOptions<'a>) -> Self {
+        Self {
+            delay_ms: options.delay_ms,
+            override_files: options.override_files,
+            verify_retries: options.verify_retries,
+            image: options.image.map(Path::to_path_buf),
+        }
     }
 }
 
-#[derive(clap::ValueEnum, Copy, Clone, PartialEq, Eq)]
+#[derive(clap::ValueEnum, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
 pub enum TrackNumberModification {
     None,
     Number,
@@ -299,8 +554,20 @@ pub enum TrackNumberModification {
     IncludeDiscNumber,
 }
 
+/// Which names and values are transliterated to ASCII.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AsciiMode {
+    /// Directories and filenames rendered by the templates
+    Paths,
+    /// Paths and the tag values of the copied songs
+    All,
+}
+
+#[derive(Serialize)]
 pub struct CopyMetadataOptions {
     pub track_number_modification: TrackNumberModification,
+    pub ascii_tags: bool,
 }
 
 pub fn start_copy_music(
@@ -309,35 +576,189 @@ pub fn start_copy_music(
     file_options: &StartCopyFileOptions,
     metadata_options: &CopyMetadataOptions,
 ) -> anyhow::Result<()> {
-    file_utils::validate_dir(src)?;
+    let copy_file_options = CopyFileOptions::try_from(file_options)?;
 
-    let file_count = file_utils::count_files(src)?;
-    let bar = progress::get_progress_bar(file_count);
-    bar.set_message("Copying files...");
+    copy_music(
+        src,
+        dest,
+        file_options,
+        &copy_file_options,
+        metadata_options,
+    )
+}
+
+fn copy_music(
+    src: &Path,
+    dest: &Path,
+    start_options: &StartCopyFileOptions,
+    file_options: &CopyFileOptions,
+    metadata_options: &CopyMetadataOptions,
+) -> anyhow::Result<()> {
+    file_utils::validate_dir(src)?;
 
     let curr_path = Path::new("");
-    let file_options = CopyFileOptions::from(file_options);
+    let mut copy_plan = plan::CopyPlan::new(plan::ExecutionOptions::from(start_options));
+    let dry_run = start_options.dry_run;
+    let plan_out = start_options.plan_out;
+    let journal_path = start_options.journal;
+    let state_path = start_options.state_file;
+    let collision_strategy = start_options.collision_strategy;
+    let collision_report = start_options.collision_report;
+    let short_names_map = start_options.short_names_map;
+    let incremental = start_options.sync.incremental;
+    let mirror_options = start_options.sync.mirror.as_ref();
+
+    let invocation = plan::Invocation::CopyMusic {
+        src: std::path::absolute(src)?,
+        dest: std::path::absolute(dest)?,
+        options: serde_json::to_value((start_options, metadata_options))?,
+    };
+    if !dry_run
+        && plan_out.is_none()
+        && let Some((state, copy_plan)) = state_path
+            .map(|state_path| plan::PlanState::resume(state_path, &invocation))
+            .transpose()?
+            .flatten()
+    {
+        return plan::run(&copy_plan, journal_path, Some(state));
+    }
 
-    let result = copy_music(
+    plan_copy_music(
         (src, dest),
         curr_path,
         curr_path,
-        &file_options,
+        file_options,
         metadata_options,
-        &bar,
-    );
-    bar.finish();
+        &mut copy_plan.operations,
+    )?;
+    collision::resolve_collisions(
+        &mut copy_plan.operations,
+        collision_strategy,
+        collision_report,
+    )?;
+    let short_names = short_names_map
+        .map(|short_names_map| shorten_dests(dest, &mut copy_plan.operations, short_names_map))
+        .transpose()?;
+
+    // Incremental planning runs first, so that mirror mode doesn't delete the files it
+    // leaves in place or moves
+    if incremental {
+        let manifest_update =
+            manifest::plan_incremental_copy(src, dest, &mut copy_plan.operations)?;
+        // Only new or changed files are left, so their old copies are replaced
+        copy_plan.options.override_files = true;
+        copy_plan.manifest = Some(manifest_update);
+    }
 
-    result
+    if let Some(mirror_options) = mirror_options {
+        // The files written by the run itself are never stale
+        let kept_files: Vec<&Path> = [
+            journal_path,
+            state_path,
+            plan_out,
+            collision_report,
+            short_names_map,
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let unchanged_files: Vec<PathBuf> = copy_plan
+            .manifest
+            .iter()
+            .flat_map(manifest::ManifestUpdate::dests)
+            .collect();
+        let stale_entries =
+            mirror::StaleEntries::find(dest, &copy_plan.operations, &unchanged_files, &kept_files)?;
+        if !mirror_options.force {
+            if !dry_run {
+                stale_entries.check_threshold(mirror_options.max_delete_percent)?;
+            }
+            copy_plan.delete_limit = Some(mirror::DeleteLimit {
+                dest: dest.to_path_buf(),
+                max_delete_percent: mirror_options.max_delete_percent,
+            });
+        }
+        copy_plan.operations.extend(stale_entries.into_operations());
+    }
+
+    if let Some(plan_out) = plan_out {
+        let json_data = serde_json::to_string_pretty(&copy_plan)?;
+        file_utils::store_data(plan_out, &json_data)?;
+    }
+    if dry_run {
+        println!("{}", serde_json::to_string_pretty(&copy_plan)?);
+    }
+    if dry_run || plan_out.is_some() {
+        return Ok(());
+    }
+
+    if let (Some(short_names_map), Some(short_names)) = (short_names_map, short_names) {
+        let json_data = serde_json::to_string(&short_names)?;
+        file_utils::store_data(short_names_map, &json_data)?;
+    }
+    let state = state_path
+        .map(|state_path| plan::PlanState::create(state_path, &invocation, &copy_plan))
+        .transpose()?;
+
+    plan::run(&copy_plan, journal_path, state)
 }
 
-fn copy_music(
+#[derive(Serialize, Deserialize)]
+struct ShortName {
+    src: PathBuf,
+    long_dest: PathBuf,
+    dest: PathBuf,
+}
+
+/// Replaces the destinations of the songs and files below `root` with 8.3 names and returns
+/// the source and rendered destination of every short name. The names saved to `map_path`
+/// by the previous run are kept, so adding a song doesn't rename the ones already copied.
+fn shorten_dests(
+    root: &Path,
+    operations: &mut [plan::CopyOperation],
+    map_path: &Path,
+) -> anyhow::Result<Vec<ShortName>> {
+    let mut short_names = file_utils::ShortNames::default();
+    if map_path.exists() {
+        let json_data = fs::read_to_string(map_path)?;
+        let previous_mapping: Vec<ShortName> =
+            serde_json::from_str(&json_data).with_context(|| {
+                format!(
+                    "Invalid short names map '{}'",
+                    map_path.to_str().unwrap_or("unknown")
+                )
+            })?;
+        for short_name in previous_mapping {
+            short_names.insert(root, &short_name.long_dest, &short_name.dest);
+        }
+    }
+
+    let mut mapping = vec![];
+    for operation in operations {
+        if let plan::CopyOperation::Song { src, dest, .. }
+        | plan::CopyOperation::File { src, dest } = operation
+        {
+            let short_dest = short_names.shorten(root, dest);
+            mapping.push(ShortName {
+                src: src.clone(),
+                long_dest: std::mem::replace(dest, short_dest.clone()),
+                dest: short_dest,
+            });
+        }
+    }
+
+    Ok(mapping)
+}
+
+/// Walks the source directory and appends the copy operations in the order in which
+/// the files should be written. Nothing is written to the destination.
+fn plan_copy_music(
     from_to: (&Path, &Path),
     curr_src_dir: &Path,
     curr_template_dir: &Path,
     file_options: &CopyFileOptions,
     metadata_options: &CopyMetadataOptions,
-    bar: &ProgressBar,
+    operations: &mut Vec<plan::CopyOperation>,
 ) -> anyhow::Result<()> {
     let (src, dest) = from_to;
 
@@ -356,13 +777,15 @@ fn copy_music(
         .collect();
     dirs.sort();
 
+    let audio_extensions = tags::supported_extensions();
     let (audio_files, other_files): (Vec<PathBuf>, Vec<PathBuf>) =
-        files.partition(|f| file_utils::file_has_extension(f, SUPPORTED_AUDIO_EXTENSIONS));
+        files.partition(|f| file_utils::file_has_extension(f, &audio_extensions));
 
     let mut songs = audio_files
         .into_iter()
         .map(|path| SongMetadata::from_file(&path))
         .collect::<anyhow::Result<Vec<_>>>()?;
+    SongMetadata::detect_compilations(&mut songs);
 
     songs.sort_by(|a, b| {
         a.disc_number
@@ -370,29 +793,10 @@ fn copy_music(
             .then(a.track_number.cmp(&b.track_number))
     });
 
-    let pad_width = file_options.pad_width;
-
     let mut template_dir = None;
 
-    for song in &songs {
-        let data = mustache::MapBuilder::new()
-            .insert("src_dir", &curr_src_dir.to_str())?
-            .insert("artist", &song.artist)?
-            .insert("title", &song.title)?
-            .insert("album", &song.album)?
-            .insert(
-                "disc_number",
-                &song.disc_number.map(|val| format!("{val:0>pad_width$}")),
-            )?
-            .insert(
-                "track_number",
-                &song.track_number.map(|val| format!("{val:0>pad_width$}")),
-            )?
-            .build();
-        let filename = file_options
-            .filename_template
-            .render_data_to_string(&data)?;
-        let dir = file_options.dir_template.render_data_to_string(&data)?;
+    for song in songs {
+        let (dir, filename) = render_song_templates(&song, curr_src_dir, file_options)?;
         if template_dir.is_none() {
             template_dir = Some(dir.clone());
         }
@@ -406,25 +810,19 @@ fn copy_music(
         dest.push(filename);
         dest.set_extension(extension);
 
-        let dest = file_utils::copy_file(
-            &song.filepath,
-            &dest,
-            file_options.override_files,
-            file_options.fat_32,
-        )?;
-        if let Some(dest) = dest {
-            modify_file_metadata(&dest, song, metadata_options)?;
-            sleep(Duration::from_millis(file_options.delay_ms));
-        }
-
-        bar.inc(1);
+        let tag_changes = plan_tag_changes(&song, metadata_options)?;
+        operations.push(plan::CopyOperation::Song {
+            dest: sanitize_dest(from_to.1, dest, file_options),
+            src: song.filepath,
+            tag_changes,
+        });
     }
 
     let template_dir = template_dir
         .as_deref()
         .map_or(curr_template_dir, |d| Path::new(d));
 
-    for f in &other_files {
+    for f in other_files {
         let os_filename: &std::ffi::OsStr = f
             .file_name()
             .ok_or_else(|| anyhow!("Unexpected error - expected filename but none found"))?;
@@ -432,9 +830,10 @@ fn copy_music(
         dest.push(template_dir);
         dest.push(os_filename);
 
-        file_utils::copy_file(f, &dest, file_options.override_files, file_options.fat_32)?;
-
-        bar.inc(1);
+        operations.push(plan::CopyOperation::File {
+            dest: sanitize_dest(from_to.1, dest, file_options),
+            src: f,
+        });
     }
 
     for d in &dirs {
@@ -445,60 +844,155 @@ fn copy_music(
         next_src_dir.push(last_dir);
         let template_dir = template_dir.join(last_dir);
 
-        copy_music(
+        plan_copy_music(
             (d, dest),
             &next_src_dir,
             &template_dir,
             file_options,
             metadata_options,
-            bar,
+            operations,
         )?;
     }
 
     Ok(())
 }
 
-fn modify_file_metadata(
-    dest: &Path,
-    song_metadata: &SongMetadata,
-    metadata_options: &CopyMetadataOptions,
-) -> anyhow::Result<()> {
-    if metadata_options.track_number_modification == TrackNumberModification::None
-        || song_metadata.track_number.is_none()
-    {
-        return Ok(());
+fn sanitize_dest(root: &Path, dest: PathBuf, file_options: &CopyFileOptions) -> PathBuf {
+    let dest = match file_options.normalization {
+        Some(normalization) => file_utils::normalize_pathbuf(root, &dest, normalization),
+        None => dest,
+    };
+
+    let dest = if file_options.ascii {
+        file_utils::transliterate_pathbuf(root, &dest)
+    } else {
+        dest
+    };
+
+    if file_options.fat_32 {
+        file_utils::sanitize_pathbuf_for_fat32(root, &dest)
+    } else {
+        dest
+    }
+}
+
+/// Renders the directory and filename templates for a song, returns `(dir, filename)`.
+fn render_song_templates(
+    song: &SongMetadata,
+    curr_src_dir: &Path,
+    file_options: &CopyFileOptions,
+) -> anyhow::Result<(String, String)> {
+    let mut variables = template::Variables::default();
+    variables.insert("src_dir", curr_src_dir.to_str());
+    variables.insert("artist", Some(song.artist.as_str()));
+    variables.insert("album_artist", Some(song.artist.as_str()));
+    variables.insert(
+        "track_artist",
+        Some(song.track_artist.as_deref().unwrap_or(&song.artist)),
+    );
+    variables.insert("title", song.title.as_deref());
+    variables.insert("album", song.album.as_deref());
+    variables.insert("disc_number", song.disc_number.map(|n| n.to_string()));
+    variables.insert("track_number", song.track_number.map(|n| n.to_string()));
+    variables.insert("year", song.year());
+    variables.insert(
+        "total_tracks",
+        song.total(TOTAL_TRACKS_KEY_NAMES, "TRACKNUMBER")
+            .map(|n| n.to_string()),
+    );
+    variables.insert(
+        "total_discs",
+        song.total(TOTAL_DISCS_KEY_NAMES, "DISCNUMBER")
+            .map(|n| n.to_string()),
+    );
+    variables.insert("duration", song.duration_secs.and_then(format_duration));
+    variables.insert(
+        "ext",
+        song.filepath.extension().map(|ext| ext.to_string_lossy()),
+    );
+    variables.insert(
+        "filename",
+        song.filepath.file_stem().map(|stem| stem.to_string_lossy()),
+    );
+    // Fields with several values, e.g. multiple genres, are joined
+    for (key, values) in &song.tags {
+        variables.insert(&format!("tags.{key}"), Some(values.join(", ")));
     }
 
-    let mut tag = metaflac::Tag::read_from_path(dest).with_context(|| {
-        anyhow!(
-            "Unable to read '{}' metadata",
-            dest.to_str().unwrap_or("unknown")
-        )
-    })?;
+    let pad_width = file_options.pad_width;
+    let filename = file_options
+        .filename_template
+        .render(&variables, pad_width)?;
+    let dir = file_options.dir_template.render(&variables, pad_width)?;
+    // An empty first variable, e.g. in "{{artist}}/{{album}}", must not make the path
+    // absolute, and tag values like ".." must not lead out of the destination
+    let filename = file_utils::confine_rendered_path(&filename);
+    let dir = file_utils::confine_rendered_path(&dir);
+
+    Ok((dir, filename))
+}
+
+/// Formats a duration as e.g. `3m07s` or `1h02m03s`, which is valid in filenames.
+fn format_duration(duration_secs: f64) -> Option<String> {
+    let secs = Duration::try_from_secs_f64(duration_secs).ok()?.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
 
-    let track_number = song_metadata.track_number.unwrap();
+    Some(if hours > 0 {
+        format!("{hours}h{minutes:02}m{secs:02}s")
+    } else {
+        format!("{minutes}m{secs:02}s")
+    })
+}
+
+fn plan_tag_changes(
+    song_metadata: &SongMetadata,
+    metadata_options: &CopyMetadataOptions,
+) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+    let mut tag_changes = BTreeMap::new();
+
+    let new_track_number =
+        song_metadata.track_number.and_then(|track_number| {
+            match metadata_options.track_number_modification {
+                TrackNumberModification::None => None,
+                TrackNumberModification::Number => Some(track_number.to_string()),
+                TrackNumberModification::PaddedNumber => Some(format!("{track_number:0>2}")),
+                TrackNumberModification::IncludeDiscNumber => {
+                    let padded_number = format!("{track_number:0>2}");
+                    let disc_number = song_metadata.disc_number.unwrap_or(0).to_string();
+                    Some(format!("{disc_number}{padded_number}"))
+                }
+            }
+        });
+    if new_track_number.is_none() && !metadata_options.ascii_tags {
+        return Ok(tag_changes);
+    }
 
-    let new_disc_number = match metadata_options.track_number_modification {
-        TrackNumberModification::None => None,
-        TrackNumberModification::Number => Some(track_number.to_string()),
-        TrackNumberModification::PaddedNumber => Some(format!("{track_number:0>2}")),
-        TrackNumberModification::IncludeDiscNumber => {
-            let padded_number = format!("{track_number:0>2}");
-            let disc_number = song_metadata.disc_number.unwrap_or(0).to_string();
-            Some(format!("{disc_number}{padded_number}"))
+    let tag = tags::read_from_path(&song_metadata.filepath)?;
+    if metadata_options.ascii_tags {
+        for (key, values) in tag.to_map() {
+            if values.iter().any(|value| !value.is_ascii()) {
+                let values = values
+                    .iter()
+                    .map(|value| file_utils::transliterate_string(value))
+                    .collect();
+                tag_changes.insert(key, values);
+            }
         }
     }
-    .unwrap();
 
-    tag.set_vorbis("TRACKNUMBER", vec![&new_disc_number]);
-    for &track_field_name in OTHER_METADATA_TRACK_NUMBER_KEY_NAMES {
-        if tag.get_vorbis(track_field_name).is_some() {
-            tag.set_vorbis(track_field_name, vec![&new_disc_number]);
+    if let Some(new_track_number) = new_track_number {
+        tag_changes.insert(String::from("TRACKNUMBER"), vec![new_track_number.clone()]);
+        for &track_field_name in OTHER_METADATA_TRACK_NUMBER_KEY_NAMES {
+            if tag.get(track_field_name).is_some() {
+                tag_changes.insert(
+                    String::from(track_field_name),
+                    vec![new_track_number.clone()],
+                );
+            }
         }
     }
-    tag.write_to_path(dest)?;
 
-    Ok(())
+    Ok(tag_changes)
 }
 
 pub fn start_unzip_music(
@@ -507,12 +1001,173 @@ pub fn start_unzip_music(
     file_options: &StartCopyFileOptions,
     metadata_options: &CopyMetadataOptions,
 ) -> anyhow::Result<()> {
+    let copy_file_options = CopyFileOptions::try_from(file_options)?;
     file_utils::validate_file(src)?;
 
     let temp_dir = file_utils::create_temp_dir()?;
     file_utils::unzip_file(src, &temp_dir)?;
-    start_copy_music(&temp_dir, dest, file_options, metadata_options)?;
+    copy_music(
+        &temp_dir,
+        dest,
+        file_options,
+        &copy_file_options,
+        metadata_options,
+    )?;
     fs::remove_dir_all(temp_dir)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use id3::TagLike;
+
+    use super::*;
+
+    fn song(album: &str, track_artist: &str, album_artist: Option<&str>) -> SongMetadata {
+        let mut tags = tags::TagMap::new();
+        if let Some(album_artist) = album_artist {
+            tags.insert(String::from("ALBUMARTIST"), vec![album_artist.to_string()]);
+        }
+
+        SongMetadata {
+            artist: album_artist.unwrap_or(track_artist).to_string(),
+            track_artist: Some(track_artist.to_string()),
+            compilation: false,
+            title: None,
+            album: Some(album.to_string()),
+            disc_number: None,
+            track_number: None,
+            duration_secs: None,
+            sample_rate: None,
+            bit_depth: None,
+            channels: None,
+            file_size: 0,
+            filepath: PathBuf::from(format!("{album}.flac")),
+            tags,
+        }
+    }
+
+    fn artists(songs: &[SongMetadata]) -> Vec<(&str, bool)> {
+        songs
+            .iter()
+            .map(|song| (song.artist.as_str(), song.compilation))
+            .collect()
+    }
+
+    #[test]
+    fn reads_numbers_and_totals_of_mp3_files() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let filepath = temp_dir.path().join("song.mp3");
+        // A single MPEG-1 Layer III frame
+        let mut data = vec![0xFF, 0xFB, 0x90, 0x00];
+        data.resize(417, 0);
+        fs::write(&filepath, data).unwrap();
+        let mut tag = id3::Tag::new();
+        tag.set_text("TPE1", "Queen");
+        tag.set_text("TRCK", "3/12");
+        tag.set_text("TPOS", "1/2");
+        tag.write_to_path(&filepath, id3::Version::Id3v23).unwrap();
+
+        let song = SongMetadata::from_file(&filepath).unwrap();
+        assert_eq!(song.artist, "Queen");
+        assert_eq!(song.track_number, Some(3));
+        assert_eq!(song.disc_number, Some(1));
+        assert_eq!(song.total(TOTAL_TRACKS_KEY_NAMES, "TRACKNUMBER"), Some(12));
+        assert_eq!(song.total(TOTAL_DISCS_KEY_NAMES, "DISCNUMBER"), Some(2));
+    }
+
+    #[test]
+    fn transliterates_every_value_of_multi_valued_fields() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let filepath = temp_dir.path().join("song.mp3");
+        let mut data = vec![0xFF, 0xFB, 0x90, 0x00];
+        data.resize(417, 0);
+        fs::write(&filepath, data).unwrap();
+        let mut tag = id3::Tag::new();
+        tag.set_text_values("TPE1", ["Björk", "Sigur Rós"]);
+        tag.set_text_values("TCON", ["Art Pop", "Post-Rock"]);
+        tag.set_text("TIT2", "Stjörnur");
+        tag.write_to_path(&filepath, id3::Version::Id3v24).unwrap();
+
+        let mut song = song("Hits", "Björk", None);
+        song.filepath.clone_from(&filepath);
+        let metadata_options = CopyMetadataOptions {
+            track_number_modification: TrackNumberModification::None,
+            ascii_tags: true,
+        };
+        let tag_changes = plan_tag_changes(&song, &metadata_options).unwrap();
+        assert_eq!(
+            tag_changes,
+            BTreeMap::from([
+                (
+                    String::from("ARTIST"),
+                    vec![String::from("Bjork"), String::from("Sigur Ros")]
+                ),
+                (String::from("TITLE"), vec![String::from("Stjornur")]),
+            ])
+        );
+
+        plan::apply_tag_changes(&filepath, &tag_changes).unwrap();
+        let tag = tags::read_from_path(&filepath).unwrap();
+        assert_eq!(
+            tag.get("ARTIST"),
+            Some(vec![String::from("Bjork"), String::from("Sigur Ros")])
+        );
+        assert_eq!(
+            tag.get("GENRE"),
+            Some(vec![String::from("Art Pop"), String::from("Post-Rock")])
+        );
+    }
+
+    #[test]
+    fn mixed_track_artists_make_a_compilation() {
+        let mut songs = vec![
+            song("Hits", "Queen", None),
+            song("Hits", "ABBA", None),
+            song("Hits", "Queen", None),
+        ];
+
+        SongMetadata::detect_compilations(&mut songs);
+
+        assert_eq!(
+            artists(&songs),
+            [
+                (VARIOUS_ARTISTS, true),
+                (VARIOUS_ARTISTS, true),
+                (VARIOUS_ARTISTS, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn single_track_artist_is_no_compilation() {
+        let mut songs = vec![
+            song("A Night at the Opera", "Queen", None),
+            song("A Night at the Opera", "Queen", None),
+            song("Arrival", "ABBA", None),
+        ];
+
+        SongMetadata::detect_compilations(&mut songs);
+
+        assert_eq!(
+            artists(&songs),
+            [("Queen", false), ("Queen", false), ("ABBA", false)]
+        );
+    }
+
+    #[test]
+    fn album_artist_is_kept() {
+        let mut songs = vec![
+            song("Duets", "Queen", Some("Freddie Mercury")),
+            song("Duets", "Montserrat Caballé", Some("Freddie Mercury")),
+        ];
+
+        SongMetadata::detect_compilations(&mut songs);
+
+        assert_eq!(
+            artists(&songs),
+            [("Freddie Mercury", false), ("Freddie Mercury", false)]
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{file_utils, plan::CopyOperation};
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionStrategy {
+    Error,
+    Counter,
+    Hash,
+}
+
+#[derive(Serialize, Default)]
+struct CollisionReport {
+    collisions: Vec<Collision>,
+}
+
+/// Files that render to the same destination. The first file keeps the destination, the
+/// destinations of the others are the ones after resolving the collision.
+#[derive(Serialize)]
+struct Collision {
+    dest: PathBuf,
+    files: Vec<CollidingFile>,
+}
+
+#[derive(Serialize)]
+struct CollidingFile {
+    src: PathBuf,
+    dest: PathBuf,
+}
+
+/// Finds the songs and files of `operations` that are written to the same destination and
+/// resolves them with `strategy`. Destinations are compared case-insensitively, because
+/// FAT32 and exFAT treat names that only differ in case as the same file, and after NFC
+/// normalization, because composed and decomposed names look the same to the user.
+///
+/// Other files with the same content as a file written to the same destination, e.g. the
+/// same cover art in every disc directory of an album, aren't collisions, they are copied once.
+pub fn resolve_collisions(
+    operations: &mut Vec<CopyOperation>,
+    strategy: CollisionStrategy,
+    report_path: Option<&Path>,
+) -> anyhow::Result<()> {
+    let mut indexes_by_dest: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, operation) in operations.iter().enumerate() {
+        if let CopyOperation::Song { dest, .. } | CopyOperation::File { dest, .. } = operation {
+            indexes_by_dest.entry(dest_key(dest)).or_default().push(i);
+        }
+    }
+
+    let mut duplicate_indexes = HashSet::new();
+    for indexes in indexes_by_dest
+        .values_mut()
+        .filter(|indexes| indexes.len() > 1)
+    {
+        duplicate_indexes.extend(find_duplicate_files(operations, indexes)?);
+        indexes.retain(|i| !duplicate_indexes.contains(i));
+    }
+
+    let mut taken_dests: HashSet<String> = indexes_by_dest.keys().cloned().collect();
+    let mut colliding_indexes: Vec<Vec<usize>> = indexes_by_dest
+        .into_values()
+        .filter(|indexes| indexes.len() > 1)
+        .collect();
+    colliding_indexes.sort_unstable();
+
+    let mut report = CollisionReport::default();
+    for indexes in colliding_indexes {
+        let dest = operations[indexes[0]].dest().to_path_buf();
+        let mut files = vec![];
+        for (n, i) in indexes.into_iter().enumerate() {
+            let operation = &mut operations[i];
+            if n > 0 && strategy != CollisionStrategy::Error {
+                let new_dest = resolved_dest(operation, strategy, &taken_dests)?;
+                taken_dests.insert(dest_key(&new_dest));
+                *operation.dest_mut() = new_dest;
+            }
+            if let CopyOperation::Song { src, dest, .. } | CopyOperation::File { src, dest } =
+                operation
+            {
+                files.push(CollidingFile {
+                    src: src.clone(),
+                    dest: dest.clone(),
+                });
+            }
+        }
+        report.collisions.push(Collision { dest, files });
+    }
+
+    if !duplicate_indexes.is_empty() {
+        *operations = std::mem::take(operations)
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !duplicate_indexes.contains(i))
+            .map(|(_, operation)| operation)
+            .collect();
+    }
+
+    if let Some(report_path) = report_path {
+        let json_data = serde_json::to_string(&report)?;
+        file_utils::store_data(report_path, &json_data)?;
+    }
+
+    if strategy == CollisionStrategy::Error && !report.collisions.is_empty() {
+        let collisions: Vec<String> = report
+            .collisions
+            .iter()
+            .map(|collision| {
+                let srcs: Vec<String> = collision
+                    .files
+                    .iter()
+                    .map(|file| format!("'{}'", file.src.to_str().unwrap_or("unknown")))
+                    .collect();
+                format!(
+                    "'{}' <- {}",
+                    collision.dest.to_str().unwrap_or("unknown"),
+                    srcs.join(", ")
+                )
+            })
+            .collect();
+        return Err(anyhow!(
+            "Found {} destination collisions, use --on-collision counter or hash to rename the files:\n{}",
+            collisions.len(),
+            collisions.join("\n")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the indexes of the other files that have the same content as an earlier other
+/// file in `indexes`. Songs are never duplicates, their tags may be rewritten per song.
+fn find_duplicate_files(
+    operations: &[CopyOperation],
+    indexes: &[usize],
+) -> anyhow::Result<Vec<usize>> {
+    let mut hashes = HashSet::new();
+    let mut duplicate_indexes = vec![];
+    for &i in indexes {
+        if let CopyOperation::File { src, .. } = &operations[i]
+            && !hashes.insert(file_utils::hash_file(src)?)
+        {
+            duplicate_indexes.push(i);
+        }
+    }
+
+    Ok(duplicate_indexes)
+}
+
+fn resolved_dest(
+    operation: &CopyOperation,
+    strategy: CollisionStrategy,
+    taken_dests: &HashSet<String>,
+) -> anyhow::Result<PathBuf> {
+    let dest = operation.dest();
+
+    match strategy {
+        CollisionStrategy::Error => Ok(dest.to_path_buf()),
+        CollisionStrategy::Counter => Ok(with_free_counter(dest, taken_dests)),
+        CollisionStrategy::Hash => {
+            let (CopyOperation::Song { src, .. } | CopyOperation::File { src, .. }) = operation
+            else {
+                return Ok(dest.to_path_buf());
+            };
+            // Hashing the content keeps the name stable across runs
+            let hash = file_utils::hash_file(src)?.to_hex();
+            let new_dest = with_suffix(dest, &format!(" [{}]", &hash[..8]));
+            if !taken_dests.contains(&dest_key(&new_dest)) {
+                return Ok(new_dest);
+            }
+            // Songs with the same content get the same hash, a counter tells them apart
+            Ok(with_free_counter(&new_dest, taken_dests))
+        }
+    }
+}
+
+/// Appends the first counter to `dest` that gives a destination not in `taken_dests`.
+fn with_free_counter(dest: &Path, taken_dests: &HashSet<String>) -> PathBuf {
+    let mut counter = 2;
+    loop {
+        let new_dest = with_suffix(dest, &format!(" ({counter})"));
+        if !taken_dests.contains(&dest_key(&new_dest)) {
+            return new_dest;
+        }
+        counter += 1;
+    }
+}
+
+/// Appends `suffix` to the file stem of `path`, before its extension.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut filename = path.file_stem().unwrap_or_default().to_os_string();
+    filename.push(suffix);
+    if let Some(extension) = path.extension() {
+        filename.push(".");
+        filename.push(extension);
+    }
+
+    path.with_file_name(filename)
+}
+
+fn dest_key(dest: &Path) -> String {
+    file_utils::Normalization::Nfc
+        .apply(&dest.to_string_lossy())
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn song(src: &Path, dest: &str) -> CopyOperation {
+        CopyOperation::Song {
+            src: src.to_path_buf(),
+            dest: PathBuf::from(dest),
+            tag_changes: BTreeMap::new(),
+        }
+    }
+
+    fn file(src: &Path, dest: &str) -> CopyOperation {
+        CopyOperation::File {
+            src: src.to_path_buf(),
+            dest: PathBuf::from(dest),
+        }
+    }
+
+    fn dests(operations: &[CopyOperation]) -> Vec<&Path> {
+        operations.iter().map(CopyOperation::dest).collect()
+    }
+
+    #[test]
+    fn copies_identical_files_once() {
+        let dir = TempDir::new().unwrap();
+        let cover_1 = dir.path().join("cover-1.jpg");
+        let cover_2 = dir.path().join("cover-2.jpg");
+        fs::write(&cover_1, b"cover").unwrap();
+        fs::write(&cover_2, b"cover").unwrap();
+        let mut operations = vec![
+            file(&cover_1, "/dest/Album/cover.jpg"),
+            file(&cover_2, "/dest/Album/Cover.jpg"),
+        ];
+
+        resolve_collisions(&mut operations, CollisionStrategy::Error, None).unwrap();
+
+        assert_eq!(dests(&operations), [Path::new("/dest/Album/cover.jpg")]);
+    }
+
+    #[test]
+    fn errors_on_different_files() {
+        let dir = TempDir::new().unwrap();
+        let cover_1 = dir.path().join("cover-1.jpg");
+        let cover_2 = dir.path().join("cover-2.jpg");
+        fs::write(&cover_1, b"front").unwrap();
+        fs::write(&cover_2, b"back").unwrap();
+        let mut operations = vec![
+            file(&cover_1, "/dest/cover.jpg"),
+            file(&cover_2, "/dest/cover.jpg"),
+        ];
+
+        assert!(resolve_collisions(&mut operations, CollisionStrategy::Error, None).is_err());
+    }
+
+    #[test]
+    fn counts_up_taken_dests() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("song.flac");
+        fs::write(&src, b"song").unwrap();
+        let mut operations = vec![
+            song(&src, "/dest/Song.flac"),
+            song(&src, "/dest/Song (2).flac"),
+            song(&src, "/dest/song.flac"),
+        ];
+
+        resolve_collisions(&mut operations, CollisionStrategy::Counter, None).unwrap();
+
+        assert_eq!(
+            dests(&operations),
+            [
+                Path::new("/dest/Song.flac"),
+                Path::new("/dest/Song (2).flac"),
+                Path::new("/dest/song (3).flac")
+            ]
+        );
+    }
+
+    #[test]
+    fn hashes_songs_with_the_same_content_apart() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("song.flac");
+        fs::write(&src, b"song").unwrap();
+        let hash = &file_utils::hash_file(&src).unwrap().to_hex()[..8];
+        let mut operations = vec![
+            song(&src, "/dest/Song.flac"),
+            song(&src, "/dest/Song.flac"),
+            song(&src, "/dest/Song.flac"),
+        ];
+
+        resolve_collisions(&mut operations, CollisionStrategy::Hash, None).unwrap();
+
+        assert_eq!(
+            dests(&operations),
+            [
+                PathBuf::from("/dest/Song.flac"),
+                PathBuf::from(format!("/dest/Song [{hash}].flac")),
+                PathBuf::from(format!("/dest/Song [{hash}] (2).flac"))
+            ]
+        );
+    }
+}
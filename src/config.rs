@@ -0,0 +1,245 @@
+use anyhow::{Context, anyhow};
+use clap::{Arg, ArgAction, Command, builder::ArgPredicate};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::file_utils;
+
+pub const CONFIG_FILENAME: &str = "ffery.toml";
+
+/// Options that can't be set by the config file.
+const IGNORED_OPTIONS: &[&str] = &["config", "help", "version"];
+
+/// Default option values for every subcommand, read from `ffery.toml`.
+///
+/// The values become the defaults of the command line arguments, so flags given on the
+/// command line (and `--profile`) always take precedence over the config file. A flag turned
+/// on by the config file is turned off with its `--no-` counterpart.
+pub struct Config {
+    path: PathBuf,
+    defaults: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Config {
+    /// Loads `config_path`, or the first `ffery.toml` found in the working directory and in
+    /// the ffery config directory. Returns `None` if there is no config file.
+    pub fn discover(config_path: Option<&Path>) -> anyhow::Result<Option<Self>> {
+        if let Some(config_path) = config_path {
+            file_utils::validate_file(config_path)?;
+            return Self::load(config_path).map(Some);
+        }
+
+        let candidates = [
+            env::current_dir().ok().map(|dir| dir.join(CONFIG_FILENAME)),
+            config_dir().ok().map(|dir| dir.join(CONFIG_FILENAME)),
+        ];
+        candidates
+            .into_iter()
+            .flatten()
+            .find(|path| path.is_file())
+            .map(|path| Self::load(&path))
+            .transpose()
+    }
+
+    fn load(config_path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(config_path).with_context(|| {
+            format!(
+                "Unable to read config '{}'",
+                config_path.to_str().unwrap_or("unknown")
+            )
+        })?;
+        let table: toml::Table = toml::from_str(&data).with_context(|| {
+            format!(
+                "Unable to parse config '{}'",
+                config_path.to_str().unwrap_or("unknown")
+            )
+        })?;
+
+        let mut defaults = BTreeMap::new();
+        for (command_name, options) in table {
+            let options = options
+                .as_table()
+                .ok_or_else(|| anyhow!("Expected a table of options for '{command_name}'"))?;
+            let values = options
+                .iter()
+                .map(|(key, value)| option_value(value).map(|value| (key.clone(), value)))
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("Invalid option in '{command_name}'"))?;
+            defaults.insert(command_name, values);
+        }
+
+        Ok(Self {
+            path: config_path.to_path_buf(),
+            defaults,
+        })
+    }
+
+    /// Sets the values of the config file as the defaults of the subcommands of `command`.
+    pub fn apply_defaults(&self, mut command: Command) -> anyhow::Result<Command> {
+        for (command_name, options) in &self.defaults {
+            let subcommand = command.find_subcommand(command_name).ok_or_else(|| {
+                anyhow!(
+                    "Unknown command '{command_name}' in config '{}'",
+                    self.path.to_str().unwrap_or("unknown")
+                )
+            })?;
+            let mut arg_ids = vec![];
+            let mut negations = vec![];
+            for (key, value) in options {
+                let arg = find_option(subcommand, key).ok_or_else(|| {
+                    anyhow!(
+                        "Unknown option '{key}' for '{command_name}' in config '{}'",
+                        self.path.to_str().unwrap_or("unknown")
+                    )
+                })?;
+                let id = arg.get_id().to_string();
+                if matches!(arg.get_action(), ArgAction::SetTrue) && value == "true" {
+                    let no_id = format!("no_{id}");
+                    let negation = subcommand
+                        .get_arguments()
+                        .all(|arg| arg.get_id() != &no_id)
+                        .then(|| {
+                            Arg::new(no_id.clone())
+                                .long(format!("no-{key}"))
+                                .action(ArgAction::SetTrue)
+                                .overrides_with(id.clone())
+                                .help(format!("Turns off the --{key} set by the config file"))
+                        });
+                    negations.push((id.clone(), no_id, negation));
+                }
+                arg_ids.push((id, value.clone()));
+            }
+
+            command = command.mut_subcommand(command_name, |mut subcommand| {
+                for (id, value) in arg_ids {
+                    subcommand = subcommand.mut_arg(id, |arg| arg.default_value(value));
+                }
+                for (id, no_id, negation) in negations {
+                    if let Some(negation) = negation {
+                        subcommand = subcommand.arg(negation);
+                    }
+                    // Overriding a flag resets it to its default, the counterpart changes it
+                    subcommand = subcommand.mut_arg(id, |arg| {
+                        arg.default_value_if(
+                            no_id,
+                            ArgPredicate::Equals("true".into()),
+                            Some("false"),
+                        )
+                    });
+                }
+                subcommand
+            });
+        }
+
+        Ok(command)
+    }
+}
+
+/// The ffery config directory, `$XDG_CONFIG_HOME/ffery` or `~/.config/ffery`.
+pub fn config_dir() -> anyhow::Result<PathBuf> {
+    if let Some(config_home) = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(config_home).join("ffery"));
+    }
+
+    env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".config").join("ffery"))
+        .ok_or_else(|| anyhow!("Unable to find the config directory, HOME is not set"))
+}
+
+/// Finds `--config` before the arguments are parsed, because the config file sets their
+/// defaults.
+pub fn config_arg() -> Option<PathBuf> {
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+
+    None
+}
+
+/// Prints the config file in use and the effective defaults of every subcommand as TOML.
+/// Values that come from the config file are marked.
+pub fn start_config_show(command: &Command, config: Option<&Config>) {
+    // Building sets the implicit defaults, e.g. `false` for flags
+    let mut command = command.clone();
+    command.build();
+
+    match config {
+        Some(config) => println!("# Config: {}", config.path.to_str().unwrap_or("unknown")),
+        None => println!("# No {CONFIG_FILENAME} found, showing the built-in defaults"),
+    }
+
+    let mut subcommands: Vec<&Command> = command.get_subcommands().collect();
+    subcommands.sort_by_key(|subcommand| subcommand.get_display_order());
+    for subcommand in subcommands {
+        let configured = config.and_then(|config| config.defaults.get(subcommand.get_name()));
+        let mut args: Vec<&Arg> = subcommand.get_arguments().collect();
+        args.sort_by_key(|arg| arg.get_display_order());
+        let options: Vec<String> = args
+            .into_iter()
+            .filter_map(|arg| {
+                let key = arg.get_long()?;
+                if IGNORED_OPTIONS.contains(&key) {
+                    return None;
+                }
+                let value = arg.get_default_values().first()?.to_string_lossy();
+                let source = if configured.is_some_and(|options| options.contains_key(key)) {
+                    "  # config"
+                } else {
+                    ""
+                };
+                Some(format!("{key} = {}{source}", toml_value(&value)))
+            })
+            .collect();
+        if options.is_empty() {
+            continue;
+        }
+
+        println!("\n[{}]", subcommand.get_name());
+        for option in options {
+            println!("{option}");
+        }
+    }
+}
+
+fn find_option<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    if IGNORED_OPTIONS.contains(&key) {
+        return None;
+    }
+
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(key))
+}
+
+fn option_value(value: &toml::Value) -> anyhow::Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(anyhow!(
+            "Expected a string, number or boolean, got '{value}'"
+        )),
+    }
+}
+
+/// Formats a default value, numbers and booleans are written without quotes.
+fn toml_value(value: &str) -> String {
+    if value.parse::<i64>().is_ok() || value.parse::<bool>().is_ok() {
+        value.to_string()
+    } else {
+        toml::Value::String(value.to_string()).to_string()
+    }
+}
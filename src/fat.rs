@@ -0,0 +1,910 @@
+use anyhow::{Context, anyhow};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Component, Path},
+};
+
+use crate::{
+    file_utils,
+    plan::{self, CopyOperation, CopyPlan, ExecutionOptions},
+    progress,
+};
+
+type FatDir<'a> = fatfs::Dir<'a, fs::File>;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_LABEL: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const DELETED_ENTRY: u8 = 0xE5;
+
+/// Executes `copy_plan` directly on the FAT32 filesystem in `image`, which can be an image
+/// file or a device. The destinations of the plan are paths inside the filesystem. Once
+/// everything is written, the directory entries are reordered to match the plan, so the
+/// order doesn't depend on which free entries the filesystem reused.
+pub fn execute_plan(image: &Path, copy_plan: &CopyPlan) -> anyhow::Result<()> {
+    validate_image(image)?;
+
+    let temp_dir = file_utils::create_temp_dir()?;
+    let result = write_operations(image, copy_plan, &temp_dir);
+    let _ = fs::remove_dir_all(&temp_dir);
+    result?;
+
+    let mut fat_image = FatImage::open(image)?;
+    for (dir, names) in planned_dir_order(&copy_plan.operations)? {
+        fat_image.sort_dir_by_plan(&dir, &names)?;
+    }
+
+    Ok(())
+}
+
+fn write_operations(image: &Path, copy_plan: &CopyPlan, temp_dir: &Path) -> anyhow::Result<()> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image)
+        .with_context(|| {
+            format!(
+                "Unable to open image '{}'",
+                image.to_str().unwrap_or("unknown")
+            )
+        })?;
+    let filesystem = fatfs::FileSystem::new(file, fatfs::FsOptions::new()).with_context(|| {
+        format!(
+            "Unable to read the filesystem of '{}'",
+            image.to_str().unwrap_or("unknown")
+        )
+    })?;
+    if filesystem.fat_type() != fatfs::FatType::Fat32 {
+        return Err(anyhow!(
+            "Image '{}' is not a FAT32 filesystem",
+            image.to_str().unwrap_or("unknown")
+        ));
+    }
+
+    let bar = progress::get_progress_bar(copy_plan.operations.len() as u64);
+    bar.set_message("Writing to image...");
+
+    let result = copy_plan.operations.iter().try_for_each(|operation| {
+        execute_operation(
+            &filesystem.root_dir(),
+            operation,
+            &copy_plan.options,
+            temp_dir,
+        )
+        .map(|()| bar.inc(1))
+    });
+    bar.finish();
+    result?;
+
+    filesystem.unmount()?;
+
+    Ok(())
+}
+
+fn execute_operation(
+    root: &FatDir,
+    operation: &CopyOperation,
+    options: &ExecutionOptions,
+    temp_dir: &Path,
+) -> anyhow::Result<()> {
+    let dest = fat_path(operation.dest())?;
+
+    match operation {
+        CopyOperation::Song {
+            src, tag_changes, ..
+        } => {
+            if !options.override_files && root.open_file(&dest).is_ok() {
+                return Ok(());
+            }
+            if tag_changes.is_empty() {
+                return write_file(root, src, &dest, options.verify_retries);
+            }
+
+            // Tags are edited on a local copy, the image only receives the final file
+            let temp_file = temp_dir.join(file_utils::random_name(12));
+            fs::copy(src, &temp_file)?;
+            plan::apply_tag_changes(&temp_file, tag_changes)?;
+            write_file(root, &temp_file, &dest, options.verify_retries)?;
+            fs::remove_file(&temp_file)?;
+        }
+        CopyOperation::File { src, .. } => {
+            if !options.override_files && root.open_file(&dest).is_ok() {
+                return Ok(());
+            }
+            write_file(root, src, &dest, options.verify_retries)?;
+        }
+        CopyOperation::Move { src, .. } => {
+            let src = fat_path(src)?;
+            create_parent_dirs(root, &dest)?;
+            root.rename(&src, root, &dest)
+                .with_context(|| format!("Failed to move file '{src}' to '{dest}' in the image"))?;
+        }
+        CopyOperation::Delete { .. } => {
+            if root.open_file(&dest).is_ok() {
+                root.remove(&dest)
+                    .with_context(|| format!("Failed to delete file '{dest}' in the image"))?;
+            }
+        }
+        CopyOperation::RemoveDir { .. } => {
+            if let Ok(dir) = root.open_dir(&dest)
+                && is_empty_fat_dir(&dir)?
+            {
+                root.remove(&dest)
+                    .with_context(|| format!("Failed to remove directory '{dest}' in the image"))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `src` to a temporary file next to `dest` and renames it, like
+/// [`file_utils::copy_file`] does on a mounted filesystem.
+fn write_file(
+    root: &FatDir,
+    src: &Path,
+    dest: &str,
+    verify_retries: Option<u32>,
+) -> anyhow::Result<()> {
+    create_parent_dirs(root, dest)?;
+    let temp_dest = match dest.rsplit_once('/') {
+        Some((parent_dir, _)) => format!("{parent_dir}/{}", file_utils::temp_copy_name()),
+        None => file_utils::temp_copy_name(),
+    };
+
+    let src_hash = verify_retries
+        .map(|_| file_utils::hash_file(src))
+        .transpose()?;
+    let mut is_verified = false;
+    for _ in 0..=verify_retries.unwrap_or(0) {
+        let mut file = root.create_file(&temp_dest)?;
+        file.truncate()?;
+        io::copy(&mut fs::File::open(src)?, &mut file).with_context(|| {
+            format!(
+                "Failed to write file '{}' to '{dest}' in the image",
+                src.to_str().unwrap_or("unknown")
+            )
+        })?;
+        file.flush()?;
+        drop(file);
+
+        let Some(src_hash) = src_hash else {
+            is_verified = true;
+            break;
+        };
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut root.open_file(&temp_dest)?, &mut hasher)?;
+        if hasher.finalize() == src_hash {
+            is_verified = true;
+            break;
+        }
+    }
+    if !is_verified {
+        root.remove(&temp_dest)?;
+        return Err(anyhow!(
+            "Checksum of the copy of '{}' does not match after {} copy attempts",
+            src.to_str().unwrap_or("unknown"),
+            verify_retries.unwrap_or(0) + 1,
+        ));
+    }
+
+    if root.open_file(dest).is_ok() {
+        root.remove(dest)?;
+    }
+    root.rename(&temp_dest, root, dest)
+        .with_context(|| format!("Failed to rename file '{temp_dest}' to '{dest}' in the image"))?;
+
+    Ok(())
+}
+
+fn create_parent_dirs(root: &FatDir, path: &str) -> anyhow::Result<()> {
+    let Some((parent_dir, _)) = path.rsplit_once('/') else {
+        return Ok(());
+    };
+
+    let mut dir_path = String::new();
+    for component in parent_dir.split('/') {
+        if !dir_path.is_empty() {
+            dir_path.push('/');
+        }
+        dir_path.push_str(component);
+        root.create_dir(&dir_path)
+            .with_context(|| format!("Failed to create directory '{dir_path}' in the image"))?;
+    }
+
+    Ok(())
+}
+
+fn is_empty_fat_dir(dir: &FatDir) -> anyhow::Result<bool> {
+    for entry in dir.iter() {
+        let name = entry?.file_name();
+        if name != "." && name != ".." {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Converts a destination of the plan to a '/' separated path inside the filesystem.
+fn fat_path(path: &Path) -> anyhow::Result<String> {
+    let mut components = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_str().ok_or_else(|| {
+                anyhow!(
+                    "Path '{}' is not valid UTF-8",
+                    path.to_str().unwrap_or("unknown")
+                )
+            })?),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(anyhow!(
+                    "Path '{}' can't be used inside an image",
+                    path.to_str().unwrap_or("unknown")
+                ));
+            }
+        }
+    }
+
+    Ok(components.join("/"))
+}
+
+/// Returns every directory written by `operations`, with the names of its entries in the
+/// order in which the plan writes them.
+fn planned_dir_order(operations: &[CopyOperation]) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    let mut dirs: Vec<(String, Vec<String>)> = vec![];
+    let mut dir_indexes: HashMap<String, usize> = HashMap::new();
+
+    for operation in operations {
+        if matches!(
+            operation,
+            CopyOperation::Delete { .. } | CopyOperation::RemoveDir { .. }
+        ) {
+            continue;
+        }
+
+        let path = fat_path(operation.dest())?;
+        let mut parent_dir = String::new();
+        for name in path.split('/') {
+            let i = *dir_indexes
+                .entry(fold_case(&parent_dir))
+                .or_insert_with(|| {
+                    dirs.push((parent_dir.clone(), vec![]));
+                    dirs.len() - 1
+                });
+            let names = &mut dirs[i].1;
+            if !names.iter().any(|n| fold_case(n) == fold_case(name)) {
+                names.push(name.to_string());
+            }
+
+            if !parent_dir.is_empty() {
+                parent_dir.push('/');
+            }
+            parent_dir.push_str(name);
+        }
+    }
+
+    Ok(dirs)
+}
+
+/// A file or directory together with the raw directory entries that describe it: its long
+/// name entries followed by its short name entry.
+struct RawEntry {
+    name: String,
+    attributes: u8,
+    first_cluster: u32,
+    data: Vec<u8>,
+}
+
+impl RawEntry {
+    const fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+
+    fn sort_rank(&self) -> u8 {
+        if self.is_dot() || self.attributes & ATTR_VOLUME_LABEL != 0 {
+            0
+        } else if self.is_dir() {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+/// Raw access to the directories of a FAT32 filesystem. The `fatfs` crate places new
+/// entries in the first free slots, this rewrites the entries of a directory in any order.
+struct FatImage {
+    file: fs::File,
+    bytes_per_cluster: usize,
+    fat_offset: u64,
+    data_offset: u64,
+    root_cluster: u32,
+    cluster_count: u32,
+}
+
+impl FatImage {
+    fn open(image: &Path) -> anyhow::Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(image)
+            .with_context(|| {
+                format!(
+                    "Unable to open image '{}'",
+                    image.to_str().unwrap_or("unknown")
+                )
+            })?;
+        let mut boot_sector = [0u8; 512];
+        file.read_exact(&mut boot_sector).with_context(|| {
+            format!(
+                "Image '{}' is not a FAT32 filesystem",
+                image.to_str().unwrap_or("unknown")
+            )
+        })?;
+
+        let read_u16 =
+            |i: usize| u64::from(u16::from_le_bytes([boot_sector[i], boot_sector[i + 1]]));
+        let read_u32 = |i: usize| {
+            u32::from_le_bytes([
+                boot_sector[i],
+                boot_sector[i + 1],
+                boot_sector[i + 2],
+                boot_sector[i + 3],
+            ])
+        };
+        let bytes_per_sector = read_u16(11);
+        let sectors_per_cluster = u64::from(boot_sector[13]);
+        let reserved_sectors = read_u16(14);
+        let fat_count = u64::from(boot_sector[16]);
+        let sectors_per_fat = u64::from(read_u32(36));
+        let total_sectors = u64::from(read_u32(32));
+
+        // FAT32 has no fixed root directory and no 16-bit FAT size
+        let fat_offset = reserved_sectors * bytes_per_sector;
+        let data_sectors = total_sectors
+            .checked_sub(reserved_sectors + fat_count * sectors_per_fat)
+            .filter(|_| bytes_per_sector != 0 && sectors_per_cluster != 0)
+            .filter(|_| read_u16(17) == 0 && read_u16(22) == 0)
+            .filter(|_| boot_sector[510..] == [0x55, 0xAA]);
+        let Some(data_sectors) = data_sectors else {
+            return Err(anyhow!(
+                "Image '{}' is not a FAT32 filesystem",
+                image.to_str().unwrap_or("unknown")
+            ));
+        };
+
+        Ok(Self {
+            file,
+            bytes_per_cluster: usize::try_from(bytes_per_sector * sectors_per_cluster)?,
+            fat_offset,
+            data_offset: fat_offset + fat_count * sectors_per_fat * bytes_per_sector,
+            root_cluster: read_u32(44),
+            cluster_count: u32::try_from(data_sectors / sectors_per_cluster)?,
+        })
+    }
+
+    fn cluster_chain(&mut self, first_cluster: u32) -> anyhow::Result<Vec<u32>> {
+        let mut chain = vec![];
+        let mut cluster = first_cluster;
+        // Cluster numbers start at 2, end of chain markers are above the last cluster
+        while (2..self.cluster_count + 2).contains(&cluster) {
+            if chain.len() > self.cluster_count as usize {
+                return Err(anyhow!(
+                    "Cluster chain starting at {first_cluster} is a loop"
+                ));
+            }
+            chain.push(cluster);
+
+            let mut next_cluster = [0u8; 4];
+            self.file
+                .seek(SeekFrom::Start(self.fat_offset + u64::from(cluster) * 4))?;
+            self.file.read_exact(&mut next_cluster)?;
+            cluster = u32::from_le_bytes(next_cluster) & 0x0FFF_FFFF;
+        }
+
+        Ok(chain)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + u64::from(cluster - 2) * self.bytes_per_cluster as u64
+    }
+
+    fn read_dir(&mut self, first_cluster: u32) -> anyhow::Result<(Vec<u32>, Vec<RawEntry>)> {
+        let clusters = self.cluster_chain(first_cluster)?;
+        let mut data = vec![0u8; clusters.len() * self.bytes_per_cluster];
+        for (cluster, chunk) in clusters.iter().zip(data.chunks_mut(self.bytes_per_cluster)) {
+            self.file
+                .seek(SeekFrom::Start(self.cluster_offset(*cluster)))?;
+            self.file.read_exact(chunk)?;
+        }
+
+        Ok((clusters, parse_dir_entries(&data)))
+    }
+
+    /// Deleted entries are dropped, the space left at the end is zeroed.
+    fn write_dir(&mut self, clusters: &[u32], entries: &[RawEntry]) -> anyhow::Result<()> {
+        let mut data: Vec<u8> = entries
+            .iter()
+            .flat_map(|e| e.data.iter().copied())
+            .collect();
+        data.resize(clusters.len() * self.bytes_per_cluster, 0);
+        for (cluster, chunk) in clusters.iter().zip(data.chunks(self.bytes_per_cluster)) {
+            self.file
+                .seek(SeekFrom::Start(self.cluster_offset(*cluster)))?;
+            self.file.write_all(chunk)?;
+        }
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn find_dir(&mut self, path: &str) -> anyhow::Result<u32> {
+        let mut cluster = self.root_cluster;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let (_, entries) = self.read_dir(cluster)?;
+            cluster = entries
+                .iter()
+                .find(|entry| entry.is_dir() && fold_case(&entry.name) == fold_case(name))
+                .map(|entry| entry.first_cluster)
+                .ok_or_else(|| anyhow!("Directory '{path}' not found in the image"))?;
+        }
+
+        Ok(cluster)
+    }
+
+    /// Entries that aren't part of the plan keep their order and come first, followed by
+    /// the planned entries in plan order.
+    fn sort_dir_by_plan(&mut self, dir: &str, names: &[String]) -> anyhow::Result<()> {
+        let cluster = self.find_dir(dir)?;
+        let (clusters, mut entries) = self.read_dir(cluster)?;
+        let names: Vec<String> = names.iter().map(|name| fold_case(name)).collect();
+        entries.sort_by_key(|entry| {
+            let name = fold_case(&entry.name);
+            names.iter().position(|n| *n == name).map_or(0, |i| i + 1)
+        });
+
+        self.write_dir(&clusters, &entries)
+    }
+
+    /// Sorts the directory and all its subdirectories, directories before files.
+    fn sort_dir_tree(&mut self, cluster: u32, natural: bool) -> anyhow::Result<()> {
+        let (clusters, mut entries) = self.read_dir(cluster)?;
+        entries.sort_by(|a, b| {
+            a.sort_rank()
+                .cmp(&b.sort_rank())
+                .then_with(|| compare_names(&a.name, &b.name, natural))
+        });
+        self.write_dir(&clusters, &entries)?;
+
+        for entry in entries.iter().filter(|e| e.is_dir() && !e.is_dot()) {
+            self.sort_dir_tree(entry.first_cluster, natural)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_dir_entries(data: &[u8]) -> Vec<RawEntry> {
+    let mut entries = vec![];
+    let mut pending_data = vec![];
+    let mut long_name_parts: Vec<Vec<u16>> = vec![];
+
+    for raw_entry in data.chunks_exact(DIR_ENTRY_SIZE) {
+        match raw_entry[0] {
+            0x00 => break,
+            DELETED_ENTRY => {
+                pending_data.clear();
+                long_name_parts.clear();
+                continue;
+            }
+            _ => {}
+        }
+
+        pending_data.extend_from_slice(raw_entry);
+        if raw_entry[11] == ATTR_LONG_NAME {
+            long_name_parts.push(long_name_part(raw_entry));
+            continue;
+        }
+
+        // Long name entries are stored last part first
+        let name = if long_name_parts.is_empty() {
+            short_name(raw_entry)
+        } else {
+            let chars: Vec<u16> = long_name_parts.iter().rev().flatten().copied().collect();
+            String::from_utf16_lossy(&chars)
+        };
+        entries.push(RawEntry {
+            name,
+            attributes: raw_entry[11],
+            first_cluster: u32::from(u16::from_le_bytes([raw_entry[20], raw_entry[21]])) << 16
+                | u32::from(u16::from_le_bytes([raw_entry[26], raw_entry[27]])),
+            data: mem::take(&mut pending_data),
+        });
+        long_name_parts.clear();
+    }
+
+    entries
+}
+
+fn long_name_part(raw_entry: &[u8]) -> Vec<u16> {
+    [1..11, 14..26, 28..32]
+        .into_iter()
+        .flat_map(|range| raw_entry[range].chunks_exact(2))
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0x0000 && *c != 0xFFFF)
+        .collect()
+}
+
+fn short_name(raw_entry: &[u8]) -> String {
+    let mut base: Vec<u8> = raw_entry[0..8].to_vec();
+    // 0x05 stands for a name starting with 0xE5
+    if base[0] == 0x05 {
+        base[0] = DELETED_ENTRY;
+    }
+    let to_string = |bytes: &[u8], lowercase: bool| {
+        let name: String = bytes.iter().map(|b| char::from(*b)).collect();
+        let name = name.trim_end().to_string();
+        if lowercase { name.to_lowercase() } else { name }
+    };
+    let base = to_string(&base, raw_entry[12] & 0x08 != 0);
+    let ext = to_string(&raw_entry[8..11], raw_entry[12] & 0x10 != 0);
+
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{base}.{ext}")
+    }
+}
+
+/// FAT compares long names case-insensitively, also beyond ASCII.
+fn fold_case(name: &str) -> String {
+    name.to_lowercase()
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum NameChunk {
+    Number(u128),
+    Text(String),
+}
+
+/// Splits a name into text and numbers, so that "2 Song" sorts before "10 Song".
+fn natural_key(name: &str) -> Vec<NameChunk> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    for c in fold_case(name).chars() {
+        if !chunk.is_empty() && chunk.chars().all(|d| d.is_ascii_digit()) != c.is_ascii_digit() {
+            chunks.push(name_chunk(mem::take(&mut chunk)));
+        }
+        chunk.push(c);
+    }
+    if !chunk.is_empty() {
+        chunks.push(name_chunk(chunk));
+    }
+
+    chunks
+}
+
+fn name_chunk(chunk: String) -> NameChunk {
+    if chunk.chars().all(|c| c.is_ascii_digit()) {
+        NameChunk::Number(chunk.parse().unwrap_or(u128::MAX))
+    } else {
+        NameChunk::Text(chunk)
+    }
+}
+
+fn compare_names(a: &str, b: &str, natural: bool) -> Ordering {
+    if natural {
+        natural_key(a).cmp(&natural_key(b))
+    } else {
+        fold_case(a).cmp(&fold_case(b))
+    }
+}
+
+/// Devices aren't regular files, so only the existence of `image` is checked.
+fn validate_image(image: &Path) -> anyhow::Result<()> {
+    if !image.exists() {
+        return Err(anyhow!(
+            "Path '{}' does not exist",
+            image.to_str().unwrap_or("unknown")
+        ));
+    }
+    if image.is_dir() {
+        return Err(anyhow!(
+            "Path '{}' must be an image file or a device",
+            image.to_str().unwrap_or("unknown")
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn start_sort_fat(image: &Path, natural: bool) -> anyhow::Result<()> {
+    validate_image(image)?;
+
+    let mut fat_image = FatImage::open(image)?;
+    let root_cluster = fat_image.root_cluster;
+    fat_image.sort_dir_tree(root_cluster, natural)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Large enough for the minimum cluster count of FAT32 with 512 byte clusters.
+    const IMAGE_LEN: u64 = 40 * 1024 * 1024;
+
+    fn create_image(temp_dir: &TempDir) -> PathBuf {
+        let image = temp_dir.path().join("fat32.img");
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&image)
+            .unwrap();
+        file.set_len(IMAGE_LEN).unwrap();
+        fatfs::format_volume(
+            file,
+            fatfs::FormatVolumeOptions::new()
+                .fat_type(fatfs::FatType::Fat32)
+                .bytes_per_cluster(512),
+        )
+        .unwrap();
+
+        image
+    }
+
+    fn with_root<T>(image: &Path, f: impl FnOnce(&FatDir) -> T) -> T {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(image)
+            .unwrap();
+        let filesystem = fatfs::FileSystem::new(file, fatfs::FsOptions::new()).unwrap();
+        let result = f(&filesystem.root_dir());
+        filesystem.unmount().unwrap();
+
+        result
+    }
+
+    fn write_image_file(root: &FatDir, path: &str, content: &str) {
+        create_parent_dirs(root, path).unwrap();
+        let mut file = root.create_file(path).unwrap();
+        file.truncate().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+
+    /// The names of the entries of `dir` in on-disk order, as `fatfs` reads them.
+    fn dir_names(image: &Path, dir: &str) -> Vec<String> {
+        with_root(image, |root| {
+            let dir = if dir.is_empty() {
+                root.clone()
+            } else {
+                root.open_dir(dir).unwrap()
+            };
+            dir.iter()
+                .map(|entry| entry.unwrap().file_name())
+                .filter(|name| name != "." && name != "..")
+                .collect()
+        })
+    }
+
+    fn read_image_file(image: &Path, path: &str) -> String {
+        with_root(image, |root| {
+            let mut content = String::new();
+            root.open_file(path)
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        })
+    }
+
+    fn local_file(temp_dir: &TempDir, filename: &str, content: &str) -> PathBuf {
+        let path = temp_dir.path().join("src").join(filename);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn execute_plan_writes_entries_in_plan_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = create_image(&temp_dir);
+        // A freed slot before an existing entry is reused for the next new file
+        with_root(&image, |root| {
+            write_image_file(root, "Music/Album/old.txt", "old");
+            write_image_file(root, "Music/Album/keep.txt", "keep");
+            root.remove("Music/Album/old.txt").unwrap();
+        });
+
+        let mut copy_plan = CopyPlan::new(ExecutionOptions {
+            delay_ms: 0,
+            override_files: false,
+            verify_retries: Some(1),
+            image: Some(image.clone()),
+        });
+        for (filename, content) in [
+            ("02 A song with a long name.txt", "second"),
+            ("01 Ärger.txt", "first"),
+            ("cover.jpg", "cover"),
+        ] {
+            copy_plan.operations.push(CopyOperation::File {
+                src: local_file(&temp_dir, filename, content),
+                dest: Path::new("/Music/Album").join(filename),
+            });
+        }
+        execute_plan(&image, &copy_plan).unwrap();
+
+        assert_eq!(
+            dir_names(&image, "Music/Album"),
+            [
+                "keep.txt",
+                "02 A song with a long name.txt",
+                "01 Ärger.txt",
+                "cover.jpg"
+            ]
+        );
+        assert_eq!(
+            read_image_file(&image, "Music/Album/02 A song with a long name.txt"),
+            "second"
+        );
+        assert_eq!(read_image_file(&image, "Music/Album/01 Ärger.txt"), "first");
+        assert_eq!(read_image_file(&image, "Music/Album/keep.txt"), "keep");
+
+        // The deleted entry and the renamed temporary file are gone from the directory
+        let mut fat_image = FatImage::open(&image).unwrap();
+        let cluster = fat_image.find_dir("music/album").unwrap();
+        let (_, entries) = fat_image.read_dir(cluster).unwrap();
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                ".",
+                "..",
+                "keep.txt",
+                "02 A song with a long name.txt",
+                "01 Ärger.txt",
+                "cover.jpg"
+            ]
+        );
+        // Every long name entry is followed by its short name entry
+        for entry in &entries {
+            let (long_entries, short_entry) =
+                entry.data.split_last_chunk::<DIR_ENTRY_SIZE>().unwrap();
+            assert_ne!(short_entry[11], ATTR_LONG_NAME);
+            assert!(
+                long_entries
+                    .chunks_exact(DIR_ENTRY_SIZE)
+                    .all(|long_entry| long_entry[11] == ATTR_LONG_NAME)
+            );
+        }
+    }
+
+    #[test]
+    fn sort_fat_natural_sorts_every_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = create_image(&temp_dir);
+        with_root(&image, |root| {
+            for (path, content) in [
+                ("10 Song.mp3", "ten"),
+                ("b.txt", "b"),
+                ("2 Song.mp3", "two"),
+                ("Zeta/10.txt", "z10"),
+                ("Zeta/9.txt", "z9"),
+                ("Alpha/1.txt", "a1"),
+                ("1 Song.mp3", "one"),
+            ] {
+                write_image_file(root, path, content);
+            }
+        });
+
+        start_sort_fat(&image, true).unwrap();
+
+        assert_eq!(
+            dir_names(&image, ""),
+            [
+                "Alpha",
+                "Zeta",
+                "1 Song.mp3",
+                "2 Song.mp3",
+                "10 Song.mp3",
+                "b.txt"
+            ]
+        );
+        assert_eq!(dir_names(&image, "Zeta"), ["9.txt", "10.txt"]);
+        assert_eq!(read_image_file(&image, "10 Song.mp3"), "ten");
+        assert_eq!(read_image_file(&image, "Zeta/9.txt"), "z9");
+        assert_eq!(read_image_file(&image, "Alpha/1.txt"), "a1");
+
+        start_sort_fat(&image, false).unwrap();
+        assert_eq!(
+            dir_names(&image, ""),
+            [
+                "Alpha",
+                "Zeta",
+                "1 Song.mp3",
+                "10 Song.mp3",
+                "2 Song.mp3",
+                "b.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn planned_dir_order_folds_case_beyond_ascii() {
+        let operations: Vec<CopyOperation> = ["/Ärger/a.txt", "/ärger/b.txt", "/ÄRGER/A.TXT"]
+            .into_iter()
+            .map(|dest| CopyOperation::Delete {
+                dest: PathBuf::from(dest),
+            })
+            .chain(
+                ["/Ärger/a.txt", "/ärger/b.txt", "/ÄRGER/A.TXT"]
+                    .into_iter()
+                    .map(|dest| CopyOperation::File {
+                        src: PathBuf::new(),
+                        dest: PathBuf::from(dest),
+                    }),
+            )
+            .collect();
+
+        let dirs = planned_dir_order(&operations).unwrap();
+        assert_eq!(
+            dirs,
+            [
+                (String::new(), vec!["Ärger".to_string()]),
+                (
+                    "Ärger".to_string(),
+                    vec!["a.txt".to_string(), "b.txt".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_long_and_short_entries() {
+        let mut data = vec![];
+        // Long name "ab" with its checksum, followed by the short entry
+        let mut long_entry = [0xFFu8; DIR_ENTRY_SIZE];
+        long_entry[0] = 0x41;
+        long_entry[1..7].copy_from_slice(&[b'a', 0, b'b', 0, 0, 0]);
+        long_entry[11] = ATTR_LONG_NAME;
+        long_entry[26..28].copy_from_slice(&[0, 0]);
+        data.extend_from_slice(&long_entry);
+        let mut short_entry = [0u8; DIR_ENTRY_SIZE];
+        short_entry[..11].copy_from_slice(b"AB         ");
+        short_entry[26] = 5;
+        data.extend_from_slice(&short_entry);
+        // A deleted entry is dropped together with its long name
+        let mut deleted_entry = short_entry;
+        deleted_entry[0] = DELETED_ENTRY;
+        data.extend_from_slice(&deleted_entry);
+        let mut lowercase_entry = [0u8; DIR_ENTRY_SIZE];
+        lowercase_entry[..11].copy_from_slice(b"README  TXT");
+        lowercase_entry[12] = 0x18;
+        data.extend_from_slice(&lowercase_entry);
+        data.extend_from_slice(&[0; DIR_ENTRY_SIZE]);
+        data.extend_from_slice(&short_entry);
+
+        let entries = parse_dir_entries(&data);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "ab");
+        assert_eq!(entries[0].first_cluster, 5);
+        assert_eq!(entries[0].data.len(), 2 * DIR_ENTRY_SIZE);
+        assert_eq!(entries[1].name, "readme.txt");
+    }
+}
@@ -1,14 +1,17 @@
 use anyhow::{Context, anyhow};
 use phf::phf_set;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::{HashMap, HashSet},
     env,
     ffi::OsStr,
     fs, io,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
+use unicode_normalization::UnicodeNormalization;
 
-use crate::progress;
+use crate::{journal::Journal, progress};
 
 static FORBIDDEN_CHARS: phf::Set<char> = phf_set! {
     // Explicitly forbidden printable ASCII
@@ -30,20 +33,246 @@ static RESERVED_NAMES_UPPERCASE: phf::Set<&'static str> = phf_set! {
 
 const MAX_FILENAME_LEN: usize = 255;
 const REPLACEMENT_CHAR: char = '_';
+const SHORT_NAME_BASE_LEN: usize = 8;
+const SHORT_NAME_EXTENSION_LEN: usize = 3;
+const SHORT_NAME_SPECIAL_CHARS: &str = "!#$%&'()-@^_`{}~";
+const TEMP_COPY_NAME_LEN: usize = 8;
+
+/// Unicode normalization form of rendered names and compared values.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Normalization {
+    /// Composed characters, used by Windows and Linux
+    Nfc,
+    /// Decomposed characters, used by macOS
+    Nfd,
+}
+
+impl Normalization {
+    pub fn apply(self, value: &str) -> String {
+        match self {
+            Self::Nfc => value.nfc().collect(),
+            Self::Nfd => value.nfd().collect(),
+        }
+    }
+}
+
+/// Sanitizes every directory and the filename of `path` below `root`. `root` itself is
+/// kept as it is, it already exists or was chosen by the user.
+pub fn sanitize_pathbuf_for_fat32(root: &Path, path: &Path) -> PathBuf {
+    map_components_below(root, path, |name, is_filename| {
+        if is_filename {
+            sanitize_filename_string(name)
+        } else {
+            sanitize_name_string(name, MAX_FILENAME_LEN)
+        }
+    })
+}
+
+/// Transliterates every directory and the filename of `path` below `root` to ASCII, e.g.
+/// "Björk" becomes "Bjork".
+pub fn transliterate_pathbuf(root: &Path, path: &Path) -> PathBuf {
+    map_components_below(root, path, |name, _| {
+        // Some symbols transliterate to a separator, e.g. "½" becomes "1/2"
+        transliterate_string(name).replace(['/', '\\'], "-")
+    })
+}
+
+/// Normalizes every directory and the filename of `path` below `root` to `normalization`.
+pub fn normalize_pathbuf(root: &Path, path: &Path, normalization: Normalization) -> PathBuf {
+    map_components_below(root, path, |name, _| normalization.apply(name))
+}
 
-pub fn sanitize_pathbuf_for_fat32(path: &Path) -> PathBuf {
-    path.file_name().map_or_else(
-        || path.to_path_buf(),
-        |filename_osstr| {
-            let filename_str = filename_osstr.to_string_lossy();
-            let sanitized_filename: String = sanitize_filename_string(&filename_str);
-            if filename_str == sanitized_filename {
-                path.to_path_buf()
+/// Maps every component of `path` below `root` with `map_name`, which also gets whether the
+/// component is the filename.
+fn map_components_below(
+    root: &Path,
+    path: &Path,
+    map_name: impl Fn(&str, bool) -> String,
+) -> PathBuf {
+    let (mut mapped_path, relative_path) = path
+        .strip_prefix(root)
+        .map_or((PathBuf::new(), path), |relative_path| {
+            (root.to_path_buf(), relative_path)
+        });
+
+    let component_count = relative_path.components().count();
+    for (i, component) in relative_path.components().enumerate() {
+        match component {
+            Component::Normal(name) => {
+                mapped_path.push(map_name(&name.to_string_lossy(), i + 1 == component_count));
+            }
+            // A `..` rendered from a tag value must not lead out of `root`
+            Component::ParentDir => mapped_path.push(REPLACEMENT_CHAR.to_string()),
+            _ => mapped_path.push(component),
+        }
+    }
+
+    mapped_path
+}
+
+/// Turns a rendered template into a path that stays inside the destination. `..` components
+/// are replaced, root and `.` components are dropped, so tag values like "../.." or "/etc"
+/// can't point outside of it.
+pub fn confine_rendered_path(rendered: &str) -> String {
+    let mut path = PathBuf::new();
+    for component in Path::new(rendered).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::ParentDir => path.push(REPLACEMENT_CHAR.to_string()),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+
+    path.to_string_lossy().into_owned()
+}
+
+/// Transliterates `value` to ASCII, characters without a transliteration are replaced.
+pub fn transliterate_string(value: &str) -> String {
+    deunicode::deunicode_with_tofu(value, &REPLACEMENT_CHAR.to_string())
+}
+
+/// Gives the directories and filenames below a root unique, deterministic 8.3 names, e.g.
+/// "01 Bohemian Rhapsody.flac" becomes `01BOHEMI.FLA`. The same rendered directory always
+/// gets the same short name and names already taken in a directory, also by the entries
+/// that already exist on disk, get a `~N` tail.
+#[derive(Default)]
+pub struct ShortNames {
+    short_paths: HashMap<PathBuf, PathBuf>,
+    taken_names: HashMap<PathBuf, HashSet<String>>,
+}
+
+impl ShortNames {
+    /// Registers a short name given by an earlier run, so that `long_path` gets the same
+    /// short path again. Paths outside of `root` or with another depth are ignored.
+    pub fn insert(&mut self, root: &Path, long_path: &Path, short_path: &Path) {
+        let (Ok(long_path), Ok(short_path)) =
+            (long_path.strip_prefix(root), short_path.strip_prefix(root))
+        else {
+            return;
+        };
+        if long_path.components().count() != short_path.components().count() {
+            return;
+        }
+
+        let mut long_prefix = PathBuf::new();
+        let mut short_prefix = PathBuf::new();
+        for (long_component, short_component) in long_path.components().zip(short_path.components())
+        {
+            let taken_names = self
+                .taken_names
+                .entry(short_prefix.clone())
+                .or_insert_with(|| existing_short_names(&root.join(&short_prefix)));
+            taken_names.insert(short_component.as_os_str().to_string_lossy().to_uppercase());
+            long_prefix.push(long_component);
+            short_prefix.push(short_component);
+            self.short_paths
+                .entry(long_prefix.clone())
+                .or_insert_with(|| short_prefix.clone());
+        }
+    }
+
+    /// Returns `path` with every component below `root` replaced by its short name.
+    pub fn shorten(&mut self, root: &Path, path: &Path) -> PathBuf {
+        let Ok(relative_path) = path.strip_prefix(root) else {
+            return path.to_path_buf();
+        };
+
+        let mut long_path = PathBuf::new();
+        let mut short_path = PathBuf::new();
+        let component_count = relative_path.components().count();
+        for (i, component) in relative_path.components().enumerate() {
+            long_path.push(component);
+            if let Some(known_path) = self.short_paths.get(&long_path) {
+                short_path.clone_from(known_path);
+                continue;
+            }
+
+            let name = component.as_os_str().to_string_lossy();
+            let short_name = match component {
+                Component::Normal(_) => {
+                    let taken_names = self
+                        .taken_names
+                        .entry(short_path.clone())
+                        .or_insert_with(|| existing_short_names(&root.join(&short_path)));
+                    unique_short_name(&name, i + 1 == component_count, taken_names)
+                }
+                _ => name.to_string(),
+            };
+            short_path.push(short_name);
+            self.short_paths
+                .insert(long_path.clone(), short_path.clone());
+        }
+
+        root.join(short_path)
+    }
+}
+
+/// The uppercase names of the entries in `dir`, FAT compares short names case-insensitively.
+/// A directory that doesn't exist yet has none.
+fn existing_short_names(dir: &Path) -> HashSet<String> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name().to_string_lossy().to_uppercase())
+        .collect()
+}
+
+fn unique_short_name(name: &str, is_filename: bool, taken_names: &mut HashSet<String>) -> String {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if is_filename && !stem.is_empty() => (stem, extension),
+        _ => (name, ""),
+    };
+    let mut base = short_name_part(stem, SHORT_NAME_BASE_LEN);
+    if base.is_empty() {
+        base.push(REPLACEMENT_CHAR);
+    }
+    if RESERVED_NAMES_UPPERCASE.contains(base.as_str()) {
+        base.truncate(SHORT_NAME_BASE_LEN - 1);
+        base.push(REPLACEMENT_CHAR);
+    }
+    let extension = short_name_part(extension, SHORT_NAME_EXTENSION_LEN);
+    let with_extension = |base: &str| {
+        if extension.is_empty() {
+            base.to_string()
+        } else {
+            format!("{base}.{extension}")
+        }
+    };
+
+    let mut short_name = with_extension(&base);
+    let mut counter = 1;
+    while taken_names.contains(&short_name) {
+        let tail = format!("~{counter}");
+        let prefix: String = base
+            .chars()
+            .take(SHORT_NAME_BASE_LEN - tail.len())
+            .collect();
+        short_name = with_extension(&format!("{prefix}{tail}"));
+        counter += 1;
+    }
+    taken_names.insert(short_name.clone());
+
+    short_name
+}
+
+/// Transliterates `value` to uppercase ASCII, drops spaces and periods and replaces the
+/// characters that aren't allowed in 8.3 names.
+fn short_name_part(value: &str, max_len: usize) -> String {
+    transliterate_string(value)
+        .to_uppercase()
+        .chars()
+        .filter(|c| *c != ' ' && *c != '.')
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL_CHARS.contains(c) {
+                c
             } else {
-                path.with_file_name(sanitized_filename)
+                REPLACEMENT_CHAR
             }
-        },
-    )
+        })
+        .take(max_len)
+        .collect()
 }
 
 fn sanitize_filename_string(filename: &str) -> String {
@@ -58,7 +287,50 @@ fn sanitize_filename_string(filename: &str) -> String {
         .unwrap_or_else(|| OsStr::new(""))
         .to_string_lossy();
 
-    let mut sanitized_stem: String = original_stem
+    // 1. Sanitize characters in extension
+    let sanitized_extension: String = original_extension
+        .chars()
+        .map(|c| {
+            // Use PHF set + explicit check for '.' and ' ' in extension
+            if FORBIDDEN_CHARS.contains(&c) || c == '.' || c == ' ' {
+                REPLACEMENT_CHAR
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    // 2. Sanitize the stem within the length left by the extension
+    let ext_len = if sanitized_extension.is_empty() {
+        0
+    } else {
+        sanitized_extension.chars().count()
+    };
+    let dot_len = usize::from(ext_len > 0);
+    let max_stem_len = MAX_FILENAME_LEN
+        .saturating_sub(ext_len)
+        .saturating_sub(dot_len);
+    let sanitized_stem = sanitize_name_string(&original_stem, max_stem_len);
+
+    // 3. Reassemble the filename
+    let mut final_filename = sanitized_stem;
+    if !sanitized_extension.is_empty() {
+        final_filename.push('.');
+        final_filename.push_str(&sanitized_extension);
+    }
+
+    // Final check for empty result
+    if final_filename.is_empty() {
+        return REPLACEMENT_CHAR.to_string();
+    }
+
+    final_filename
+}
+
+/// Sanitizes a directory name or the stem of a filename, which has no extension to keep.
+fn sanitize_name_string(name: &str, max_len: usize) -> String {
+    // 1. Replace forbidden characters
+    let mut sanitized_stem: String = name
         .chars()
         .map(|c| {
             if FORBIDDEN_CHARS.contains(&c) {
@@ -84,32 +356,9 @@ fn sanitize_filename_string(filename: &str) -> String {
         sanitized_stem.push(REPLACEMENT_CHAR); // Append replacement char if reserved
     }
 
-    // 5. Sanitize characters in extension
-    let sanitized_extension: String = original_extension
-        .chars()
-        .map(|c| {
-            // Use PHF set + explicit check for '.' and ' ' in extension
-            if FORBIDDEN_CHARS.contains(&c) || c == '.' || c == ' ' {
-                REPLACEMENT_CHAR
-            } else {
-                c
-            }
-        })
-        .collect();
-
-    // 6. Handle Length Constraint
-    let ext_len = if sanitized_extension.is_empty() {
-        0
-    } else {
-        sanitized_extension.chars().count()
-    };
-    let dot_len = usize::from(ext_len > 0);
-    let max_stem_len = MAX_FILENAME_LEN
-        .saturating_sub(ext_len)
-        .saturating_sub(dot_len);
-
-    if sanitized_stem.chars().count() > max_stem_len {
-        sanitized_stem = sanitized_stem.chars().take(max_stem_len).collect();
+    // 5. Handle length constraint
+    if sanitized_stem.chars().count() > max_len {
+        sanitized_stem = sanitized_stem.chars().take(max_len).collect();
         // Re-trim after truncation
         while sanitized_stem.ends_with(' ') || sanitized_stem.ends_with('.') {
             sanitized_stem.pop();
@@ -120,28 +369,16 @@ fn sanitize_filename_string(filename: &str) -> String {
         }
         // Re-check reserved names *if* truncation could have created one
         if RESERVED_NAMES_UPPERCASE.contains(sanitized_stem.to_uppercase().as_str()) {
-            if sanitized_stem.chars().count() < max_stem_len {
+            if sanitized_stem.chars().count() < max_len {
                 sanitized_stem.push(REPLACEMENT_CHAR);
-            } else if max_stem_len > 0 {
+            } else if max_len > 0 {
                 sanitized_stem.pop();
                 sanitized_stem.push(REPLACEMENT_CHAR);
             }
         }
     }
 
-    // 7. Reassemble the filename
-    let mut final_filename = sanitized_stem;
-    if !sanitized_extension.is_empty() {
-        final_filename.push('.');
-        final_filename.push_str(&sanitized_extension);
-    }
-
-    // Final check for empty result
-    if final_filename.is_empty() {
-        return REPLACEMENT_CHAR.to_string();
-    }
-
-    final_filename
+    sanitized_stem
 }
 
 pub fn validate_dir(dir: &Path) -> anyhow::Result<()> {
@@ -170,29 +407,40 @@ pub fn validate_file(f: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn create_temp_dir() -> anyhow::Result<PathBuf> {
-    let temp_dir = env::temp_dir();
-
-    let unique_name: String = rand::rng()
+pub fn random_name(len: usize) -> String {
+    rand::rng()
         .sample_iter(rand::distr::Alphanumeric)
-        .take(12)
+        .take(len)
         .map(char::from)
-        .collect();
+        .collect()
+}
 
-    let temp_path = temp_dir.join(unique_name);
+/// Name of the temporary file a copy is written to before it's renamed. The name is random, so
+/// it neither replaces a user's file nor collides with another run writing to the same
+/// directory. A valid uppercase 8.3 name takes a single FAT directory entry. The entry freed by
+/// the rename is reused by the next temporary file, so renamed files keep their write order.
+pub fn temp_copy_name() -> String {
+    format!("{}.TMP", random_name(TEMP_COPY_NAME_LEN).to_uppercase())
+}
+
+pub fn create_temp_dir() -> anyhow::Result<PathBuf> {
+    let temp_dir = env::temp_dir();
+
+    let temp_path = temp_dir.join(random_name(12));
     fs::create_dir_all(&temp_path)?;
 
     Ok(temp_path)
 }
 
-pub fn count_files(dir: &Path) -> anyhow::Result<u64> {
-    count_files_recursive(dir, None)
-}
-
 pub fn count_files_by_extension(dir: &Path, extensions: &[&str]) -> anyhow::Result<u64> {
     count_files_recursive(dir, Some(extensions))
 }
 
+pub fn is_empty_dir(dir: &Path) -> bool {
+    dir.read_dir()
+        .is_ok_and(|mut entries| entries.next().is_none())
+}
+
 pub fn file_has_extension(f: &Path, extensions: &[&str]) -> bool {
     let extension = f.extension();
     extension.is_some_and(|extension| {
@@ -202,27 +450,138 @@ pub fn file_has_extension(f: &Path, extensions: &[&str]) -> bool {
     })
 }
 
+/// Copies `src` to `dest` atomically. The content is written to a temporary file in the
+/// destination directory first, which is then renamed, so `dest` is never left partially
+/// written.
 pub fn copy_file(
     src: &Path,
     dest: &Path,
     override_file: bool,
-    fat_32: bool,
+    verify_retries: Option<u32>,
+) -> anyhow::Result<Option<PathBuf>> {
+    copy_file_with(src, dest, override_file, verify_retries, |_| Ok(()))
+}
+
+/// Like [`copy_file`], but runs `edit` on the verified temporary file before it is renamed, so
+/// an interrupted edit never leaves a broken `dest` behind.
+pub fn copy_file_with(
+    src: &Path,
+    dest: &Path,
+    override_file: bool,
+    verify_retries: Option<u32>,
+    edit: impl FnOnce(&Path) -> anyhow::Result<()>,
 ) -> anyhow::Result<Option<PathBuf>> {
-    if dest.exists() && !override_file {
+    let existing_dest = find_existing_entry(dest)?;
+    if existing_dest.is_some() && !override_file {
         return Ok(None);
     }
 
-    let dest = if fat_32 {
-        sanitize_pathbuf_for_fat32(dest)
-    } else {
-        dest.to_path_buf()
-    };
+    let dest = dest.to_path_buf();
 
     if let Some(parent_dir) = dest.parent() {
         fs::create_dir_all(parent_dir)?;
     }
 
-    fs::copy(src, &dest).with_context(|| {
+    let temp_dest = dest.with_file_name(temp_copy_name());
+    let result = copy_file_to_temp(src, &temp_dest, verify_retries)
+        .and_then(|()| edit(&temp_dest))
+        .and_then(|()| rename_file(&temp_dest, &dest));
+    if result.is_err() && temp_dest.exists() {
+        let _ = fs::remove_file(&temp_dest);
+    }
+    result?;
+    // An overridden copy written by an earlier run in another normalization form
+    if let Some(existing_dest) = existing_dest
+        && existing_dest != dest
+    {
+        fs::remove_file(existing_dest)?;
+    }
+
+    Ok(Some(dest))
+}
+
+/// Finds the entry at `path`, also when it or one of its directories was written in another
+/// Unicode normalization form, e.g. by an earlier run without `--normalize`. Filesystems
+/// that don't normalize names treat composed and decomposed names as different entries.
+pub fn find_existing_entry(path: &Path) -> anyhow::Result<Option<PathBuf>> {
+    if path.exists() {
+        return Ok(Some(path.to_path_buf()));
+    }
+    let (Some(parent_dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(None);
+    };
+    let Some(parent_dir) = find_existing_entry(parent_dir)? else {
+        return Ok(None);
+    };
+    if !parent_dir.is_dir() {
+        return Ok(None);
+    }
+
+    let name = Normalization::Nfc.apply(&name.to_string_lossy());
+    for entry in fs::read_dir(&parent_dir)? {
+        let entry = entry?;
+        if Normalization::Nfc.apply(&entry.file_name().to_string_lossy()) == name {
+            return Ok(Some(entry.path()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn copy_file_to_temp(
+    src: &Path,
+    temp_dest: &Path,
+    verify_retries: Option<u32>,
+) -> anyhow::Result<()> {
+    let Some(retries) = verify_retries else {
+        return copy_file_contents(src, temp_dest);
+    };
+
+    copy_verified_file(src, temp_dest, retries, hash_file)
+}
+
+/// Copies `src` until the `hash` of the copy matches the one of `src`, at most `retries` + 1
+/// times. The copy is flushed to the device, but read back through the page cache of the
+/// operating system, so it catches corruption on the way to the device driver, not bad
+/// blocks that only show up once the cache is dropped.
+fn copy_verified_file(
+    src: &Path,
+    temp_dest: &Path,
+    retries: u32,
+    mut hash: impl FnMut(&Path) -> anyhow::Result<blake3::Hash>,
+) -> anyhow::Result<()> {
+    let src_hash = hash(src)?;
+    for _ in 0..=retries {
+        copy_file_contents(src, temp_dest)?;
+        // Flush the copy to the device so write errors surface before hashing it back
+        fs::OpenOptions::new()
+            .write(true)
+            .open(temp_dest)?
+            .sync_all()?;
+        if hash(temp_dest)? == src_hash {
+            return Ok(());
+        }
+    }
+
+    Err(anyhow!(
+        "Checksum of the copy of '{}' does not match after {} copy attempts",
+        src.to_str().unwrap_or("unknown"),
+        retries + 1,
+    ))
+}
+
+fn rename_file(src: &Path, dest: &Path) -> anyhow::Result<()> {
+    fs::rename(src, dest).with_context(|| {
+        format!(
+            "Failed to rename file '{}' to '{}'",
+            src.to_str().unwrap_or("unknown"),
+            dest.to_str().unwrap_or("unknown"),
+        )
+    })
+}
+
+fn copy_file_contents(src: &Path, dest: &Path) -> anyhow::Result<()> {
+    fs::copy(src, dest).with_context(|| {
         format!(
             "Failed to copy file '{}' to '{}'",
             src.to_str().unwrap_or("unknown"),
@@ -230,10 +589,29 @@ pub fn copy_file(
         )
     })?;
 
-    Ok(Some(dest))
+    Ok(())
 }
 
-pub fn remove_prefix_from_files(prefix: &str, ext: &str, dir: &Path) -> anyhow::Result<()> {
+pub fn hash_file(filepath: &Path) -> anyhow::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_reader(fs::File::open(filepath)?)
+        .with_context(|| {
+            format!(
+                "Failed to hash file '{}'",
+                filepath.to_str().unwrap_or("unknown")
+            )
+        })?;
+
+    Ok(hasher.finalize())
+}
+
+pub fn remove_prefix_from_files(
+    prefix: &str,
+    ext: &str,
+    dir: &Path,
+    mut journal: Option<&mut Journal>,
+) -> anyhow::Result<()> {
     validate_dir(dir)?;
 
     let target_ext = OsStr::new(ext);
@@ -258,9 +636,15 @@ pub fn remove_prefix_from_files(prefix: &str, ext: &str, dir: &Path) -> anyhow::
                     .to_string(),
             )
         })
-        .filter(|(_, target_filename)| !target_filename.is_empty())
+        .filter(|(src_filename, target_filename)| {
+            !target_filename.is_empty() && src_filename != target_filename
+        })
         .map(|(src_filename, target_filename)| {
-            fs::rename(dir.join(&src_filename), dir.join(&target_filename)).with_context(|| {
+            let (src, target) = (dir.join(&src_filename), dir.join(&target_filename));
+            if let Some(journal) = journal.as_deref_mut() {
+                journal.record_rename(&src, &target)?;
+            }
+            fs::rename(src, target).with_context(|| {
                 format!("Failed to rename file '{src_filename}' to '{target_filename}'")
             })
         })
@@ -372,3 +756,149 @@ pub fn walk_directory(
 
     Ok((files, dirs))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confines_rendered_paths() {
+        assert_eq!(confine_rendered_path("Artist/Album"), "Artist/Album");
+        assert_eq!(confine_rendered_path("/Album"), "Album");
+        assert_eq!(confine_rendered_path("../../etc"), "_/_/etc");
+        assert_eq!(confine_rendered_path("Artist/../Album"), "Artist/_/Album");
+        assert_eq!(confine_rendered_path("./Album/."), "Album");
+        assert_eq!(confine_rendered_path(".."), "_");
+        assert_eq!(confine_rendered_path(""), "");
+    }
+
+    #[test]
+    fn finds_entries_in_another_normalization_form() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let decomposed_dir = temp_dir.path().join(Normalization::Nfd.apply("Sigur Rós"));
+        let decomposed_file = decomposed_dir.join(Normalization::Nfd.apply("Ágætis byrjun.flac"));
+        fs::create_dir(&decomposed_dir).unwrap();
+        fs::write(&decomposed_file, b"song").unwrap();
+
+        let composed_path = temp_dir.path().join("Sigur Rós/Ágætis byrjun.flac");
+        assert_eq!(
+            find_existing_entry(&composed_path).unwrap(),
+            Some(decomposed_file)
+        );
+        assert_eq!(
+            find_existing_entry(&temp_dir.path().join("Sigur Rós/Svefn-g-englar.flac")).unwrap(),
+            None
+        );
+        assert_eq!(
+            copy_file(Path::new("missing"), &composed_path, false, None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn short_names_keep_earlier_names_and_skip_existing_entries() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir(root.join("QUEEN")).unwrap();
+        fs::write(root.join("QUEEN/01BOHEMI.FLA"), b"song").unwrap();
+        fs::write(root.join("QUEEN/02BOHEMI.FLA"), b"other song").unwrap();
+
+        let mut short_names = ShortNames::default();
+        short_names.insert(
+            root,
+            &root.join("Queen/02 Bohemian Rhapsody (Live).flac"),
+            &root.join("QUEEN/02BOHEMI.FLA"),
+        );
+
+        assert_eq!(
+            short_names.shorten(root, &root.join("Queen/01 Bohemian Rhapsody.flac")),
+            root.join("QUEEN/01BOHE~1.FLA")
+        );
+        assert_eq!(
+            short_names.shorten(root, &root.join("Queen/02 Bohemian Rhapsody (Live).flac")),
+            root.join("QUEEN/02BOHEMI.FLA")
+        );
+        assert_eq!(
+            short_names.shorten(root, &root.join("Queen/02 Bohemian Rhapsody.flac")),
+            root.join("QUEEN/02BOHE~1.FLA")
+        );
+    }
+
+    /// Hashes like `hash_file`, but the first `bad_copies` hashes of copies don't match.
+    fn flaky_hash(
+        src: &Path,
+        mut bad_copies: u32,
+    ) -> impl FnMut(&Path) -> anyhow::Result<blake3::Hash> {
+        let src = src.to_path_buf();
+        move |path| {
+            if path != src && bad_copies > 0 {
+                bad_copies -= 1;
+                return Ok(blake3::hash(b"bad copy"));
+            }
+            hash_file(path)
+        }
+    }
+
+    #[test]
+    fn retries_mismatching_copies() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let src = temp_dir.path().join("song.flac");
+        let temp_dest = temp_dir.path().join("copy.tmp");
+        fs::write(&src, b"song").unwrap();
+
+        copy_verified_file(&src, &temp_dest, 2, flaky_hash(&src, 2)).unwrap();
+        assert_eq!(fs::read(&temp_dest).unwrap(), b"song");
+    }
+
+    #[test]
+    fn fails_when_copies_keep_mismatching() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let src = temp_dir.path().join("song.flac");
+        let temp_dest = temp_dir.path().join("copy.tmp");
+        fs::write(&src, b"song").unwrap();
+
+        let err = copy_verified_file(&src, &temp_dest, 2, flaky_hash(&src, 3)).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("does not match after 3 copy attempts")
+        );
+    }
+
+    #[test]
+    fn failed_edits_leave_no_destination() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let src = temp_dir.path().join("song.flac");
+        let dest_dir = temp_dir.path().join("dest");
+        let dest = dest_dir.join("song.flac");
+        fs::write(&src, b"song").unwrap();
+
+        let result = copy_file_with(&src, &dest, false, None, |temp_dest| {
+            fs::write(temp_dest, b"half")?;
+            Err(anyhow!("Interrupted"))
+        });
+        assert!(result.is_err());
+        assert!(is_empty_dir(&dest_dir));
+
+        copy_file_with(&src, &dest, false, None, |temp_dest| {
+            fs::write(temp_dest, b"tagged song")?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"tagged song");
+        assert_eq!(fs::read_dir(&dest_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn sanitizing_replaces_parent_dirs() {
+        let root = Path::new("/mnt/usb");
+
+        assert_eq!(
+            sanitize_pathbuf_for_fat32(root, Path::new("/mnt/usb/../Song?.flac")),
+            Path::new("/mnt/usb/_/Song_.flac")
+        );
+        assert_eq!(
+            normalize_pathbuf(root, Path::new("/mnt/usb/a/../b"), Normalization::Nfc),
+            Path::new("/mnt/usb/a/_/b")
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use anyhow::{Context, anyhow};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::{self, Path, PathBuf},
+};
+
+use crate::{file_utils, progress};
+
+/// A single change of the filesystem. Entries are appended to the journal before the
+/// change is made, so that an interrupted run can be reverted too.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JournalEntry {
+    CreateDir { dir: PathBuf },
+    Rename { from: PathBuf, to: PathBuf },
+    Copy { dest: PathBuf },
+    Overwrite { dest: PathBuf, backup: PathBuf },
+    Delete { dest: PathBuf, backup: PathBuf },
+    RemoveDir { dir: PathBuf },
+}
+
+impl JournalEntry {
+    fn revert(&self) -> anyhow::Result<()> {
+        match self {
+            Self::CreateDir { dir } => {
+                // Directories that contain files which weren't created by the run are kept
+                if file_utils::is_empty_dir(dir) {
+                    fs::remove_dir(dir)?;
+                }
+            }
+            Self::RemoveDir { dir } => fs::create_dir_all(dir)?,
+            Self::Rename { from, to } => {
+                if !to.exists() {
+                    return Ok(());
+                }
+                if from.exists() {
+                    return Err(anyhow!(
+                        "Unable to rename '{}' back to '{}', the file already exists",
+                        to.to_str().unwrap_or("unknown"),
+                        from.to_str().unwrap_or("unknown"),
+                    ));
+                }
+                fs::rename(to, from).with_context(|| {
+                    format!(
+                        "Failed to rename file '{}' back to '{}'",
+                        to.to_str().unwrap_or("unknown"),
+                        from.to_str().unwrap_or("unknown"),
+                    )
+                })?;
+            }
+            Self::Copy { dest } => {
+                if dest.exists() {
+                    fs::remove_file(dest).with_context(|| {
+                        format!(
+                            "Failed to remove file '{}'",
+                            dest.to_str().unwrap_or("unknown")
+                        )
+                    })?;
+                }
+            }
+            Self::Overwrite { dest, backup } | Self::Delete { dest, backup } => {
+                if let Some(parent_dir) = dest.parent() {
+                    fs::create_dir_all(parent_dir)?;
+                }
+                fs::copy(backup, dest).with_context(|| {
+                    format!(
+                        "Failed to restore file '{}' from backup '{}'",
+                        dest.to_str().unwrap_or("unknown"),
+                        backup.to_str().unwrap_or("unknown"),
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Append-only record of the changes made by a command. Every line holds one JSON entry.
+/// Files that get replaced are backed up to the `<journal>.backup` directory.
+pub struct Journal {
+    file: fs::File,
+    backup_dir: PathBuf,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent_dir) = path.parent() {
+            fs::create_dir_all(parent_dir)?;
+        }
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| {
+                format!(
+                    "Unable to open journal '{}'",
+                    path.to_str().unwrap_or("unknown")
+                )
+            })?;
+
+        Ok(Self {
+            file,
+            backup_dir: backup_dir_path(&path::absolute(path)?),
+        })
+    }
+
+    /// Records the rename of `from` to `to`, backing up a file that `to` would replace.
+    pub fn record_rename(&mut self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        if to.exists() {
+            self.record_overwrite(to)?;
+        }
+        self.record_missing_dirs(to)?;
+
+        self.record(&JournalEntry::Rename {
+            from: path::absolute(from)?,
+            to: path::absolute(to)?,
+        })
+    }
+
+    /// Records a copy to `dest`, backing up the file it would replace.
+    pub fn record_copy(&mut self, dest: &Path) -> anyhow::Result<()> {
+        if dest.exists() {
+            return self.record_overwrite(dest);
+        }
+
+        self.record_missing_dirs(dest)?;
+
+        self.record(&JournalEntry::Copy {
+            dest: path::absolute(dest)?,
+        })
+    }
+
+    fn record_missing_dirs(&mut self, dest: &Path) -> anyhow::Result<()> {
+        let dest = path::absolute(dest)?;
+        let mut missing_dirs: Vec<_> = dest
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| !dir.exists())
+            .collect();
+        missing_dirs.reverse();
+        for dir in missing_dirs {
+            self.record(&JournalEntry::CreateDir {
+                dir: dir.to_path_buf(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the deletion of `dest`, backing up the file first.
+    pub fn record_delete(&mut self, dest: &Path) -> anyhow::Result<()> {
+        let backup = self.backup(dest)?;
+
+        self.record(&JournalEntry::Delete {
+            dest: path::absolute(dest)?,
+            backup,
+        })
+    }
+
+    pub fn record_remove_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        self.record(&JournalEntry::RemoveDir {
+            dir: path::absolute(dir)?,
+        })
+    }
+
+    fn record_overwrite(&mut self, dest: &Path) -> anyhow::Result<()> {
+        let backup = self.backup(dest)?;
+
+        self.record(&JournalEntry::Overwrite {
+            dest: path::absolute(dest)?,
+            backup,
+        })
+    }
+
+    fn backup(&self, dest: &Path) -> anyhow::Result<PathBuf> {
+        fs::create_dir_all(&self.backup_dir)?;
+
+        let mut backup_filename = OsString::from(file_utils::random_name(12));
+        if let Some(filename) = dest.file_name() {
+            backup_filename.push("-");
+            backup_filename.push(filename);
+        }
+        let backup = self.backup_dir.join(backup_filename);
+        fs::copy(dest, &backup).with_context(|| {
+            format!(
+                "Failed to back up file '{}'",
+                dest.to_str().unwrap_or("unknown")
+            )
+        })?;
+
+        Ok(backup)
+    }
+
+    fn record(&mut self, entry: &JournalEntry) -> anyhow::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(entry)?)?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+fn backup_dir_path(journal_path: &Path) -> PathBuf {
+    let mut backup_dir = journal_path.as_os_str().to_owned();
+    backup_dir.push(".backup");
+
+    PathBuf::from(backup_dir)
+}
+
+pub fn start_undo(journal_path: &Path) -> anyhow::Result<()> {
+    file_utils::validate_file(journal_path)?;
+
+    let entries = BufReader::new(fs::File::open(journal_path)?)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(i, line)| {
+            serde_json::from_str::<JournalEntry>(&line?)
+                .with_context(|| format!("Invalid journal entry on line {}", i + 1))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let bar = progress::get_progress_bar(entries.len() as u64);
+    bar.set_message("Reverting changes...");
+
+    let result = entries
+        .iter()
+        .rev()
+        .try_for_each(|entry| entry.revert().map(|()| bar.inc(1)));
+    bar.finish();
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn undo_reverts_every_change_in_reverse_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("music");
+        let journal_path = temp_dir.path().join("journal.log");
+        fs::create_dir_all(root.join("gone")).unwrap();
+        fs::write(root.join("overwritten.txt"), b"old").unwrap();
+        fs::write(root.join("renamed.txt"), b"renamed").unwrap();
+        fs::write(root.join("deleted.txt"), b"deleted").unwrap();
+
+        let mut journal = Journal::open(&journal_path).unwrap();
+        // A new file in a new directory, renamed afterwards
+        let copied = root.join("new/copied.txt");
+        journal.record_copy(&copied).unwrap();
+        fs::create_dir_all(root.join("new")).unwrap();
+        fs::write(&copied, b"copied").unwrap();
+        journal
+            .record_rename(&copied, &root.join("new/moved.txt"))
+            .unwrap();
+        fs::rename(&copied, root.join("new/moved.txt")).unwrap();
+
+        journal.record_copy(&root.join("overwritten.txt")).unwrap();
+        fs::write(root.join("overwritten.txt"), b"new").unwrap();
+
+        journal
+            .record_rename(&root.join("renamed.txt"), &root.join("sub/renamed.txt"))
+            .unwrap();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::rename(root.join("renamed.txt"), root.join("sub/renamed.txt")).unwrap();
+
+        journal.record_delete(&root.join("deleted.txt")).unwrap();
+        fs::remove_file(root.join("deleted.txt")).unwrap();
+
+        journal.record_remove_dir(&root.join("gone")).unwrap();
+        fs::remove_dir(root.join("gone")).unwrap();
+        drop(journal);
+
+        start_undo(&journal_path).unwrap();
+
+        let mut entries: Vec<_> = fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            ["deleted.txt", "gone", "overwritten.txt", "renamed.txt"]
+        );
+        assert_eq!(fs::read(root.join("overwritten.txt")).unwrap(), b"old");
+        assert_eq!(fs::read(root.join("renamed.txt")).unwrap(), b"renamed");
+        assert_eq!(fs::read(root.join("deleted.txt")).unwrap(), b"deleted");
+        assert!(root.join("gone").is_dir());
+    }
+
+    #[test]
+    fn backs_up_replaced_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let journal_path = temp_dir.path().join("journal.log");
+        let dest = temp_dir.path().join("song.flac");
+        fs::write(&dest, b"old").unwrap();
+
+        let mut journal = Journal::open(&journal_path).unwrap();
+        journal.record_copy(&dest).unwrap();
+
+        let backups: Vec<_> = fs::read_dir(temp_dir.path().join("journal.log.backup"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].to_string_lossy().ends_with("-song.flac"));
+        assert_eq!(fs::read(&backups[0]).unwrap(), b"old");
+    }
+
+    #[test]
+    fn undo_keeps_directories_with_other_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let journal_path = temp_dir.path().join("journal.log");
+        let dest = temp_dir.path().join("new/song.flac");
+
+        let mut journal = Journal::open(&journal_path).unwrap();
+        journal.record_copy(&dest).unwrap();
+        fs::create_dir_all(temp_dir.path().join("new")).unwrap();
+        fs::write(&dest, b"song").unwrap();
+        fs::write(temp_dir.path().join("new/other.txt"), b"other").unwrap();
+        drop(journal);
+
+        start_undo(&journal_path).unwrap();
+
+        assert!(!dest.exists());
+        assert!(temp_dir.path().join("new/other.txt").exists());
+    }
+}
@@ -1,14 +1,30 @@
-use clap::{Parser, Subcommand};
+use anyhow::anyhow;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 use std::path::PathBuf;
 
 mod audio;
+mod collision;
+mod config;
+mod fat;
 mod file_utils;
+mod journal;
+mod manifest;
+mod mirror;
+mod plan;
+mod profile;
 mod progress;
+mod tags;
+mod template;
+mod verify;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// The config file with the defaults of every command, instead of `ffery.toml` in the
+    /// working directory or in the ffery config directory
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -20,13 +36,39 @@ enum Commands {
         prefix: String,
         #[arg(short = 'e', long)]
         ext: String,
+        #[arg(long)]
+        journal: Option<PathBuf>,
         dir: PathBuf,
     },
     AnalyzeMusic {
         #[arg(short = 'r', long)]
         result: PathBuf,
+        #[arg(long, value_enum, default_value_t = file_utils::Normalization::Nfc)]
+        normalize: file_utils::Normalization,
         src: PathBuf,
     },
+    VerifyMusic {
+        #[arg(short = 'r', long)]
+        result: PathBuf,
+        src: PathBuf,
+    },
+    ApplyPlan {
+        #[arg(long)]
+        journal: Option<PathBuf>,
+        #[arg(long)]
+        state_file: Option<PathBuf>,
+        #[arg(long, action)]
+        force: bool,
+        plan: PathBuf,
+    },
+    Undo {
+        journal: PathBuf,
+    },
+    SortFat {
+        #[arg(long, action)]
+        natural: bool,
+        image: PathBuf,
+    },
     GetAllMetadata {
         #[arg(short = 'r', long)]
         result: PathBuf,
@@ -35,116 +77,501 @@ enum Commands {
     CopyMusic {
         #[arg(short = 's', long)]
         src: PathBuf,
-        #[arg(short = 'd', long)]
-        dest: PathBuf,
-        #[arg(long, default_value_t = 30)]
-        delay_ms: u64,
-        #[arg(short = 'o', long, action)]
-        override_files: bool,
-        #[arg(long, action)]
-        fat_32: bool,
-        #[arg(
-            short = 't',
-            long,
-            default_value_t = String::from("{{#disc_number}}{{{disc_number}}}-{{/disc_number}}{{{track_number}}} {{{title}}}")
-        )]
-        filename_template: String,
-        #[arg(
-            long,
-            default_value_t = String::from("{{src_dir}}")
-        )]
-        dir_template: String,
-        #[arg(long, default_value_t = 2)]
-        pad_width: usize,
-        #[arg(short = 'm', long, value_enum, default_value_t = audio::TrackNumberModification::None)]
-        metadata_track_number_modification: audio::TrackNumberModification,
+        #[command(flatten)]
+        copy: CopyArgs,
+        #[arg(long)]
+        plan_out: Option<PathBuf>,
+        #[arg(long, conflicts_with_all = ["journal", "state_file", "incremental", "mirror"])]
+        image: Option<PathBuf>,
+        #[arg(long)]
+        state_file: Option<PathBuf>,
+        #[command(flatten)]
+        sync: SyncArgs,
     },
     UnzipMusic {
-        #[arg(short = 'd', long)]
-        dest: PathBuf,
-        #[arg(long, default_value_t = 30)]
-        delay_ms: u64,
-        #[arg(short = 'o', long, action)]
-        override_files: bool,
-        #[arg(long, action)]
-        fat_32: bool,
-        #[arg(
-            short = 't',
-            long,
-            default_value_t = String::from("{{#disc_number}}{{{disc_number}}}-{{/disc_number}}{{{track_number}}} {{{title}}}")
-        )]
-        filename_template: String,
-        #[arg(
-            long,
-            default_value_t = String::from("{{src_dir}}")
-        )]
-        dir_template: String,
-        #[arg(long, default_value_t = 2)]
-        pad_width: usize,
-        #[arg(short = 'm', long, value_enum, default_value_t = audio::TrackNumberModification::None)]
-        metadata_track_number_modification: audio::TrackNumberModification,
+        #[command(flatten)]
+        copy: CopyArgs,
         src: PathBuf,
     },
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Prints the effective defaults of every command
+    Show,
+}
+
+/// Options shared by `copy-music` and `unzip-music`.
+#[derive(Args)]
+struct CopyArgs {
+    #[arg(short = 'd', long)]
+    dest: PathBuf,
+    #[arg(long, default_value_t = 30)]
+    delay_ms: u64,
+    #[arg(short = 'o', long, action, overrides_with = "no_override_files")]
+    override_files: bool,
+    #[arg(long, action, overrides_with = "override_files")]
+    no_override_files: bool,
+    #[command(flatten)]
+    fat: FatArgs,
+    #[arg(long, alias = "transliterate", value_enum, num_args = 0..=1, default_missing_value = "paths")]
+    ascii: Option<audio::AsciiMode>,
+    #[arg(long, value_enum)]
+    normalize: Option<file_utils::Normalization>,
+    #[command(flatten)]
+    verify: VerifyArgs,
+    #[arg(long, action)]
+    dry_run: bool,
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = collision::CollisionStrategy::Error)]
+    on_collision: collision::CollisionStrategy,
+    #[arg(long)]
+    collision_report: Option<PathBuf>,
+    #[arg(
+        short = 't',
+        long,
+        default_value_t = String::from("{{#disc_number}}{{{disc_number}}}-{{/disc_number}}{{{track_number}}} {{{title}}}")
+    )]
+    filename_template: String,
+    #[arg(long, default_value_t = String::from("{{src_dir}}"))]
+    dir_template: String,
+    #[arg(long, default_value_t = 2)]
+    pad_width: usize,
+    #[arg(short = 'm', long, value_enum, default_value_t = audio::TrackNumberModification::None)]
+    metadata_track_number_modification: audio::TrackNumberModification,
+    #[arg(long)]
+    profile: Option<String>,
+    #[arg(long, requires = "profile")]
+    profiles_file: Option<PathBuf>,
+}
+
+impl CopyArgs {
+    fn file_options(&self) -> audio::StartCopyFileOptions<'_> {
+        audio::StartCopyFileOptions {
+            filename_template: &self.filename_template,
+            dir_template: &self.dir_template,
+            delay_ms: self.delay_ms,
+            override_files: self.override_files,
+            pad_width: self.pad_width,
+            fat_32: self.fat.fat_32,
+            ascii: self.ascii,
+            normalization: self.normalize,
+            verify_retries: self.verify.verify.then_some(self.verify.verify_retries),
+            dry_run: self.dry_run,
+            plan_out: None,
+            image: None,
+            journal: self.journal.as_deref(),
+            state_file: None,
+            sync: audio::SyncOptions::default(),
+            collision_strategy: self.on_collision,
+            collision_report: self.collision_report.as_deref(),
+            // `--short-names` and `--short-names-map` require each other
+            short_names_map: self.fat.short_names_map.as_deref(),
+        }
+    }
+
+    fn load_profile(&self) -> anyhow::Result<Option<profile::Profile>> {
+        self.profile
+            .as_deref()
+            .map(|name| profile::Profile::load(name, self.profiles_file.as_deref()))
+            .transpose()
+    }
+
+    /// Applies the options of `profile` that weren't given on the command line and the `--no-`
+    /// flags, which also turn off the flags set by the config file.
+    fn apply_profile(&mut self, profile: profile::Profile, matches: &ArgMatches) {
+        profile::merge(&mut self.delay_ms, profile.delay_ms, matches, "delay_ms");
+        profile::merge_flag(
+            &mut self.override_files,
+            profile.override_files,
+            matches,
+            "override_files",
+        );
+        profile::merge_flag(&mut self.fat.fat_32, profile.fat_32, matches, "fat_32");
+        profile::merge_flag(
+            &mut self.fat.short_names,
+            profile.short_names,
+            matches,
+            "short_names",
+        );
+        profile::merge(
+            &mut self.fat.short_names_map,
+            profile.short_names_map.map(Some),
+            matches,
+            "short_names_map",
+        );
+        // The map is only used together with the short names
+        if !self.fat.short_names && self.fat.no_short_names {
+            self.fat.short_names_map = None;
+        }
+        profile::merge(&mut self.ascii, profile.ascii.map(Some), matches, "ascii");
+        profile::merge(
+            &mut self.normalize,
+            profile.normalize.map(Some),
+            matches,
+            "normalize",
+        );
+        profile::merge_flag(&mut self.verify.verify, profile.verify, matches, "verify");
+        profile::merge(
+            &mut self.verify.verify_retries,
+            profile.verify_retries,
+            matches,
+            "verify_retries",
+        );
+        profile::merge(
+            &mut self.on_collision,
+            profile.on_collision,
+            matches,
+            "on_collision",
+        );
+        profile::merge(
+            &mut self.filename_template,
+            profile.filename_template,
+            matches,
+            "filename_template",
+        );
+        profile::merge(
+            &mut self.dir_template,
+            profile.dir_template,
+            matches,
+            "dir_template",
+        );
+        profile::merge(&mut self.pad_width, profile.pad_width, matches, "pad_width");
+        profile::merge(
+            &mut self.metadata_track_number_modification,
+            profile.metadata_track_number_modification,
+            matches,
+            "metadata_track_number_modification",
+        );
+    }
+
+    /// Checks the requirements between the options, clap only checks the ones given on the
+    /// command line.
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.fat.short_names != self.fat.short_names_map.is_some() {
+            return Err(anyhow!(
+                "--short-names and --short-names-map must be used together"
+            ));
+        }
+        if self.profiles_file.is_some() && self.profile.is_none() {
+            return Err(anyhow!("--profiles-file requires --profile"));
+        }
+
+        Ok(())
+    }
+
+    const fn metadata_options(&self) -> audio::CopyMetadataOptions {
+        audio::CopyMetadataOptions {
+            track_number_modification: self.metadata_track_number_modification,
+            ascii_tags: matches!(self.ascii, Some(audio::AsciiMode::All)),
+        }
+    }
+}
+
+// Every flag has a `--no-` counterpart
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Args)]
+struct FatArgs {
+    #[arg(long, action, overrides_with = "no_fat_32")]
+    fat_32: bool,
+    #[arg(long, action, overrides_with = "fat_32")]
+    no_fat_32: bool,
+    #[arg(
+        long,
+        action,
+        requires = "short_names_map",
+        overrides_with = "no_short_names"
+    )]
+    short_names: bool,
+    #[arg(long, action, overrides_with = "short_names")]
+    no_short_names: bool,
+    #[arg(long, requires = "short_names")]
+    short_names_map: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    #[arg(long, action, overrides_with = "no_verify")]
+    verify: bool,
+    #[arg(long, action, overrides_with = "verify")]
+    no_verify: bool,
+    #[arg(long, default_value_t = 3)]
+    verify_retries: u32,
+}
+
+// Every flag has a `--no-` counterpart
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Args)]
+struct SyncArgs {
+    #[arg(long, action, overrides_with = "no_incremental")]
+    incremental: bool,
+    #[arg(long, action, overrides_with = "incremental")]
+    no_incremental: bool,
+    #[arg(long, action, overrides_with = "no_mirror")]
+    mirror: bool,
+    #[arg(long, action, overrides_with = "mirror")]
+    no_mirror: bool,
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(0..=100))]
+    max_delete_percent: u32,
+    #[arg(long, action)]
+    force: bool,
+}
+
+impl SyncArgs {
+    fn apply_profile(&mut self, profile: &profile::Profile, matches: &ArgMatches) {
+        profile::merge_flag(
+            &mut self.incremental,
+            profile.incremental,
+            matches,
+            "incremental",
+        );
+        profile::merge_flag(&mut self.mirror, profile.mirror, matches, "mirror");
+        profile::merge(
+            &mut self.max_delete_percent,
+            profile.max_delete_percent,
+            matches,
+            "max_delete_percent",
+        );
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.max_delete_percent > 100 {
+            return Err(anyhow!(
+                "--max-delete-percent must be between 0 and 100, got {}",
+                self.max_delete_percent
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl From<&SyncArgs> for audio::SyncOptions {
+    fn from(args: &SyncArgs) -> Self {
+        Self {
+            incremental: args.incremental,
+            mirror: args.mirror.then_some(mirror::MirrorOptions {
+                max_delete_percent: args.max_delete_percent,
+                force: args.force,
+            }),
+        }
+    }
+}
+
+/// Applies the device profile selected with `--profile` and the `--no-` flags to the copy
+/// options and checks the merged options. Values from the config file and profiles aren't
+/// checked by clap.
+fn resolve_options(cli: &mut Cli, matches: &ArgMatches) -> anyhow::Result<()> {
+    let Some((_, matches)) = matches.subcommand() else {
+        return Ok(());
+    };
+
+    match &mut cli.command {
+        Commands::CopyMusic {
+            copy,
+            image,
+            state_file,
+            sync,
+            ..
+        } => {
+            let profile = copy.load_profile()?.unwrap_or_default();
+            sync.apply_profile(&profile, matches);
+            copy.apply_profile(profile, matches);
+            copy.validate()?;
+            sync.validate()?;
+            if image.is_some() {
+                let conflicts: Vec<&str> = [
+                    ("--journal", copy.journal.is_some()),
+                    ("--state-file", state_file.is_some()),
+                    ("--incremental", sync.incremental),
+                    ("--mirror", sync.mirror),
+                ]
+                .into_iter()
+                .filter_map(|(option, is_set)| is_set.then_some(option))
+                .collect();
+                if !conflicts.is_empty() {
+                    return Err(anyhow!(
+                        "--image can't be combined with {}",
+                        conflicts.join(", ")
+                    ));
+                }
+            }
+        }
+        Commands::UnzipMusic { copy, .. } => {
+            let profile = copy.load_profile()?.unwrap_or_default();
+            copy.apply_profile(profile, matches);
+            copy.validate()?;
+        }
+        _ => {}
+    }
+
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let config = config::Config::discover(config::config_arg().as_deref())?;
+    let command = match &config {
+        Some(config) => config.apply_defaults(Cli::command())?,
+        None => Cli::command(),
+    };
+    let matches = command.clone().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    resolve_options(&mut cli, &matches)?;
 
     match &cli.command {
-        Commands::RemovePrefix { prefix, ext, dir } => {
-            file_utils::remove_prefix_from_files(prefix, ext, dir)
+        Commands::RemovePrefix {
+            prefix,
+            ext,
+            journal,
+            dir,
+        } => {
+            let mut journal = journal.as_deref().map(journal::Journal::open).transpose()?;
+            file_utils::remove_prefix_from_files(prefix, ext, dir, journal.as_mut())
         }
-        Commands::AnalyzeMusic { result, src } => audio::start_analyze_music(src, result),
+        Commands::AnalyzeMusic {
+            result,
+            normalize,
+            src,
+        } => audio::start_analyze_music(src, result, *normalize),
+        Commands::VerifyMusic { result, src } => verify::start_verify_music(src, result),
+        Commands::ApplyPlan {
+            journal,
+            state_file,
+            force,
+            plan,
+        } => plan::start_apply_plan(plan, journal.as_deref(), state_file.as_deref(), *force),
+        Commands::Undo { journal } => journal::start_undo(journal),
+        Commands::SortFat { natural, image } => fat::start_sort_fat(image, *natural),
         Commands::GetAllMetadata { result, src } => audio::start_get_all_metadata(src, result),
         Commands::CopyMusic {
             src,
-            dest,
-            delay_ms,
-            override_files,
-            filename_template,
-            dir_template,
-            pad_width,
-            metadata_track_number_modification,
-            fat_32,
+            copy,
+            plan_out,
+            image,
+            state_file,
+            sync,
         } => audio::start_copy_music(
             src,
-            dest,
+            &copy.dest,
             &audio::StartCopyFileOptions {
-                filename_template,
-                dir_template,
-                delay_ms: *delay_ms,
-                override_files: *override_files,
-                pad_width: *pad_width,
-                fat_32: *fat_32,
-            },
-            &audio::CopyMetadataOptions {
-                track_number_modification: *metadata_track_number_modification,
+                plan_out: plan_out.as_deref(),
+                image: image.as_deref(),
+                state_file: state_file.as_deref(),
+                sync: audio::SyncOptions::from(sync),
+                ..copy.file_options()
             },
+            &copy.metadata_options(),
         ),
-        Commands::UnzipMusic {
+        Commands::UnzipMusic { copy, src } => audio::start_unzip_music(
             src,
-            dest,
-            delay_ms,
-            override_files,
-            fat_32,
-            filename_template,
-            dir_template,
-            pad_width,
-            metadata_track_number_modification,
-        } => audio::start_unzip_music(
-            src,
-            dest,
-            &audio::StartCopyFileOptions {
-                filename_template,
-                dir_template,
-                delay_ms: *delay_ms,
-                override_files: *override_files,
-                pad_width: *pad_width,
-                fat_32: *fat_32,
-            },
-            &audio::CopyMetadataOptions {
-                track_number_modification: *metadata_track_number_modification,
-            },
+            &copy.dest,
+            &copy.file_options(),
+            &copy.metadata_options(),
         ),
+        Commands::Config {
+            command: ConfigCommands::Show,
+        } => {
+            config::start_config_show(&command, config.as_ref());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const CONFIG: &str = "[copy-music]\ndelay-ms = 100\nverify = true\nmirror = true\n";
+    const PROFILES: &str = "[profiles.usb]\ndelay-ms = 200\nfat-32 = true\nverify = true\n";
+
+    /// Parses a `copy-music` command line like `main` does, with `config` as `ffery.toml`.
+    fn parse(config: Option<&str>, args: &[&str]) -> (CopyArgs, SyncArgs) {
+        let temp_dir = TempDir::new().unwrap();
+        let config_path = temp_dir.path().join(config::CONFIG_FILENAME);
+        let profiles_path = temp_dir.path().join("profiles.toml");
+        fs::write(&profiles_path, PROFILES).unwrap();
+
+        let command = config.map_or_else(Cli::command, |config| {
+            fs::write(&config_path, config).unwrap();
+            let config = config::Config::discover(Some(&config_path))
+                .unwrap()
+                .unwrap();
+            config.apply_defaults(Cli::command()).unwrap()
+        });
+        let mut all_args = vec![
+            "ffery",
+            "copy-music",
+            "--src",
+            "/music",
+            "--dest",
+            "/mnt/usb",
+        ];
+        all_args.extend(args);
+        if args.contains(&"--profile") {
+            all_args.extend(["--profiles-file", profiles_path.to_str().unwrap()]);
+        }
+        let matches = command.try_get_matches_from(all_args).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        resolve_options(&mut cli, &matches).unwrap();
+
+        let Commands::CopyMusic { copy, sync, .. } = cli.command else {
+            panic!("Expected the copy-music command");
+        };
+        (copy, sync)
+    }
+
+    #[test]
+    fn command_line_beats_profile_beats_config_beats_default() {
+        assert_eq!(parse(None, &[]).0.delay_ms, 30);
+        assert_eq!(parse(Some(CONFIG), &[]).0.delay_ms, 100);
+        assert_eq!(parse(Some(CONFIG), &["--profile", "usb"]).0.delay_ms, 200);
+        assert_eq!(
+            parse(Some(CONFIG), &["--profile", "usb", "--delay-ms", "300"])
+                .0
+                .delay_ms,
+            300
+        );
+        assert_eq!(parse(Some(CONFIG), &["--delay-ms", "0"]).0.delay_ms, 0);
+    }
+
+    #[test]
+    fn negations_turn_off_flags_of_the_config_and_profile() {
+        let (copy, sync) = parse(Some(CONFIG), &[]);
+        assert!(copy.verify.verify);
+        assert!(sync.mirror);
+
+        let (copy, sync) = parse(Some(CONFIG), &["--no-verify", "--no-mirror"]);
+        assert!(!copy.verify.verify);
+        assert!(!sync.mirror);
+
+        let (copy, _) = parse(Some(CONFIG), &["--profile", "usb", "--no-verify"]);
+        assert!(!copy.verify.verify);
+
+        let (copy, _) = parse(None, &["--profile", "usb"]);
+        assert!(copy.fat.fat_32);
+        let (copy, _) = parse(None, &["--profile", "usb", "--no-fat-32"]);
+        assert!(!copy.fat.fat_32);
+
+        // The last of a flag and its counterpart wins
+        assert!(
+            !parse(Some(CONFIG), &["--verify", "--no-verify"])
+                .0
+                .verify
+                .verify
+        );
+        assert!(
+            parse(Some(CONFIG), &["--no-verify", "--verify"])
+                .0
+                .verify
+                .verify
+        );
     }
 }
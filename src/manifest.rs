@@ -0,0 +1,418 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{file_utils, plan::CopyOperation};
+
+/// Name of the manifest stored in the root of the destination. It is hidden, so
+/// `--mirror` never deletes it.
+const MANIFEST_FILENAME: &str = ".ffery-manifest.json";
+
+/// Record of the files written by previous incremental runs, so that later runs only
+/// copy what changed.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    /// Relative to the source directory
+    src: PathBuf,
+    /// Rendered destination, relative to the destination directory
+    dest: PathBuf,
+    mtime: SystemTime,
+    size: u64,
+    /// BLAKE3 hash of the source file
+    hash: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tag_changes: BTreeMap<String, Vec<String>>,
+}
+
+/// Manifest to store in `dest` once the plan is executed.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestUpdate {
+    dest: PathBuf,
+    manifest: Manifest,
+}
+
+impl ManifestUpdate {
+    /// The destinations of every file of the new state, including the unchanged ones that
+    /// aren't copied again.
+    pub fn dests(&self) -> impl Iterator<Item = PathBuf> {
+        self.manifest
+            .entries
+            .iter()
+            .map(|entry| self.dest.join(&entry.dest))
+    }
+
+    /// The path the manifest is stored at.
+    pub fn path(&self) -> PathBuf {
+        self.dest.join(MANIFEST_FILENAME)
+    }
+
+    pub fn store(&self) -> anyhow::Result<()> {
+        let json_data = serde_json::to_string(&self.manifest)?;
+
+        file_utils::store_data(&self.path(), &json_data)
+    }
+}
+
+impl ManifestEntry {
+    /// The hash of `previous` is reused if the size and mtime of the source didn't change.
+    fn new(
+        (src_path, relative_src): (&Path, &Path),
+        relative_dest: &Path,
+        tag_changes: BTreeMap<String, Vec<String>>,
+        previous: Option<&Self>,
+    ) -> anyhow::Result<Self> {
+        let metadata = fs::metadata(src_path)?;
+        let mtime = metadata.modified()?;
+        let size = metadata.len();
+        let hash = match previous {
+            Some(previous) if previous.size == size && previous.mtime == mtime => {
+                previous.hash.clone()
+            }
+            _ => file_utils::hash_file(src_path)?.to_hex().to_string(),
+        };
+
+        Ok(Self {
+            src: relative_src.to_path_buf(),
+            dest: relative_dest.to_path_buf(),
+            mtime,
+            size,
+            hash,
+            tag_changes,
+        })
+    }
+}
+
+impl Manifest {
+    fn read(dest: &Path) -> anyhow::Result<Self> {
+        let manifest_path = dest.join(MANIFEST_FILENAME);
+        if !manifest_path.exists() {
+            return Ok(Self::default());
+        }
+
+        let json_data = fs::read_to_string(&manifest_path)?;
+        serde_json::from_str(&json_data).with_context(|| {
+            format!(
+                "Invalid manifest '{}'",
+                manifest_path.to_str().unwrap_or("unknown")
+            )
+        })
+    }
+}
+
+/// Compares the planned copies with the manifest of the previous run. Unchanged files are
+/// dropped from `operations`, files whose destination changed are moved, and only new or
+/// changed files are copied again. Returns the manifest update for the new state.
+pub fn plan_incremental_copy(
+    src: &Path,
+    dest: &Path,
+    operations: &mut Vec<CopyOperation>,
+) -> anyhow::Result<ManifestUpdate> {
+    let previous_manifest = Manifest::read(dest)?;
+    let previous_by_src: HashMap<&Path, &ManifestEntry> = previous_manifest
+        .entries
+        .iter()
+        .map(|entry| (entry.src.as_path(), entry))
+        .collect();
+    // Identical files share a hash, every one of them can be matched once
+    let mut previous_by_hash: HashMap<&str, Vec<&ManifestEntry>> = HashMap::new();
+    for entry in &previous_manifest.entries {
+        previous_by_hash
+            .entry(entry.hash.as_str())
+            .or_default()
+            .push(entry);
+    }
+
+    // A previous destination can only be moved once, and not if it gets overwritten
+    let mut unmovable_dests: HashSet<PathBuf> = operations
+        .iter()
+        .map(|operation| operation.dest().to_path_buf())
+        .collect();
+
+    let mut manifest = Manifest::default();
+    let mut incremental_operations = vec![];
+    let mut outdated_dests = vec![];
+
+    for operation in operations.drain(..) {
+        let (src_path, dest_path, tag_changes) = match &operation {
+            CopyOperation::Song {
+                src,
+                dest,
+                tag_changes,
+            } => (src, dest, tag_changes.clone()),
+            CopyOperation::File { src, dest } => (src, dest, BTreeMap::new()),
+            CopyOperation::Move { .. }
+            | CopyOperation::Delete { .. }
+            | CopyOperation::RemoveDir { .. } => {
+                incremental_operations.push(operation);
+                continue;
+            }
+        };
+
+        let relative_src = src_path.strip_prefix(src).unwrap_or(src_path);
+        let previous = previous_by_src.get(relative_src).copied();
+        let entry = ManifestEntry::new(
+            (src_path, relative_src),
+            dest_path.strip_prefix(dest).unwrap_or(dest_path),
+            tag_changes,
+            previous,
+        )?;
+
+        // The same content may have been copied from a source that was moved or renamed
+        let unchanged = previous
+            .filter(|previous| previous.hash == entry.hash)
+            .or_else(|| {
+                let candidates = previous_by_hash.get(entry.hash.as_str())?;
+                find_same_content(candidates, &entry, dest, &unmovable_dests)
+            })
+            .filter(|previous| previous.tag_changes == entry.tag_changes);
+
+        if let Some(unchanged) = unchanged {
+            let previous_dest = dest.join(&unchanged.dest);
+            if unchanged.dest == entry.dest && dest_path.exists() {
+                manifest.entries.push(entry);
+                continue;
+            }
+            if previous_dest.exists()
+                && !dest_path.exists()
+                && unmovable_dests.insert(previous_dest.clone())
+            {
+                incremental_operations.push(CopyOperation::Move {
+                    src: previous_dest,
+                    dest: dest_path.clone(),
+                });
+                manifest.entries.push(entry);
+                continue;
+            }
+        } else if let Some(previous) = previous {
+            // A changed file whose destination got renamed, e.g. by a tag edit, is copied
+            // again, and its old destination is deleted so that it doesn't stay behind
+            let previous_dest = dest.join(&previous.dest);
+            if previous.dest != entry.dest
+                && previous_dest.exists()
+                && unmovable_dests.insert(previous_dest.clone())
+            {
+                outdated_dests.push(previous_dest);
+            }
+        } else if entry.tag_changes.is_empty()
+            && dest_path.is_file()
+            && file_utils::hash_file(dest_path)?.to_hex().as_str() == entry.hash
+        {
+            // Files copied before the manifest existed are adopted
+            manifest.entries.push(entry);
+            continue;
+        }
+
+        manifest.entries.push(entry);
+        incremental_operations.push(operation);
+    }
+
+    // Deletions run after the copies, like the ones of mirror mode
+    incremental_operations.extend(
+        outdated_dests
+            .into_iter()
+            .map(|dest| CopyOperation::Delete { dest }),
+    );
+    *operations = incremental_operations;
+
+    Ok(ManifestUpdate {
+        dest: dest.to_path_buf(),
+        manifest,
+    })
+}
+
+/// Picks the previous entry with the content of `entry` that it can take over: the one that
+/// already has its destination, otherwise one whose destination can still be moved.
+fn find_same_content<'a>(
+    candidates: &[&'a ManifestEntry],
+    entry: &ManifestEntry,
+    dest: &Path,
+    unmovable_dests: &HashSet<PathBuf>,
+) -> Option<&'a ManifestEntry> {
+    let candidates = candidates
+        .iter()
+        .copied()
+        .filter(|candidate| candidate.tag_changes == entry.tag_changes);
+
+    candidates
+        .clone()
+        .find(|candidate| candidate.dest == entry.dest)
+        .or_else(|| {
+            candidates.clone().find(|candidate| {
+                let candidate_dest = dest.join(&candidate.dest);
+                candidate_dest.exists() && !unmovable_dests.contains(&candidate_dest)
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn copy_operation(root: &Path, src: &str, dest: &str) -> CopyOperation {
+        CopyOperation::File {
+            src: root.join("src").join(src),
+            dest: root.join("dest").join(dest),
+        }
+    }
+
+    /// Plans an incremental copy of `operations`, executes it and returns the executed
+    /// operations relative to `root`.
+    fn run(root: &Path, mut operations: Vec<CopyOperation>) -> Vec<String> {
+        let dest = root.join("dest");
+        let manifest = plan_incremental_copy(&root.join("src"), &dest, &mut operations).unwrap();
+
+        let relative = |path: &Path| path.strip_prefix(root).unwrap().display().to_string();
+        let executed = operations
+            .iter()
+            .map(|operation| match operation {
+                CopyOperation::Song { src, dest, .. } | CopyOperation::File { src, dest } => {
+                    fs::create_dir_all(dest.parent().unwrap()).unwrap();
+                    fs::copy(src, dest).unwrap();
+                    format!("copy {} to {}", relative(src), relative(dest))
+                }
+                CopyOperation::Move { src, dest } => {
+                    fs::create_dir_all(dest.parent().unwrap()).unwrap();
+                    fs::rename(src, dest).unwrap();
+                    format!("move {} to {}", relative(src), relative(dest))
+                }
+                CopyOperation::Delete { dest } => {
+                    fs::remove_file(dest).unwrap();
+                    format!("delete {}", relative(dest))
+                }
+                CopyOperation::RemoveDir { dest } => format!("remove {}", relative(dest)),
+            })
+            .collect();
+        manifest.store().unwrap();
+
+        executed
+    }
+
+    fn setup() -> TempDir {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir_all(temp_dir.path().join("src")).unwrap();
+        fs::write(temp_dir.path().join("src/a.flac"), b"song a").unwrap();
+        fs::write(temp_dir.path().join("src/b.flac"), b"song b").unwrap();
+
+        temp_dir
+    }
+
+    #[test]
+    fn skips_unchanged_files() {
+        let temp_dir = setup();
+        let root = temp_dir.path();
+        let operations = || {
+            vec![
+                copy_operation(root, "a.flac", "A/a.flac"),
+                copy_operation(root, "b.flac", "B/b.flac"),
+            ]
+        };
+
+        assert_eq!(
+            run(root, operations()),
+            [
+                "copy src/a.flac to dest/A/a.flac",
+                "copy src/b.flac to dest/B/b.flac"
+            ]
+        );
+        assert!(run(root, operations()).is_empty());
+    }
+
+    #[test]
+    fn moves_files_whose_destination_changed() {
+        let temp_dir = setup();
+        let root = temp_dir.path();
+        run(root, vec![copy_operation(root, "a.flac", "A/a.flac")]);
+
+        fs::rename(root.join("src/a.flac"), root.join("src/renamed.flac")).unwrap();
+        assert_eq!(
+            run(
+                root,
+                vec![copy_operation(root, "renamed.flac", "A/renamed.flac")]
+            ),
+            ["move dest/A/a.flac to dest/A/renamed.flac"]
+        );
+        assert_eq!(
+            fs::read(root.join("dest/A/renamed.flac")).unwrap(),
+            b"song a"
+        );
+    }
+
+    #[test]
+    fn moves_every_identical_file() {
+        let temp_dir = setup();
+        let root = temp_dir.path();
+        fs::write(root.join("src/b.flac"), b"song a").unwrap();
+        run(
+            root,
+            vec![
+                copy_operation(root, "a.flac", "A/a.flac"),
+                copy_operation(root, "b.flac", "B/b.flac"),
+            ],
+        );
+
+        fs::rename(root.join("src/a.flac"), root.join("src/c.flac")).unwrap();
+        fs::rename(root.join("src/b.flac"), root.join("src/d.flac")).unwrap();
+        assert_eq!(
+            run(
+                root,
+                vec![
+                    copy_operation(root, "c.flac", "C/c.flac"),
+                    copy_operation(root, "d.flac", "D/d.flac"),
+                ]
+            ),
+            [
+                "move dest/A/a.flac to dest/C/c.flac",
+                "move dest/B/b.flac to dest/D/d.flac"
+            ]
+        );
+    }
+
+    #[test]
+    fn recopies_changed_files_and_deletes_their_old_destination() {
+        let temp_dir = setup();
+        let root = temp_dir.path();
+        run(root, vec![copy_operation(root, "a.flac", "A/a.flac")]);
+
+        fs::write(root.join("src/a.flac"), b"retagged song a").unwrap();
+        assert_eq!(
+            run(root, vec![copy_operation(root, "a.flac", "A2/a.flac")]),
+            ["copy src/a.flac to dest/A2/a.flac", "delete dest/A/a.flac"]
+        );
+        assert!(!root.join("dest/A/a.flac").exists());
+    }
+
+    #[test]
+    fn adopts_files_copied_without_a_manifest() {
+        let temp_dir = setup();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("dest/A")).unwrap();
+        fs::write(root.join("dest/A/a.flac"), b"song a").unwrap();
+        fs::create_dir_all(root.join("dest/B")).unwrap();
+        fs::write(root.join("dest/B/b.flac"), b"other song").unwrap();
+
+        assert_eq!(
+            run(
+                root,
+                vec![
+                    copy_operation(root, "a.flac", "A/a.flac"),
+                    copy_operation(root, "b.flac", "B/b.flac"),
+                ]
+            ),
+            ["copy src/b.flac to dest/B/b.flac"]
+        );
+        assert!(Manifest::read(&root.join("dest")).unwrap().entries.len() == 2);
+    }
+}
@@ -0,0 +1,305 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::plan::CopyOperation;
+
+/// Entries the devices or operating systems keep for themselves, they are never deleted.
+const SYSTEM_DIR_NAMES: &[&str] = &["System Volume Information", "LOST.DIR"];
+
+#[derive(Serialize)]
+pub struct MirrorOptions {
+    pub max_delete_percent: u32,
+    pub force: bool,
+}
+
+/// The deletion limit of a mirror run. It is stored in the plan, so that `apply-plan` checks
+/// it against the destination as it is when the plan is applied.
+#[derive(Serialize, Deserialize)]
+pub struct DeleteLimit {
+    pub dest: PathBuf,
+    pub max_delete_percent: u32,
+}
+
+impl DeleteLimit {
+    /// Fails if the deletions of `operations` exceed the limit.
+    pub fn check(&self, operations: &[CopyOperation]) -> anyhow::Result<()> {
+        let delete_count = operations
+            .iter()
+            .filter(
+                |operation| matches!(operation, CopyOperation::Delete { dest } if dest.exists()),
+            )
+            .count();
+
+        check_threshold(
+            delete_count,
+            count_files(&self.dest)?,
+            self.max_delete_percent,
+        )
+    }
+}
+
+pub struct StaleEntries {
+    files: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+    existing_file_count: usize,
+}
+
+impl StaleEntries {
+    /// Finds the files and directories in `dest` that aren't written by `operations` and
+    /// aren't one of the `unchanged_files` left in place by an incremental run. The
+    /// `kept_files`, e.g. the journal of the run, are never stale.
+    pub fn find(
+        dest: &Path,
+        operations: &[CopyOperation],
+        unchanged_files: &[PathBuf],
+        kept_files: &[&Path],
+    ) -> anyhow::Result<Self> {
+        let mut stale_entries = Self {
+            files: vec![],
+            dirs: vec![],
+            existing_file_count: 0,
+        };
+        if !dest.is_dir() {
+            return Ok(stale_entries);
+        }
+
+        let expected_files: HashSet<&Path> = operations
+            .iter()
+            .filter(|operation| {
+                matches!(
+                    operation,
+                    CopyOperation::Song { .. }
+                        | CopyOperation::File { .. }
+                        | CopyOperation::Move { .. }
+                )
+            })
+            .map(CopyOperation::dest)
+            .chain(unchanged_files.iter().map(PathBuf::as_path))
+            .collect();
+        let expected_dirs: HashSet<&Path> = expected_files
+            .iter()
+            .flat_map(|f| f.ancestors().skip(1))
+            .collect();
+        // Moved and deleted files are already gone once the stale entries are deleted
+        let removed_files: HashSet<&Path> = operations
+            .iter()
+            .filter_map(|operation| match operation {
+                CopyOperation::Move { src, .. } => Some(src.as_path()),
+                CopyOperation::Delete { dest } => Some(dest.as_path()),
+                _ => None,
+            })
+            .collect();
+
+        let kept_files = kept_files
+            .iter()
+            .map(std::path::absolute)
+            .collect::<Result<HashSet<PathBuf>, io::Error>>()?;
+
+        stale_entries.collect(
+            dest,
+            (&expected_files, &removed_files),
+            &expected_dirs,
+            &kept_files,
+        )?;
+
+        Ok(stale_entries)
+    }
+
+    /// Walks `dir` and returns `true` if nothing is left in it once the stale entries are gone.
+    fn collect(
+        &mut self,
+        dir: &Path,
+        (expected_files, removed_files): (&HashSet<&Path>, &HashSet<&Path>),
+        expected_dirs: &HashSet<&Path>,
+        kept_files: &HashSet<PathBuf>,
+    ) -> anyhow::Result<bool> {
+        let mut entries: Vec<_> = fs::read_dir(dir)?
+            .map(|entry_result| entry_result.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, io::Error>>()?;
+        entries.sort();
+
+        let mut is_left_empty = true;
+        for entry in entries {
+            if is_system_entry(&entry) || kept_files.contains(&std::path::absolute(&entry)?) {
+                is_left_empty = false;
+            } else if entry.is_dir() {
+                if self.collect(
+                    &entry,
+                    (expected_files, removed_files),
+                    expected_dirs,
+                    kept_files,
+                )? && !expected_dirs.contains(entry.as_path())
+                {
+                    self.dirs.push(entry);
+                } else {
+                    is_left_empty = false;
+                }
+            } else {
+                self.existing_file_count += 1;
+                if expected_files.contains(entry.as_path()) {
+                    is_left_empty = false;
+                } else if !removed_files.contains(entry.as_path()) {
+                    self.files.push(entry);
+                }
+            }
+        }
+
+        Ok(is_left_empty)
+    }
+
+    /// Fails if more than `max_delete_percent` of the existing files would be deleted.
+    pub fn check_threshold(&self, max_delete_percent: u32) -> anyhow::Result<()> {
+        check_threshold(
+            self.files.len(),
+            self.existing_file_count,
+            max_delete_percent,
+        )
+    }
+
+    /// Deletions run after the copies, so the directory entries they free can't be taken
+    /// by new files, which would break the write order.
+    pub fn into_operations(self) -> impl Iterator<Item = CopyOperation> {
+        let files = self
+            .files
+            .into_iter()
+            .map(|dest| CopyOperation::Delete { dest });
+        // Directories were collected after their subdirectories
+        let dirs = self
+            .dirs
+            .into_iter()
+            .map(|dest| CopyOperation::RemoveDir { dest });
+
+        files.chain(dirs)
+    }
+}
+
+fn check_threshold(
+    delete_count: usize,
+    existing_file_count: usize,
+    max_delete_percent: u32,
+) -> anyhow::Result<()> {
+    if delete_count * 100 > existing_file_count * max_delete_percent as usize {
+        return Err(anyhow!(
+            "Refusing to delete {delete_count} of {existing_file_count} files in the destination (more than {max_delete_percent}%), use --force to delete them anyway"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Counts the files in `dir` that mirror mode may delete.
+fn count_files(dir: &Path) -> anyhow::Result<usize> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+
+    let mut count = 0;
+    for entry_result in fs::read_dir(dir)? {
+        let entry = entry_result?.path();
+        if is_system_entry(&entry) {
+            continue;
+        }
+        if entry.is_dir() {
+            count += count_files(&entry)?;
+        } else {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+fn is_system_entry(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|filename| filename.to_str())
+        .is_none_or(|filename| filename.starts_with('.') || SYSTEM_DIR_NAMES.contains(&filename))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn dest_with_files(filenames: &[&str]) -> TempDir {
+        let dest = TempDir::new().unwrap();
+        for filename in filenames {
+            let path = dest.path().join(filename);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, filename).unwrap();
+        }
+
+        dest
+    }
+
+    fn copy_to(dest: &Path, filename: &str) -> CopyOperation {
+        CopyOperation::File {
+            src: PathBuf::from("/music").join(filename),
+            dest: dest.join(filename),
+        }
+    }
+
+    #[test]
+    fn finds_stale_files_and_emptied_dirs() {
+        let dest = dest_with_files(&["Album/01.flac", "Old/01.flac", ".hidden", "LOST.DIR/x"]);
+        let operations = [copy_to(dest.path(), "Album/01.flac")];
+
+        let stale_entries = StaleEntries::find(dest.path(), &operations, &[], &[]).unwrap();
+        assert_eq!(stale_entries.files, [dest.path().join("Old/01.flac")]);
+        assert_eq!(stale_entries.dirs, [dest.path().join("Old")]);
+        assert_eq!(stale_entries.existing_file_count, 2);
+    }
+
+    #[test]
+    fn skips_unchanged_and_moved_files() {
+        let dest = dest_with_files(&["Album/01.flac", "Old/02.flac", "Old/03.flac"]);
+        let operations = [
+            CopyOperation::Move {
+                src: dest.path().join("Old/02.flac"),
+                dest: dest.path().join("Album/02.flac"),
+            },
+            CopyOperation::Delete {
+                dest: dest.path().join("Old/03.flac"),
+            },
+        ];
+        let unchanged_files = [dest.path().join("Album/01.flac")];
+
+        let stale_entries =
+            StaleEntries::find(dest.path(), &operations, &unchanged_files, &[]).unwrap();
+        assert!(stale_entries.files.is_empty());
+        assert_eq!(stale_entries.dirs, [dest.path().join("Old")]);
+        assert!(stale_entries.check_threshold(0).is_ok());
+    }
+
+    #[test]
+    fn keeps_files_written_by_the_run() {
+        let dest = dest_with_files(&["01.flac", "journal.jsonl", "state.jsonl"]);
+        let journal = dest.path().join("journal.jsonl");
+        let state = dest.path().join("state.jsonl");
+        let operations = [copy_to(dest.path(), "01.flac")];
+
+        let stale_entries =
+            StaleEntries::find(dest.path(), &operations, &[], &[&journal, &state]).unwrap();
+        assert!(stale_entries.files.is_empty());
+    }
+
+    #[test]
+    fn delete_limit_counts_the_current_destination() {
+        let dest = dest_with_files(&["01.flac", "02.flac", "03.flac", "04.flac"]);
+        let operations = [CopyOperation::Delete {
+            dest: dest.path().join("01.flac"),
+        }];
+        let delete_limit = |max_delete_percent| DeleteLimit {
+            dest: dest.path().to_path_buf(),
+            max_delete_percent,
+        };
+
+        assert!(delete_limit(10).check(&operations).is_err());
+        assert!(delete_limit(25).check(&operations).is_ok());
+    }
+}
@@ -0,0 +1,558 @@
+use anyhow::{Context, anyhow};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::Duration,
+};
+
+use crate::{
+    fat, file_utils, journal::Journal, manifest::ManifestUpdate, mirror::DeleteLimit, progress,
+    tags,
+};
+
+/// Ordered list of copy operations produced by the planning phase of `copy-music`.
+/// It is stored as JSON so that it can be reviewed and edited before `apply-plan`
+/// executes it.
+#[derive(Serialize, Deserialize)]
+pub struct CopyPlan {
+    #[serde(flatten)]
+    pub options: ExecutionOptions,
+    pub operations: Vec<CopyOperation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<ManifestUpdate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_limit: Option<DeleteLimit>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CopyOperation {
+    Song {
+        src: PathBuf,
+        dest: PathBuf,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        tag_changes: BTreeMap<String, Vec<String>>,
+    },
+    File {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    /// Moves a file copied by a previous incremental run to its new destination.
+    Move {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    /// Deletes a stale file in mirror mode.
+    Delete {
+        dest: PathBuf,
+    },
+    /// Removes a stale directory in mirror mode, unless it isn't empty.
+    RemoveDir {
+        dest: PathBuf,
+    },
+}
+
+impl CopyOperation {
+    pub fn dest(&self) -> &Path {
+        match self {
+            Self::Song { dest, .. }
+            | Self::File { dest, .. }
+            | Self::Move { dest, .. }
+            | Self::Delete { dest }
+            | Self::RemoveDir { dest } => dest,
+        }
+    }
+
+    pub const fn dest_mut(&mut self) -> &mut PathBuf {
+        match self {
+            Self::Song { dest, .. }
+            | Self::File { dest, .. }
+            | Self::Move { dest, .. }
+            | Self::Delete { dest }
+            | Self::RemoveDir { dest } => dest,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExecutionOptions {
+    pub delay_ms: u64,
+    pub override_files: bool,
+    pub verify_retries: Option<u32>,
+    /// FAT32 image or device the plan is written to, the destinations are paths inside it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<PathBuf>,
+}
+
+impl CopyPlan {
+    pub const fn new(options: ExecutionOptions) -> Self {
+        Self {
+            options,
+            operations: vec![],
+            manifest: None,
+            delete_limit: None,
+        }
+    }
+
+    pub fn execute(
+        &self,
+        bar: &ProgressBar,
+        mut journal: Option<&mut Journal>,
+        mut state: Option<&mut PlanState>,
+    ) -> anyhow::Result<()> {
+        let start = state.as_deref().map_or(0, |state| state.next_operation);
+        bar.inc(start as u64);
+
+        for (i, operation) in self.operations.iter().enumerate().skip(start) {
+            if let Some(journal) = journal.as_deref_mut() {
+                self.record_operation(journal, operation)?;
+            }
+
+            self.execute_operation(operation)?;
+            if let Some(state) = state.as_deref_mut() {
+                state.record(&StateEntry::Finished(i))?;
+            }
+
+            bar.inc(1);
+        }
+
+        Ok(())
+    }
+
+    fn record_operation(
+        &self,
+        journal: &mut Journal,
+        operation: &CopyOperation,
+    ) -> anyhow::Result<()> {
+        let dest = operation.dest();
+
+        match operation {
+            CopyOperation::Song { .. } | CopyOperation::File { .. } => {
+                match file_utils::find_existing_entry(dest)? {
+                    None => journal.record_copy(dest)?,
+                    Some(existing_dest) if self.options.override_files => {
+                        journal.record_copy(dest)?;
+                        // The copy replaces the one in another normalization form
+                        if existing_dest != dest {
+                            journal.record_delete(&existing_dest)?;
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+            CopyOperation::Move { src, .. } => journal.record_rename(src, dest)?,
+            CopyOperation::Delete { .. } => {
+                if dest.exists() {
+                    journal.record_delete(dest)?;
+                }
+            }
+            CopyOperation::RemoveDir { .. } => {
+                if file_utils::is_empty_dir(dest) {
+                    journal.record_remove_dir(dest)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn execute_operation(&self, operation: &CopyOperation) -> anyhow::Result<()> {
+        let options = &self.options;
+
+        match operation {
+            CopyOperation::Song {
+                src,
+                dest,
+                tag_changes,
+            } => {
+                // The copy is verified before the tag edit changes its bytes
+                let dest = file_utils::copy_file_with(
+                    src,
+                    dest,
+                    options.override_files,
+                    options.verify_retries,
+                    |temp_dest| apply_tag_changes(temp_dest, tag_changes),
+                )?;
+                if dest.is_some() {
+                    sleep(Duration::from_millis(options.delay_ms));
+                }
+            }
+            CopyOperation::File { src, dest } => {
+                file_utils::copy_file(src, dest, options.override_files, options.verify_retries)?;
+            }
+            CopyOperation::Move { src, dest } => {
+                if let Some(parent_dir) = dest.parent() {
+                    fs::create_dir_all(parent_dir)?;
+                }
+                fs::rename(src, dest).with_context(|| {
+                    format!(
+                        "Failed to move file '{}' to '{}'",
+                        src.to_str().unwrap_or("unknown"),
+                        dest.to_str().unwrap_or("unknown"),
+                    )
+                })?;
+            }
+            CopyOperation::Delete { dest } => {
+                if dest.exists() {
+                    fs::remove_file(dest).with_context(|| {
+                        format!(
+                            "Failed to delete file '{}'",
+                            dest.to_str().unwrap_or("unknown")
+                        )
+                    })?;
+                }
+            }
+            CopyOperation::RemoveDir { dest } => {
+                if file_utils::is_empty_dir(dest) {
+                    fs::remove_dir(dest).with_context(|| {
+                        format!(
+                            "Failed to remove directory '{}'",
+                            dest.to_str().unwrap_or("unknown")
+                        )
+                    })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The command a state file was created by. Only the same command resumes the run, another
+/// one would execute the stored plan with different paths and options.
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Invocation {
+    CopyMusic {
+        src: PathBuf,
+        dest: PathBuf,
+        options: serde_json::Value,
+    },
+    ApplyPlan {
+        plan: PathBuf,
+    },
+}
+
+/// The first line of a state file.
+#[derive(Serialize, Deserialize)]
+struct StateHeader<I, P> {
+    invocation: I,
+    plan: P,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StateEntry {
+    Finished(usize),
+}
+
+/// Progress of a run, stored so that an interrupted run resumes at the operation where it
+/// stopped. The first line of the state file holds the [`Invocation`] and the plan, so the
+/// resumed run keeps the original write order. Every following line holds one [`StateEntry`].
+pub struct PlanState {
+    path: PathBuf,
+    file: fs::File,
+    next_operation: usize,
+}
+
+impl PlanState {
+    /// Creates the state file for a new run of `copy_plan` by `invocation`.
+    pub fn create(
+        path: &Path,
+        invocation: &Invocation,
+        copy_plan: &CopyPlan,
+    ) -> anyhow::Result<Self> {
+        if let Some(parent_dir) = path.parent() {
+            fs::create_dir_all(parent_dir)?;
+        }
+
+        let header = StateHeader {
+            invocation,
+            plan: copy_plan,
+        };
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        file.sync_data()?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            next_operation: 0,
+        })
+    }
+
+    /// Reads the state file of an interrupted run, returns `None` if there is none. Fails if
+    /// the run was started by another invocation.
+    pub fn resume(
+        path: &Path,
+        invocation: &Invocation,
+    ) -> anyhow::Result<Option<(Self, CopyPlan)>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let json_data = fs::read_to_string(path)?;
+        let mut lines = json_data.lines().filter(|line| !line.trim().is_empty());
+        let header: StateHeader<Invocation, CopyPlan> =
+            serde_json::from_str(lines.next().unwrap_or_default()).with_context(|| {
+                format!(
+                    "Invalid state file '{}'",
+                    path.to_str().unwrap_or("unknown")
+                )
+            })?;
+        if header.invocation != *invocation {
+            return Err(anyhow!(
+                "State file '{}' belongs to a run with different paths or options, run the same command again to resume it or remove the state file",
+                path.to_str().unwrap_or("unknown")
+            ));
+        }
+        let copy_plan = header.plan;
+        // A line cut short by the interruption is skipped
+        let last_entry = lines
+            .rev()
+            .find_map(|line| serde_json::from_str::<StateEntry>(line).ok());
+        let next_operation = last_entry.map_or(0, |StateEntry::Finished(i)| i + 1);
+
+        let mut file = fs::OpenOptions::new().append(true).open(path)?;
+        // Terminates a line cut short by the interruption
+        writeln!(file)?;
+
+        let state = Self {
+            path: path.to_path_buf(),
+            file,
+            next_operation,
+        };
+
+        Ok(Some((state, copy_plan)))
+    }
+
+    fn record(&mut self, entry: &StateEntry) -> anyhow::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(entry)?)?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+/// Executes `copy_plan` and removes the state file once the run is finished.
+pub fn run(
+    copy_plan: &CopyPlan,
+    journal_path: Option<&Path>,
+    mut state: Option<PlanState>,
+) -> anyhow::Result<()> {
+    if let Some(image) = &copy_plan.options.image {
+        if journal_path.is_some() || state.is_some() {
+            return Err(anyhow!(
+                "A journal or state file can't be used when writing to an image"
+            ));
+        }
+        return fat::execute_plan(image, copy_plan);
+    }
+
+    let mut journal = journal_path.map(Journal::open).transpose()?;
+
+    let bar = progress::get_progress_bar(copy_plan.operations.len() as u64);
+    bar.set_message("Copying files...");
+
+    let result = copy_plan.execute(&bar, journal.as_mut(), state.as_mut());
+    bar.finish();
+    result?;
+
+    if let Some(manifest) = &copy_plan.manifest {
+        // Undoing the run restores the manifest of the previous run too
+        if let Some(journal) = journal.as_mut() {
+            journal.record_copy(&manifest.path())?;
+        }
+        manifest.store()?;
+    }
+
+    if let Some(PlanState { path, file, .. }) = state {
+        drop(file);
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+pub fn apply_tag_changes(
+    dest: &Path,
+    tag_changes: &BTreeMap<String, Vec<String>>,
+) -> anyhow::Result<()> {
+    if tag_changes.is_empty() {
+        return Ok(());
+    }
+
+    let mut tag = tags::read_from_path(dest)?;
+    for (key, values) in tag_changes {
+        tag.set(key, values.clone())?;
+    }
+    tag.write_to_path(dest)?;
+
+    Ok(())
+}
+
+pub fn start_apply_plan(
+    plan_path: &Path,
+    journal_path: Option<&Path>,
+    state_path: Option<&Path>,
+    force: bool,
+) -> anyhow::Result<()> {
+    file_utils::validate_file(plan_path)?;
+
+    let invocation = Invocation::ApplyPlan {
+        plan: std::path::absolute(plan_path)?,
+    };
+    if let Some((state, copy_plan)) = state_path
+        .map(|state_path| PlanState::resume(state_path, &invocation))
+        .transpose()?
+        .flatten()
+    {
+        return run(&copy_plan, journal_path, Some(state));
+    }
+
+    let json_data = fs::read_to_string(plan_path)?;
+    let copy_plan: CopyPlan = serde_json::from_str(&json_data).with_context(|| {
+        format!(
+            "Invalid copy plan '{}'",
+            plan_path.to_str().unwrap_or("unknown")
+        )
+    })?;
+    // The destination may have changed since the plan was made
+    if let Some(delete_limit) = &copy_plan.delete_limit
+        && !force
+    {
+        delete_limit.check(&copy_plan.operations)?;
+    }
+
+    let state = state_path
+        .map(|state_path| PlanState::create(state_path, &invocation, &copy_plan))
+        .transpose()?;
+
+    run(&copy_plan, journal_path, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::file_utils::Normalization;
+
+    fn copy_plan() -> CopyPlan {
+        let mut copy_plan = CopyPlan::new(ExecutionOptions {
+            delay_ms: 0,
+            override_files: false,
+            verify_retries: None,
+            image: None,
+        });
+        copy_plan.operations.push(CopyOperation::File {
+            src: PathBuf::from("/music/cover.jpg"),
+            dest: PathBuf::from("/mnt/usb/cover.jpg"),
+        });
+
+        copy_plan
+    }
+
+    #[test]
+    fn resumes_only_the_same_invocation() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_path = temp_dir.path().join("state.jsonl");
+        let invocation = Invocation::ApplyPlan {
+            plan: PathBuf::from("/plans/usb.json"),
+        };
+        let mut state = PlanState::create(&state_path, &invocation, &copy_plan()).unwrap();
+        state.record(&StateEntry::Finished(0)).unwrap();
+        drop(state);
+
+        let other_invocation = Invocation::ApplyPlan {
+            plan: PathBuf::from("/plans/car.json"),
+        };
+        assert!(PlanState::resume(&state_path, &other_invocation).is_err());
+
+        let (state, copy_plan) = PlanState::resume(&state_path, &invocation)
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.next_operation, 1);
+        assert_eq!(copy_plan.operations.len(), 1);
+    }
+
+    #[test]
+    fn undo_restores_the_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("dest");
+        let journal_path = temp_dir.path().join("journal.jsonl");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.flac"), b"song a").unwrap();
+        fs::write(src.join("b.flac"), b"song b").unwrap();
+
+        let incremental_run = |names: &[&str], journal_path: Option<&Path>| {
+            let mut copy_plan = copy_plan();
+            copy_plan.operations = names
+                .iter()
+                .map(|name| CopyOperation::File {
+                    src: src.join(name),
+                    dest: dest.join(name),
+                })
+                .collect();
+            copy_plan.manifest = Some(
+                crate::manifest::plan_incremental_copy(&src, &dest, &mut copy_plan.operations)
+                    .unwrap(),
+            );
+            run(&copy_plan, journal_path, None).unwrap();
+        };
+
+        incremental_run(&["a.flac"], None);
+        let manifest_path = dest.join(".ffery-manifest.json");
+        let previous_manifest = fs::read(&manifest_path).unwrap();
+        incremental_run(&["a.flac", "b.flac"], Some(&journal_path));
+        assert_ne!(fs::read(&manifest_path).unwrap(), previous_manifest);
+
+        crate::journal::start_undo(&journal_path).unwrap();
+        assert!(!dest.join("b.flac").exists());
+        assert_eq!(fs::read(&manifest_path).unwrap(), previous_manifest);
+    }
+
+    #[test]
+    fn undo_restores_copies_in_another_normalization_form() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src.flac");
+        let dest = temp_dir.path().join("dest");
+        let journal_path = temp_dir.path().join("journal.jsonl");
+        let decomposed_dest = dest.join(Normalization::Nfd.apply("Ágætis byrjun.flac"));
+        let composed_dest = dest.join(Normalization::Nfc.apply("Ágætis byrjun.flac"));
+        fs::write(&src, b"new song").unwrap();
+        fs::create_dir(&dest).unwrap();
+        fs::write(&decomposed_dest, b"old song").unwrap();
+
+        let mut copy_plan = copy_plan();
+        copy_plan.options.override_files = true;
+        copy_plan.operations = vec![CopyOperation::File {
+            src,
+            dest: composed_dest.clone(),
+        }];
+        run(&copy_plan, Some(&journal_path), None).unwrap();
+        assert_eq!(fs::read(&composed_dest).unwrap(), b"new song");
+        assert!(!decomposed_dest.exists());
+
+        crate::journal::start_undo(&journal_path).unwrap();
+        assert!(!composed_dest.exists());
+        assert_eq!(fs::read(&decomposed_dest).unwrap(), b"old song");
+    }
+
+    #[test]
+    fn missing_state_file_starts_a_new_run() {
+        let temp_dir = TempDir::new().unwrap();
+        let invocation = Invocation::ApplyPlan {
+            plan: PathBuf::from("/plans/usb.json"),
+        };
+
+        let state = PlanState::resume(&temp_dir.path().join("state.jsonl"), &invocation).unwrap();
+        assert!(state.is_none());
+    }
+}
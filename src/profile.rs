@@ -0,0 +1,173 @@
+use anyhow::{Context, anyhow};
+use clap::{ArgMatches, parser::ValueSource};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{audio, collision, config, file_utils};
+
+const PROFILES_FILENAME: &str = "profiles.toml";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfilesFile {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// Named `copy-music` and `unzip-music` options for a device. Every option is optional and
+/// only applies when the matching flag, or its `--no-` counterpart, isn't given on the
+/// command line.
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Profile {
+    pub delay_ms: Option<u64>,
+    pub override_files: Option<bool>,
+    pub fat_32: Option<bool>,
+    pub short_names: Option<bool>,
+    pub short_names_map: Option<PathBuf>,
+    pub ascii: Option<audio::AsciiMode>,
+    pub normalize: Option<file_utils::Normalization>,
+    pub verify: Option<bool>,
+    pub verify_retries: Option<u32>,
+    pub on_collision: Option<collision::CollisionStrategy>,
+    pub filename_template: Option<String>,
+    pub dir_template: Option<String>,
+    pub pad_width: Option<usize>,
+    pub metadata_track_number_modification: Option<audio::TrackNumberModification>,
+    pub incremental: Option<bool>,
+    pub mirror: Option<bool>,
+    pub max_delete_percent: Option<u32>,
+}
+
+impl Profile {
+    /// Loads the profile `name` from `profiles_path`, or from `profiles.toml` in the ffery
+    /// config directory. Files ending with `.json` are read as JSON, all others as TOML.
+    pub fn load(name: &str, profiles_path: Option<&Path>) -> anyhow::Result<Self> {
+        let profiles_path = match profiles_path {
+            Some(profiles_path) => profiles_path.to_path_buf(),
+            None => config::config_dir()?.join(PROFILES_FILENAME),
+        };
+        file_utils::validate_file(&profiles_path)?;
+
+        let data = fs::read_to_string(&profiles_path).with_context(|| {
+            format!(
+                "Unable to read profiles '{}'",
+                profiles_path.to_str().unwrap_or("unknown")
+            )
+        })?;
+        let profiles_file: anyhow::Result<ProfilesFile> =
+            if profiles_path.extension().is_some_and(|ext| ext == "json") {
+                serde_json::from_str(&data).map_err(anyhow::Error::from)
+            } else {
+                toml::from_str(&data).map_err(anyhow::Error::from)
+            };
+        let mut profiles_file = profiles_file.with_context(|| {
+            format!(
+                "Unable to parse profiles '{}'",
+                profiles_path.to_str().unwrap_or("unknown")
+            )
+        })?;
+
+        profiles_file.profiles.remove(name).ok_or_else(|| {
+            anyhow!(
+                "Profile '{}' not found in '{}'",
+                name,
+                profiles_path.to_str().unwrap_or("unknown")
+            )
+        })
+    }
+}
+
+/// Sets `target` to the profile `value` unless the argument `id` was given on the command line.
+pub fn merge<T>(target: &mut T, value: Option<T>, matches: &ArgMatches, id: &str) {
+    if let Some(value) = value
+        && matches.value_source(id) != Some(ValueSource::CommandLine)
+    {
+        *target = value;
+    }
+}
+
+/// Sets the flag `target` to the profile `value` unless the flag `id` or its `--no-` counterpart
+/// was given on the command line. The counterpart turns the flag off, also when a profile or the
+/// config file turns it on.
+pub fn merge_flag(target: &mut bool, value: Option<bool>, matches: &ArgMatches, id: &str) {
+    if matches.value_source(&format!("no_{id}")) == Some(ValueSource::CommandLine) {
+        *target = false;
+        return;
+    }
+
+    merge(target, value, matches, id);
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction, Command, value_parser};
+
+    use super::*;
+
+    fn matches(args: &[&str]) -> ArgMatches {
+        Command::new("ffery")
+            .arg(
+                Arg::new("delay_ms")
+                    .long("delay-ms")
+                    .value_parser(value_parser!(u64))
+                    .default_value("0"),
+            )
+            .arg(Arg::new("verify").long("verify").action(ArgAction::SetTrue))
+            .arg(
+                Arg::new("no_verify")
+                    .long("no-verify")
+                    .action(ArgAction::SetTrue),
+            )
+            .get_matches_from(args)
+    }
+
+    #[test]
+    fn profile_values_only_replace_values_not_given_on_the_command_line() {
+        let mut delay_ms = 0;
+        merge(&mut delay_ms, Some(500), &matches(&["ffery"]), "delay_ms");
+        assert_eq!(delay_ms, 500);
+
+        let mut delay_ms = 100;
+        merge(
+            &mut delay_ms,
+            Some(500),
+            &matches(&["ffery", "--delay-ms", "100"]),
+            "delay_ms",
+        );
+        assert_eq!(delay_ms, 100);
+
+        let mut delay_ms = 0;
+        merge(&mut delay_ms, None, &matches(&["ffery"]), "delay_ms");
+        assert_eq!(delay_ms, 0);
+    }
+
+    #[test]
+    fn negations_turn_off_flags_turned_on_by_a_profile() {
+        let mut verify = false;
+        merge_flag(&mut verify, Some(true), &matches(&["ffery"]), "verify");
+        assert!(verify);
+
+        let mut verify = false;
+        merge_flag(
+            &mut verify,
+            Some(true),
+            &matches(&["ffery", "--no-verify"]),
+            "verify",
+        );
+        assert!(!verify);
+
+        let mut verify = true;
+        merge_flag(
+            &mut verify,
+            Some(false),
+            &matches(&["ffery", "--verify"]),
+            "verify",
+        );
+        assert!(verify);
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{Context, anyhow};
+use std::{collections::HashMap, fs, io::Read, path::Path};
+
+use crate::file_utils;
+
+mod flac;
+mod mp3;
+mod mp4;
+mod ogg;
+
+/// All values of every tag field, keyed by the common (Vorbis comment style) field name.
+pub type TagMap = HashMap<String, Vec<String>>;
+
+/// Technical properties of the audio stream, any of them may be unknown for a format.
+#[derive(Default, Clone, Copy)]
+pub struct AudioProperties {
+    pub duration_secs: Option<f64>,
+    pub sample_rate: Option<u32>,
+    /// Only known for lossless formats
+    pub bit_depth: Option<u8>,
+    pub channels: Option<u8>,
+}
+
+/// Format-agnostic access to the metadata of an audio file.
+///
+/// Keys follow the Vorbis comment naming (`ALBUMARTIST`, `TRACKNUMBER`, ...) and every backend
+/// translates them to and from its native representation.
+pub trait AudioTag {
+    fn get(&self, key: &str) -> Option<Vec<String>>;
+
+    fn get_first(&self, key: &str) -> Option<String> {
+        self.get(key).and_then(|values| values.into_iter().next())
+    }
+
+    /// Replaces every value of `key`, fails for values the format can't store.
+    fn set(&mut self, key: &str, values: Vec<String>) -> anyhow::Result<()>;
+
+    fn to_map(&self) -> TagMap;
+
+    fn properties(&self) -> AudioProperties {
+        AudioProperties::default()
+    }
+
+    fn write_to_path(&mut self, filepath: &Path) -> anyhow::Result<()>;
+}
+
+struct TagFormat {
+    extensions: &'static [&'static str],
+    matches_magic: fn(&[u8]) -> bool,
+    read_from_path: fn(&Path) -> anyhow::Result<Box<dyn AudioTag>>,
+}
+
+static TAG_FORMATS: &[TagFormat] = &[flac::FORMAT, mp3::FORMAT, mp4::FORMAT, ogg::FORMAT];
+
+const MAGIC_LEN: u64 = 12;
+
+pub fn supported_extensions() -> Vec<&'static str> {
+    TAG_FORMATS
+        .iter()
+        .flat_map(|format| format.extensions.iter().copied())
+        .collect()
+}
+
+pub fn read_from_path(filepath: &Path) -> anyhow::Result<Box<dyn AudioTag>> {
+    let format = find_format(filepath)?;
+
+    (format.read_from_path)(filepath).with_context(|| {
+        anyhow!(
+            "Unable to read '{}' metadata",
+            filepath.to_str().unwrap_or("unknown")
+        )
+    })
+}
+
+fn find_format(filepath: &Path) -> anyhow::Result<&'static TagFormat> {
+    if let Some(format) = TAG_FORMATS
+        .iter()
+        .find(|format| file_utils::file_has_extension(filepath, format.extensions))
+    {
+        return Ok(format);
+    }
+
+    let mut magic = vec![];
+    fs::File::open(filepath)?
+        .take(MAGIC_LEN)
+        .read_to_end(&mut magic)?;
+
+    TAG_FORMATS
+        .iter()
+        .find(|format| (format.matches_magic)(&magic))
+        .ok_or_else(|| {
+            anyhow!(
+                "Unsupported audio format of '{}'",
+                filepath.to_str().unwrap_or("unknown")
+            )
+        })
+}
@@ -0,0 +1,51 @@
+use std::path::Path;
+
+use super::{AudioProperties, AudioTag, TagFormat, TagMap};
+
+pub(super) const FORMAT: TagFormat = TagFormat {
+    extensions: &["flac"],
+    matches_magic: |magic| magic.starts_with(b"fLaC"),
+    read_from_path: |filepath| Ok(Box::new(FlacTag(metaflac::Tag::read_from_path(filepath)?))),
+};
+
+struct FlacTag(metaflac::Tag);
+
+impl AudioTag for FlacTag {
+    fn get(&self, key: &str) -> Option<Vec<String>> {
+        self.0
+            .get_vorbis(key)
+            .map(|entries| entries.map(str::to_string).collect())
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) -> anyhow::Result<()> {
+        self.0.set_vorbis(key, values);
+
+        Ok(())
+    }
+
+    fn to_map(&self) -> TagMap {
+        self.0
+            .vorbis_comments()
+            .map(|c| c.comments.clone())
+            .unwrap_or_default()
+    }
+
+    fn properties(&self) -> AudioProperties {
+        self.0
+            .get_streaminfo()
+            .map_or_else(AudioProperties::default, |info| AudioProperties {
+                #[allow(clippy::cast_precision_loss)]
+                duration_secs: (info.sample_rate > 0)
+                    .then(|| info.total_samples as f64 / f64::from(info.sample_rate)),
+                sample_rate: Some(info.sample_rate),
+                bit_depth: Some(info.bits_per_sample),
+                channels: Some(info.num_channels),
+            })
+    }
+
+    fn write_to_path(&mut self, filepath: &Path) -> anyhow::Result<()> {
+        self.0.write_to_path(filepath)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,579 @@
+use id3::TagLike;
+use phf::phf_map;
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use super::{AudioProperties, AudioTag, TagFormat, TagMap};
+
+pub(super) const FORMAT: TagFormat = TagFormat {
+    extensions: &["mp3"],
+    matches_magic: |magic| {
+        magic.starts_with(b"ID3")
+            || (magic.len() >= 2 && magic[0] == 0xFF && magic[1] & 0xE0 == 0xE0)
+    },
+    read_from_path: |filepath| {
+        // Untagged MP3 files are valid, they simply carry no metadata
+        let tag = id3::no_tag_ok(id3::v1v2::read_from_path(filepath))?.unwrap_or_default();
+        let properties = read_properties(filepath)?;
+        Ok(Box::new(Mp3Tag(tag, properties)))
+    },
+};
+
+static FRAME_IDS_TO_KEYS: phf::Map<&'static str, &'static str> = phf_map! {
+    "TPE1" => "ARTIST",
+    "TPE2" => "ALBUMARTIST",
+    "TIT2" => "TITLE",
+    "TALB" => "ALBUM",
+    "TRCK" => "TRACKNUMBER",
+    "TPOS" => "DISCNUMBER",
+    "TCON" => "GENRE",
+    "TCOM" => "COMPOSER",
+    "TDRC" => "DATE",
+    "TYER" => "DATE",
+    "TDOR" => "ORIGINALDATE",
+    "TCMP" => "COMPILATION",
+    "COMM" => "COMMENT",
+};
+
+/// How far past the ID3 tag the first MPEG frame is searched for
+const FRAME_SEARCH_LEN: u64 = 64 * 1024;
+const ID3V2_HEADER_LEN: usize = 10;
+const ID3V1_TAG_LEN: u64 = 128;
+
+/// Bitrates in kbit/s indexed by `[MPEG-1 or not][layer - 1][bitrate index]`
+static BITRATES: [[[u32; 15]; 3]; 2] = [
+    [
+        [
+            0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+        ],
+        [
+            0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+        ],
+        [
+            0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ],
+    ],
+    [
+        [
+            0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+        ],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ],
+];
+static MPEG1_SAMPLE_RATES: [u32; 3] = [44_100, 48_000, 32_000];
+
+struct Mp3Tag(id3::Tag, AudioProperties);
+
+impl Mp3Tag {
+    fn frame_id(&self, key: &str) -> Option<&'static str> {
+        let mut frame_ids = FRAME_IDS_TO_KEYS
+            .entries()
+            .filter(|(_, k)| **k == key)
+            .map(|(frame_id, _)| *frame_id);
+        // Prefer the frame the tag already contains (e.g. TYER in ID3v2.3 vs TDRC in ID3v2.4)
+        frame_ids
+            .clone()
+            .find(|frame_id| self.0.get(frame_id).is_some())
+            .or_else(|| frame_ids.next())
+    }
+}
+
+impl AudioTag for Mp3Tag {
+    fn get(&self, key: &str) -> Option<Vec<String>> {
+        self.to_map().remove(key)
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) -> anyhow::Result<()> {
+        match self.frame_id(key) {
+            Some("COMM") => {
+                self.0.remove("COMM");
+                for value in values {
+                    self.0.add_frame(id3::frame::Comment {
+                        lang: "eng".to_string(),
+                        description: String::new(),
+                        text: value,
+                    });
+                }
+            }
+            Some(frame_id) => self.0.set_text_values(frame_id, values),
+            // Unmapped frames are read with their frame ID as the key
+            None if is_raw_frame_id(key) && key.starts_with('T') => {
+                self.0.set_text_values(key, values);
+            }
+            None if is_raw_frame_id(key) => {
+                self.0.remove(key);
+                for value in values {
+                    self.0.add_frame(id3::Frame::link(key, value));
+                }
+            }
+            None => {
+                let descriptions: Vec<_> = self
+                    .0
+                    .extended_texts()
+                    .filter(|extended_text| extended_text.description.eq_ignore_ascii_case(key))
+                    .map(|extended_text| extended_text.description.clone())
+                    .collect();
+                for description in &descriptions {
+                    self.0.remove_extended_text(Some(description), None);
+                }
+                // Keys are read in uppercase, the frame keeps the description it had
+                let description = descriptions
+                    .into_iter()
+                    .next()
+                    .unwrap_or_else(|| key.to_string());
+                self.0.add_frame(id3::frame::ExtendedText {
+                    description,
+                    value: values.join("\0"),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn to_map(&self) -> TagMap {
+        let mut metadata = TagMap::new();
+        for frame in self.0.frames() {
+            let content = frame.content();
+            let key = FRAME_IDS_TO_KEYS
+                .get(frame.id())
+                .map_or_else(|| frame.id().to_string(), |key| (*key).to_string());
+            let (key, values): (String, Vec<String>) = if let Some(values) = content.text_values() {
+                (key, values.map(str::to_string).collect())
+            } else if let Some(extended_text) = content.extended_text() {
+                (
+                    extended_text.description.to_uppercase(),
+                    extended_text
+                        .value
+                        .split('\0')
+                        .map(str::to_string)
+                        .collect(),
+                )
+            } else if let Some(comment) = content.comment() {
+                (key, vec![comment.text.clone()])
+            } else if let Some(link) = content.link() {
+                (key, vec![link.to_string()])
+            } else {
+                continue;
+            };
+
+            metadata.entry(key).or_default().extend(values);
+        }
+
+        metadata
+    }
+
+    fn properties(&self) -> AudioProperties {
+        self.1
+    }
+
+    fn write_to_path(&mut self, filepath: &Path) -> anyhow::Result<()> {
+        id3::v1v2::write_to_path(filepath, &self.0, self.0.version())?;
+
+        Ok(())
+    }
+}
+
+/// Whether `key` is the ID of a text or link frame, which `to_map` uses as the key of the
+/// frames it doesn't map. User defined frames are keyed by their description instead.
+fn is_raw_frame_id(key: &str) -> bool {
+    key.len() == 4
+        && key.starts_with(['T', 'W'])
+        && key
+            .bytes()
+            .all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit())
+        && key != "TXXX"
+        && key != "WXXX"
+}
+
+/// Reads the stream properties from the first MPEG audio frame, using the Xing/Info or VBRI
+/// frame count when present and assuming a constant bitrate otherwise.
+fn read_properties(filepath: &Path) -> anyhow::Result<AudioProperties> {
+    let mut file = fs::File::open(filepath)?;
+    let file_len = file.metadata()?.len();
+
+    let mut header = [0; ID3V2_HEADER_LEN];
+    let audio_start = if file.read_exact(&mut header).is_ok() && header.starts_with(b"ID3") {
+        let size = header[6..10]
+            .iter()
+            .fold(0, |size, &byte| (size << 7) | u64::from(byte & 0x7F));
+        let footer_len = if header[5] & 0x10 == 0 { 0 } else { 10 };
+        ID3V2_HEADER_LEN as u64 + size + footer_len
+    } else {
+        0
+    };
+
+    file.seek(SeekFrom::Start(audio_start))?;
+    let mut data = vec![];
+    (&mut file).take(FRAME_SEARCH_LEN).read_to_end(&mut data)?;
+
+    let Some((offset, frame)) = (0..data.len().saturating_sub(4))
+        .find_map(|offset| FrameHeader::parse(&data[offset..]).map(|frame| (offset, frame)))
+    else {
+        return Ok(AudioProperties::default());
+    };
+
+    let mut id3v1_marker = [0; 3];
+    file.seek(SeekFrom::End(-i64::try_from(ID3V1_TAG_LEN.min(file_len))?))?;
+    let id3v1_len = if file.read_exact(&mut id3v1_marker).is_ok() && &id3v1_marker == b"TAG" {
+        ID3V1_TAG_LEN
+    } else {
+        0
+    };
+    let audio_len = file_len
+        .saturating_sub(audio_start + offset as u64)
+        .saturating_sub(id3v1_len);
+    #[allow(clippy::cast_precision_loss)]
+    let duration_secs = frame.frame_count(&data[offset..]).map_or_else(
+        || audio_len as f64 * 8.0 / (f64::from(frame.bitrate) * 1000.0),
+        |frames| {
+            f64::from(frames) * f64::from(frame.samples_per_frame) / f64::from(frame.sample_rate)
+        },
+    );
+
+    Ok(AudioProperties {
+        duration_secs: Some(duration_secs),
+        sample_rate: Some(frame.sample_rate),
+        bit_depth: None,
+        channels: Some(frame.channels),
+    })
+}
+
+struct FrameHeader {
+    mpeg1: bool,
+    bitrate: u32,
+    sample_rate: u32,
+    samples_per_frame: u32,
+    channels: u8,
+}
+
+impl FrameHeader {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let header = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+        if header >> 21 != 0x7FF {
+            return None;
+        }
+
+        // 0 = MPEG-2.5, 2 = MPEG-2, 3 = MPEG-1
+        let version = (header >> 19) & 0b11;
+        // 1 = Layer III, 2 = Layer II, 3 = Layer I
+        let layer_bits = (header >> 17) & 0b11;
+        let bitrate_index = ((header >> 12) & 0b1111) as usize;
+        let sample_rate_index = ((header >> 10) & 0b11) as usize;
+        if version == 1
+            || layer_bits == 0
+            || bitrate_index == 0
+            || bitrate_index == 15
+            || sample_rate_index == 3
+        {
+            return None;
+        }
+
+        let mpeg1 = version == 3;
+        let layer = 4 - layer_bits as usize;
+        let sample_rate = MPEG1_SAMPLE_RATES[sample_rate_index] >> (3 - version).min(2);
+        let samples_per_frame = match (layer, mpeg1) {
+            (1, _) => 384,
+            (3, false) => 576,
+            _ => 1152,
+        };
+
+        Some(Self {
+            mpeg1,
+            bitrate: BITRATES[usize::from(!mpeg1)][layer - 1][bitrate_index],
+            sample_rate,
+            samples_per_frame,
+            // Channel mode 3 is mono
+            channels: if (header >> 6) & 0b11 == 3 { 1 } else { 2 },
+        })
+    }
+
+    /// Reads the total number of frames from a Xing/Info or VBRI header in the first frame.
+    fn frame_count(&self, frame: &[u8]) -> Option<u32> {
+        let read_u32 = |offset: usize| {
+            frame
+                .get(offset..offset + 4)
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u32::from_be_bytes)
+        };
+
+        // Xing/Info follows the side information, which depends on version and channel count
+        let xing_offset = 4 + match (self.mpeg1, self.channels) {
+            (true, 1) | (false, 2) => 17,
+            (true, _) => 32,
+            (false, _) => 9,
+        };
+        let tag = frame.get(xing_offset..xing_offset + 4)?;
+        if tag == b"Xing" || tag == b"Info" {
+            let flags = read_u32(xing_offset + 4)?;
+            return if flags & 0x1 == 0 {
+                None
+            } else {
+                read_u32(xing_offset + 8)
+            };
+        }
+
+        // VBRI always sits 32 bytes after the frame header
+        if frame.get(36..40) == Some(b"VBRI") {
+            return read_u32(36 + 14);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// MPEG-1 Layer III, 128 kbit/s, 44.1 kHz, no padding
+    const STEREO_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+    const MONO_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0xC0];
+    const FRAME_LEN: usize = 417;
+
+    fn frame(header: [u8; 4]) -> Vec<u8> {
+        let mut frame = header.to_vec();
+        frame.resize(FRAME_LEN, 0);
+
+        frame
+    }
+
+    /// A first frame carrying a Xing (or VBRI) frame count at `offset`.
+    fn info_frame(header: [u8; 4], offset: usize, tag: &[u8], frames: u32) -> Vec<u8> {
+        let mut info_frame = frame(header);
+        info_frame[offset..offset + 4].copy_from_slice(tag);
+        if tag == b"VBRI" {
+            info_frame[offset + 14..offset + 18].copy_from_slice(&frames.to_be_bytes());
+        } else {
+            info_frame[offset + 4..offset + 8].copy_from_slice(&1u32.to_be_bytes());
+            info_frame[offset + 8..offset + 12].copy_from_slice(&frames.to_be_bytes());
+        }
+
+        info_frame
+    }
+
+    /// Writes `frames` after an empty ID3 tag with 100 bytes of padding, `id3v1` appends a
+    /// version 1 tag.
+    fn write_file(frames: &[Vec<u8>], id3v1: bool) -> (TempDir, PathBuf) {
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x64".to_vec();
+        data.resize(ID3V2_HEADER_LEN + 100, 0);
+        for frame in frames {
+            data.extend_from_slice(frame);
+        }
+        if id3v1 {
+            let mut id3v1_tag = b"TAG".to_vec();
+            id3v1_tag.resize(128, 0);
+            data.extend_from_slice(&id3v1_tag);
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let filepath = temp_dir.path().join("song.mp3");
+        fs::write(&filepath, data).unwrap();
+
+        (temp_dir, filepath)
+    }
+
+    #[test]
+    fn maps_id3v2_frames_to_keys() {
+        let (_temp_dir, filepath) = write_file(&[frame(STEREO_HEADER)], false);
+        let mut tag = id3::Tag::new();
+        tag.set_text("TPE1", "Freddie Mercury");
+        tag.set_text("TPE2", "Queen");
+        tag.set_text("TIT2", "Bohemian Rhapsody");
+        tag.set_text("TALB", "A Night at the Opera");
+        tag.set_text("TRCK", "3/12");
+        tag.set_text("TPOS", "1/2");
+        tag.set_text("TDRC", "1975");
+        tag.set_text("TCMP", "1");
+        tag.write_to_path(&filepath, id3::Version::Id3v24).unwrap();
+
+        let tag_map = (FORMAT.read_from_path)(&filepath).unwrap().to_map();
+        let value = |key: &str| tag_map.get(key).map(|values| values.join(";"));
+        assert_eq!(value("ARTIST").as_deref(), Some("Freddie Mercury"));
+        assert_eq!(value("ALBUMARTIST").as_deref(), Some("Queen"));
+        assert_eq!(value("TITLE").as_deref(), Some("Bohemian Rhapsody"));
+        assert_eq!(value("ALBUM").as_deref(), Some("A Night at the Opera"));
+        assert_eq!(value("TRACKNUMBER").as_deref(), Some("3/12"));
+        assert_eq!(value("DISCNUMBER").as_deref(), Some("1/2"));
+        assert_eq!(value("DATE").as_deref(), Some("1975"));
+        assert_eq!(value("COMPILATION").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn falls_back_to_id3v1() {
+        let field = |value: &str, len: usize| {
+            let mut field = value.as_bytes().to_vec();
+            field.resize(len, 0);
+            field
+        };
+        let mut data = frame(STEREO_HEADER);
+        data.extend_from_slice(b"TAG");
+        data.extend(field("Bohemian Rhapsody", 30));
+        data.extend(field("Queen", 30));
+        data.extend(field("A Night at the Opera", 30));
+        data.extend(field("1975", 4));
+        // ID3v1.1: a zero byte before the track number in the last bytes of the comment
+        data.extend(field("", 29));
+        data.extend([11, 255]);
+        let temp_dir = TempDir::new().unwrap();
+        let filepath = temp_dir.path().join("song.mp3");
+        fs::write(&filepath, data).unwrap();
+
+        let mp3_tag = (FORMAT.read_from_path)(&filepath).unwrap();
+        assert_eq!(
+            mp3_tag.get("TITLE"),
+            Some(vec![String::from("Bohemian Rhapsody")])
+        );
+        assert_eq!(mp3_tag.get("ARTIST"), Some(vec![String::from("Queen")]));
+        assert_eq!(
+            mp3_tag.get("ALBUM"),
+            Some(vec![String::from("A Night at the Opera")])
+        );
+        assert_eq!(mp3_tag.get("TRACKNUMBER"), Some(vec![String::from("11")]));
+        assert_eq!(mp3_tag.properties().sample_rate, Some(44_100));
+    }
+
+    #[test]
+    fn set_rewrites_unmapped_frames_in_place() {
+        let (_temp_dir, filepath) = write_file(&[frame(STEREO_HEADER)], false);
+        let mut tag = id3::Tag::new();
+        tag.set_text("TSOP", "Björk");
+        tag.set_text("TPUB", "Ünited");
+        tag.add_frame(id3::Frame::link("WOAR", "https://example.com/björk"));
+        tag.add_frame(id3::frame::ExtendedText {
+            description: "MusicBrainz Album Type".to_string(),
+            value: "álbum".to_string(),
+        });
+        tag.write_to_path(&filepath, id3::Version::Id3v24).unwrap();
+
+        let mut mp3_tag = (FORMAT.read_from_path)(&filepath).unwrap();
+        let tag_map = mp3_tag.to_map();
+        for (key, values) in tag_map {
+            let values = values
+                .iter()
+                .map(|value| value.replace(['ö', 'Ü', 'á'], "_"))
+                .collect();
+            mp3_tag.set(&key, values).unwrap();
+        }
+        mp3_tag.write_to_path(&filepath).unwrap();
+
+        let tag = id3::Tag::read_from_path(&filepath).unwrap();
+        let mut frame_ids: Vec<_> = tag.frames().map(id3::Frame::id).collect();
+        frame_ids.sort_unstable();
+        assert_eq!(frame_ids, ["TPUB", "TSOP", "TXXX", "WOAR"]);
+        assert_eq!(tag.get("TSOP").unwrap().content().text(), Some("Bj_rk"));
+        assert_eq!(tag.get("TPUB").unwrap().content().text(), Some("_nited"));
+        assert_eq!(
+            tag.get("WOAR").unwrap().content().link(),
+            Some("https://example.com/bj_rk")
+        );
+        let extended_text = tag.extended_texts().next().unwrap();
+        assert_eq!(extended_text.description, "MusicBrainz Album Type");
+        assert_eq!(extended_text.value, "_lbum");
+    }
+
+    fn assert_duration(properties: AudioProperties, expected_secs: f64) {
+        let duration_secs = properties.duration_secs.unwrap();
+        assert!(
+            (duration_secs - expected_secs).abs() < 1e-9,
+            "{duration_secs} != {expected_secs}"
+        );
+    }
+
+    #[test]
+    fn reads_xing_frame_count() {
+        let (_temp_dir, filepath) = write_file(
+            &[
+                info_frame(STEREO_HEADER, 36, b"Xing", 1000),
+                frame(STEREO_HEADER),
+            ],
+            false,
+        );
+
+        let properties = read_properties(&filepath).unwrap();
+        assert_duration(properties, 1000.0 * 1152.0 / 44_100.0);
+        assert_eq!(properties.sample_rate, Some(44_100));
+        assert_eq!(properties.channels, Some(2));
+        assert_eq!(properties.bit_depth, None);
+    }
+
+    #[test]
+    fn reads_info_frame_count_of_mono_stream() {
+        let (_temp_dir, filepath) = write_file(&[info_frame(MONO_HEADER, 21, b"Info", 500)], false);
+
+        let properties = read_properties(&filepath).unwrap();
+        assert_duration(properties, 500.0 * 1152.0 / 44_100.0);
+        assert_eq!(properties.channels, Some(1));
+    }
+
+    #[test]
+    fn reads_vbri_frame_count() {
+        let (_temp_dir, filepath) =
+            write_file(&[info_frame(STEREO_HEADER, 36, b"VBRI", 250)], false);
+
+        assert_duration(
+            read_properties(&filepath).unwrap(),
+            250.0 * 1152.0 / 44_100.0,
+        );
+    }
+
+    #[test]
+    fn estimates_constant_bitrate_duration_without_id3v1() {
+        let frames = vec![frame(STEREO_HEADER); 10];
+        let (_temp_dir, filepath) = write_file(&frames, true);
+
+        #[allow(clippy::cast_precision_loss)]
+        let expected_secs = (10 * FRAME_LEN * 8) as f64 / 128_000.0;
+        assert_duration(read_properties(&filepath).unwrap(), expected_secs);
+    }
+
+    #[test]
+    fn xing_without_frame_count_falls_back_to_bitrate() {
+        let mut first_frame = info_frame(STEREO_HEADER, 36, b"Xing", 1000);
+        // Only the byte count flag is set
+        first_frame[40..44].copy_from_slice(&2u32.to_be_bytes());
+        let (_temp_dir, filepath) = write_file(&[first_frame], false);
+
+        #[allow(clippy::cast_precision_loss)]
+        let expected_secs = (FRAME_LEN * 8) as f64 / 128_000.0;
+        assert_duration(read_properties(&filepath).unwrap(), expected_secs);
+    }
+
+    #[test]
+    fn parses_mpeg2_frame_header() {
+        // MPEG-2 Layer III, 64 kbit/s, 22.05 kHz, joint stereo
+        let frame = FrameHeader::parse(&[0xFF, 0xF3, 0x80, 0x40]).unwrap();
+        assert!(!frame.mpeg1);
+        assert_eq!(frame.bitrate, 64);
+        assert_eq!(frame.sample_rate, 22_050);
+        assert_eq!(frame.samples_per_frame, 576);
+        assert_eq!(frame.channels, 2);
+    }
+
+    #[test]
+    fn rejects_invalid_frame_headers() {
+        // Reserved version, free bitrate, bad bitrate and reserved sample rate
+        for header in [
+            [0xFF, 0xEB, 0x90, 0x00],
+            [0xFF, 0xFB, 0x00, 0x00],
+            [0xFF, 0xFB, 0xF0, 0x00],
+            [0xFF, 0xFB, 0x9C, 0x00],
+        ] {
+            assert!(FrameHeader::parse(&header).is_none());
+        }
+    }
+
+    #[test]
+    fn file_without_frames_has_no_properties() {
+        let (_temp_dir, filepath) = write_file(&[], false);
+
+        assert!(read_properties(&filepath).unwrap().duration_secs.is_none());
+    }
+}
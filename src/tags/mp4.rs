@@ -0,0 +1,879 @@
+use anyhow::anyhow;
+use std::{fs, path::Path};
+
+use super::{AudioProperties, AudioTag, TagFormat, TagMap};
+
+pub(super) const FORMAT: TagFormat = TagFormat {
+    extensions: &["m4a", "m4b"],
+    matches_magic: |magic| magic.get(4..8) == Some(b"ftyp"),
+    read_from_path: |filepath| Ok(Box::new(Mp4Tag::read_from_path(filepath)?)),
+};
+
+type AtomKind = [u8; 4];
+
+const ITEM_LIST_PATH: &[AtomKind] = &[*b"moov", *b"udta", *b"meta", *b"ilst"];
+/// Containers that lead to the sample tables (chunk offsets, sample descriptions) of every track
+const SAMPLE_TABLE_PATH: &[AtomKind] = &[*b"trak", *b"mdia", *b"minf", *b"stbl"];
+
+/// Apple Lossless sample description, the only MP4 codec with a meaningful bit depth
+const ALAC: AtomKind = *b"alac";
+/// Size of the common audio sample entry fields preceding codec specific atoms
+const AUDIO_SAMPLE_ENTRY_LEN: usize = 28;
+
+const FREEFORM: AtomKind = *b"----";
+const FREEFORM_MEAN: &str = "com.apple.iTunes";
+const TRACK_NUMBER: AtomKind = *b"trkn";
+const DISC_NUMBER: AtomKind = *b"disk";
+const COMPILATION: AtomKind = *b"cpil";
+
+const DATA_TYPE_IMPLICIT: u32 = 0;
+const DATA_TYPE_UTF8: u32 = 1;
+const DATA_TYPE_UTF16: u32 = 2;
+const DATA_TYPE_INTEGER: u32 = 21;
+
+static ATOMS_TO_KEYS: &[(AtomKind, &str)] = &[
+    (*b"\xA9ART", "ARTIST"),
+    (*b"aART", "ALBUMARTIST"),
+    (*b"\xA9nam", "TITLE"),
+    (*b"\xA9alb", "ALBUM"),
+    (TRACK_NUMBER, "TRACKNUMBER"),
+    (DISC_NUMBER, "DISCNUMBER"),
+    (*b"\xA9gen", "GENRE"),
+    (*b"\xA9wrt", "COMPOSER"),
+    (*b"\xA9day", "DATE"),
+    (COMPILATION, "COMPILATION"),
+    (*b"\xA9cmt", "COMMENT"),
+];
+
+#[derive(Clone, Copy)]
+struct Atom {
+    kind: AtomKind,
+    start: usize,
+    content_start: usize,
+    end: usize,
+}
+
+impl Atom {
+    fn parse_all(data: &[u8], start: usize, end: usize) -> anyhow::Result<Vec<Self>> {
+        let invalid = || anyhow!("Invalid MP4 atom structure");
+
+        let mut atoms = vec![];
+        let mut offset = start;
+        while offset + 8 <= end {
+            let size = u32::from_be_bytes(data[offset..offset + 4].try_into()?);
+            let kind: AtomKind = data[offset + 4..offset + 8].try_into()?;
+            let (header_len, len) = match size {
+                // The atom extends to the end of its parent
+                0 => (8, end - offset),
+                // 64-bit extended size follows the kind
+                1 => {
+                    let len = data.get(offset + 8..offset + 16).ok_or_else(invalid)?;
+                    (16, usize::try_from(u64::from_be_bytes(len.try_into()?))?)
+                }
+                len => (8, len as usize),
+            };
+            if len < header_len || offset + len > end {
+                return Err(invalid());
+            }
+
+            atoms.push(Self {
+                kind,
+                start: offset,
+                content_start: offset + header_len,
+                end: offset + len,
+            });
+            offset += len;
+        }
+
+        Ok(atoms)
+    }
+
+    /// Returns where the children of this atom start, `meta` carries version and flags first.
+    fn children_start(&self, data: &[u8]) -> usize {
+        if &self.kind == b"meta"
+            && data.get(self.content_start + 4..self.content_start + 8) != Some(b"hdlr")
+        {
+            self.content_start + 4
+        } else {
+            self.content_start
+        }
+    }
+}
+
+fn find_atom(atoms: &[Atom], kind: AtomKind) -> Option<Atom> {
+    atoms.iter().find(|atom| atom.kind == kind).copied()
+}
+
+fn write_atom(output: &mut Vec<u8>, kind: AtomKind, content: &[u8]) -> anyhow::Result<()> {
+    output.extend_from_slice(&u32::try_from(content.len() + 8)?.to_be_bytes());
+    output.extend_from_slice(&kind);
+    output.extend_from_slice(content);
+
+    Ok(())
+}
+
+struct DataValue {
+    type_code: u32,
+    locale: u32,
+    payload: Vec<u8>,
+}
+
+impl DataValue {
+    fn text(value: &str) -> Self {
+        Self {
+            type_code: DATA_TYPE_UTF8,
+            locale: 0,
+            payload: value.as_bytes().to_vec(),
+        }
+    }
+
+    /// Track and disc numbers are stored as `[reserved, number, total(, reserved)]` 16-bit values
+    fn number_pair(&self) -> Option<(u16, u16)> {
+        let number = u16::from_be_bytes(self.payload.get(2..4)?.try_into().ok()?);
+        let total = self
+            .payload
+            .get(4..6)
+            .and_then(|total| total.try_into().ok())
+            .map_or(0, u16::from_be_bytes);
+
+        Some((number, total))
+    }
+
+    fn to_string(&self, kind: AtomKind) -> Option<String> {
+        match self.type_code {
+            DATA_TYPE_UTF8 => Some(String::from_utf8_lossy(&self.payload).into_owned()),
+            DATA_TYPE_UTF16 => {
+                let units: Vec<u16> = self
+                    .payload
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                    .collect();
+                Some(String::from_utf16_lossy(&units))
+            }
+            DATA_TYPE_INTEGER => {
+                let mut bytes = [0; 8];
+                let payload = self.payload.get(..self.payload.len().min(8))?;
+                bytes[8 - payload.len()..].copy_from_slice(payload);
+                Some(u64::from_be_bytes(bytes).to_string())
+            }
+            DATA_TYPE_IMPLICIT if kind == TRACK_NUMBER || kind == DISC_NUMBER => {
+                let (number, total) = self.number_pair()?;
+                Some(if total == 0 {
+                    number.to_string()
+                } else {
+                    format!("{number}/{total}")
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+struct IlstItem {
+    kind: AtomKind,
+    /// Namespace and name of a freeform (`----`) item
+    mean: Option<String>,
+    name: Option<String>,
+    values: Vec<DataValue>,
+}
+
+impl IlstItem {
+    fn parse(data: &[u8], atom: &Atom) -> anyhow::Result<Self> {
+        let mut mean = None;
+        let mut name = None;
+        let mut values = vec![];
+        for child in Atom::parse_all(data, atom.content_start, atom.end)? {
+            // `mean`, `name` and `data` all start with version and flags
+            let content = data
+                .get(child.content_start + 4..child.end)
+                .ok_or_else(|| anyhow!("Invalid MP4 metadata item"))?;
+            match &child.kind {
+                b"mean" => mean = Some(String::from_utf8_lossy(content).into_owned()),
+                b"name" => name = Some(String::from_utf8_lossy(content).into_owned()),
+                b"data" => values.push(DataValue {
+                    type_code: u32::from_be_bytes(
+                        data[child.content_start..child.content_start + 4].try_into()?,
+                    ) & 0x00FF_FFFF,
+                    locale: u32::from_be_bytes(
+                        content
+                            .get(..4)
+                            .ok_or_else(|| anyhow!("Invalid MP4 data atom"))?
+                            .try_into()?,
+                    ),
+                    payload: content[4..].to_vec(),
+                }),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            kind: atom.kind,
+            mean,
+            name,
+            values,
+        })
+    }
+
+    fn key(&self) -> String {
+        if self.kind == FREEFORM {
+            return self.name.as_deref().unwrap_or_default().to_uppercase();
+        }
+
+        ATOMS_TO_KEYS
+            .iter()
+            .find(|(kind, _)| *kind == self.kind)
+            .map_or_else(
+                // Atom names are Latin-1, e.g. the copyright sign of '©nam'
+                || self.kind.iter().copied().map(char::from).collect(),
+                |(_, key)| (*key).to_string(),
+            )
+    }
+
+    fn write(&self, output: &mut Vec<u8>) -> anyhow::Result<()> {
+        let mut content = vec![];
+        if let Some(mean) = &self.mean {
+            write_atom(&mut content, *b"mean", &[&[0; 4], mean.as_bytes()].concat())?;
+        }
+        if let Some(name) = &self.name {
+            write_atom(&mut content, *b"name", &[&[0; 4], name.as_bytes()].concat())?;
+        }
+        for value in &self.values {
+            write_atom(
+                &mut content,
+                *b"data",
+                &[
+                    &value.type_code.to_be_bytes()[..],
+                    &value.locale.to_be_bytes(),
+                    &value.payload,
+                ]
+                .concat(),
+            )?;
+        }
+
+        write_atom(output, self.kind, &content)
+    }
+}
+
+struct Mp4Tag {
+    items: Vec<IlstItem>,
+    properties: AudioProperties,
+}
+
+impl Mp4Tag {
+    fn read_from_path(filepath: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(filepath)?;
+
+        Ok(Self {
+            items: read_items(&data)?,
+            properties: read_properties(&data)?,
+        })
+    }
+
+    fn items_mut<'a>(&'a mut self, key: &'a str) -> impl Iterator<Item = &'a mut IlstItem> + 'a {
+        self.items
+            .iter_mut()
+            .filter(move |item| item.key().eq_ignore_ascii_case(key))
+    }
+
+    fn ilst_content(&self) -> anyhow::Result<Vec<u8>> {
+        let mut content = vec![];
+        for item in &self.items {
+            item.write(&mut content)?;
+        }
+
+        Ok(content)
+    }
+}
+
+impl AudioTag for Mp4Tag {
+    fn get(&self, key: &str) -> Option<Vec<String>> {
+        self.to_map().remove(&key.to_uppercase())
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) -> anyhow::Result<()> {
+        let kind = ATOMS_TO_KEYS
+            .iter()
+            .find(|(_, k)| k.eq_ignore_ascii_case(key))
+            .map_or(FREEFORM, |(kind, _)| *kind);
+
+        let new_values: Vec<DataValue> = match kind {
+            TRACK_NUMBER | DISC_NUMBER => {
+                // Keep the stored total unless the new value carries one
+                let old_total = self
+                    .items_mut(key)
+                    .next()
+                    .and_then(|item| item.values.first())
+                    .and_then(DataValue::number_pair)
+                    .map_or(0, |(_, total)| total);
+                // The atoms store 16-bit numbers, anything else would be written as 0
+                let parse = |number: &str, value: &str| {
+                    number.trim().parse::<u16>().map_err(|_| {
+                        anyhow!("Invalid {key} '{value}', expected numbers from 0 to 65535")
+                    })
+                };
+                values
+                    .iter()
+                    .map(|value| {
+                        let (number, total) = value.split_once('/').unwrap_or((value, ""));
+                        let number = parse(number, value)?;
+                        let total = if total.trim().is_empty() {
+                            old_total
+                        } else {
+                            parse(total, value)?
+                        };
+                        let mut payload =
+                            [[0; 2], number.to_be_bytes(), total.to_be_bytes()].concat();
+                        if kind == TRACK_NUMBER {
+                            payload.extend_from_slice(&[0; 2]);
+                        }
+                        Ok(DataValue {
+                            type_code: DATA_TYPE_IMPLICIT,
+                            locale: 0,
+                            payload,
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?
+            }
+            COMPILATION => values
+                .iter()
+                .map(|value| DataValue {
+                    type_code: DATA_TYPE_INTEGER,
+                    locale: 0,
+                    payload: vec![u8::from(value.trim() == "1")],
+                })
+                .collect(),
+            _ => values.iter().map(|value| DataValue::text(value)).collect(),
+        };
+
+        if let Some(item) = self.items_mut(key).next() {
+            item.values = new_values;
+        } else {
+            self.items.push(IlstItem {
+                kind,
+                mean: (kind == FREEFORM).then(|| FREEFORM_MEAN.to_string()),
+                name: (kind == FREEFORM).then(|| key.to_string()),
+                values: new_values,
+            });
+        }
+        let mut seen = false;
+        self.items.retain(|item| {
+            if !item.key().eq_ignore_ascii_case(key) {
+                return true;
+            }
+            let keep = !seen;
+            seen = true;
+            keep
+        });
+
+        Ok(())
+    }
+
+    fn to_map(&self) -> TagMap {
+        let mut metadata = TagMap::new();
+        for item in &self.items {
+            let values: Vec<_> = item
+                .values
+                .iter()
+                .filter_map(|value| value.to_string(item.kind))
+                .collect();
+            if !values.is_empty() {
+                metadata.entry(item.key()).or_default().extend(values);
+            }
+        }
+
+        metadata
+    }
+
+    fn properties(&self) -> AudioProperties {
+        self.properties
+    }
+
+    fn write_to_path(&mut self, filepath: &Path) -> anyhow::Result<()> {
+        let data = fs::read(filepath)?;
+
+        let atoms = Atom::parse_all(&data, 0, data.len())?;
+        let moov =
+            find_atom(&atoms, *b"moov").ok_or_else(|| anyhow!("MP4 file without 'moov' atom"))?;
+
+        let ilst_content = self.ilst_content()?;
+        let mut new_moov = vec![];
+        rebuild_container(
+            &data,
+            moov,
+            &ITEM_LIST_PATH[1..],
+            &ilst_content,
+            &mut new_moov,
+        )?;
+
+        // Chunk offsets point into 'mdat', they move when a preceding 'moov' changes size
+        let media_follows = atoms
+            .iter()
+            .any(|atom| &atom.kind == b"mdat" && atom.start > moov.start);
+        let delta = i64::try_from(new_moov.len())? - i64::try_from(moov.end - moov.start)?;
+        if media_follows && delta != 0 {
+            shift_chunk_offsets(&mut new_moov, delta)?;
+        }
+
+        let mut output = Vec::with_capacity(data.len() + new_moov.len());
+        output.extend_from_slice(&data[..moov.start]);
+        output.extend_from_slice(&new_moov);
+        output.extend_from_slice(&data[moov.end..]);
+        fs::write(filepath, output)?;
+
+        Ok(())
+    }
+}
+
+fn read_items(data: &[u8]) -> anyhow::Result<Vec<IlstItem>> {
+    let mut atoms = Atom::parse_all(data, 0, data.len())?;
+    for kind in ITEM_LIST_PATH {
+        let Some(atom) = find_atom(&atoms, *kind) else {
+            return Ok(vec![]);
+        };
+        atoms = Atom::parse_all(data, atom.children_start(data), atom.end)?;
+    }
+
+    atoms
+        .iter()
+        .map(|atom| IlstItem::parse(data, atom))
+        .collect()
+}
+
+fn read_properties(data: &[u8]) -> anyhow::Result<AudioProperties> {
+    let read_u16 = |offset: usize| {
+        data.get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let read_u32 = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    let read_u64 = |offset: usize| {
+        data.get(offset..offset + 8)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_be_bytes)
+    };
+
+    let mut properties = AudioProperties::default();
+    let Some(moov) = find_atom(&Atom::parse_all(data, 0, data.len())?, *b"moov") else {
+        return Ok(properties);
+    };
+
+    if let Some(mvhd) = find_atom(
+        &Atom::parse_all(data, moov.content_start, moov.end)?,
+        *b"mvhd",
+    ) {
+        // Version 1 uses 64-bit creation/modification times and duration
+        let (timescale, duration) = if data.get(mvhd.content_start) == Some(&1) {
+            (
+                read_u32(mvhd.content_start + 20),
+                read_u64(mvhd.content_start + 24),
+            )
+        } else {
+            (
+                read_u32(mvhd.content_start + 12),
+                read_u32(mvhd.content_start + 16).map(u64::from),
+            )
+        };
+        #[allow(clippy::cast_precision_loss)]
+        {
+            properties.duration_secs = timescale
+                .zip(duration)
+                .filter(|(timescale, _)| *timescale > 0)
+                .map(|(timescale, duration)| duration as f64 / f64::from(timescale));
+        }
+    }
+
+    for stbl in find_sample_tables(data, moov)? {
+        let Some(stsd) = find_atom(
+            &Atom::parse_all(data, stbl.content_start, stbl.end)?,
+            *b"stsd",
+        ) else {
+            continue;
+        };
+        // Version, flags and entry count precede the sample entries
+        let Some(entry) = Atom::parse_all(data, stsd.content_start + 8, stsd.end)?
+            .first()
+            .copied()
+        else {
+            continue;
+        };
+        if entry.kind != ALAC && &entry.kind != b"mp4a" {
+            continue;
+        }
+
+        properties.channels =
+            read_u16(entry.content_start + 16).and_then(|channels| u8::try_from(channels).ok());
+        // 16.16 fixed point
+        properties.sample_rate = read_u32(entry.content_start + 24).map(|rate| rate >> 16);
+
+        if entry.kind == ALAC
+            && let Some(config) = find_atom(
+                &Atom::parse_all(
+                    data,
+                    entry.content_start + AUDIO_SAMPLE_ENTRY_LEN,
+                    entry.end,
+                )?,
+                ALAC,
+            )
+        {
+            // The codec configuration also holds sample rates above 65535 Hz
+            properties.bit_depth = data.get(config.content_start + 9).copied();
+            properties.channels = data.get(config.content_start + 13).copied();
+            properties.sample_rate = read_u32(config.content_start + 24);
+        }
+
+        break;
+    }
+
+    Ok(properties)
+}
+
+fn find_sample_tables(data: &[u8], moov: Atom) -> anyhow::Result<Vec<Atom>> {
+    let mut containers = vec![moov];
+    for kind in SAMPLE_TABLE_PATH {
+        let mut children = vec![];
+        for container in &containers {
+            children.extend(
+                Atom::parse_all(data, container.content_start, container.end)?
+                    .into_iter()
+                    .filter(|atom| atom.kind == *kind),
+            );
+        }
+        containers = children;
+    }
+
+    Ok(containers)
+}
+
+/// Writes `container` to `output`, replacing (or creating) the atom at `path` below it with
+/// an `ilst` atom holding `ilst_content`.
+fn rebuild_container(
+    data: &[u8],
+    container: Atom,
+    path: &[AtomKind],
+    ilst_content: &[u8],
+    output: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    let children_start = container.children_start(data);
+    let mut content = data[container.content_start..children_start].to_vec();
+
+    let Some((next_kind, rest)) = path.split_first() else {
+        return write_atom(output, container.kind, ilst_content);
+    };
+
+    let children = Atom::parse_all(data, children_start, container.end)?;
+    let mut found = false;
+    for child in &children {
+        if child.kind == *next_kind && !found {
+            found = true;
+            rebuild_container(data, *child, rest, ilst_content, &mut content)?;
+        } else {
+            content.extend_from_slice(&data[child.start..child.end]);
+        }
+    }
+    if !found {
+        create_atoms(path, ilst_content, &mut content)?;
+    }
+
+    write_atom(output, container.kind, &content)
+}
+
+fn create_atoms(
+    path: &[AtomKind],
+    ilst_content: &[u8],
+    output: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    let Some((kind, rest)) = path.split_first() else {
+        return Ok(());
+    };
+    if rest.is_empty() {
+        return write_atom(output, *kind, ilst_content);
+    }
+
+    let mut content = vec![];
+    if kind == b"meta" {
+        // Version and flags followed by the handler the iTunes metadata requires
+        content.extend_from_slice(&[0; 4]);
+        write_atom(
+            &mut content,
+            *b"hdlr",
+            &[&[0; 8][..], b"mdirappl", &[0; 9]].concat(),
+        )?;
+    }
+    create_atoms(rest, ilst_content, &mut content)?;
+
+    write_atom(output, *kind, &content)
+}
+
+fn shift_chunk_offsets(moov: &mut [u8], delta: i64) -> anyhow::Result<()> {
+    let moov_atom = Atom::parse_all(moov, 0, moov.len())?[0];
+    let containers = find_sample_tables(moov, moov_atom)?;
+
+    for stbl in containers {
+        for table in Atom::parse_all(moov, stbl.content_start, stbl.end)? {
+            let entry_len = match &table.kind {
+                b"stco" => 4,
+                b"co64" => 8,
+                _ => continue,
+            };
+            // Version and flags followed by the entry count
+            let entries_start = table.content_start + 8;
+            for entry in moov[entries_start..table.end].chunks_exact_mut(entry_len) {
+                if entry_len == 4 {
+                    let offset = i64::from(u32::from_be_bytes(entry.try_into()?)) + delta;
+                    entry.copy_from_slice(&u32::try_from(offset)?.to_be_bytes());
+                } else {
+                    let offset = i64::try_from(u64::from_be_bytes(entry.try_into()?))? + delta;
+                    entry.copy_from_slice(&u64::try_from(offset)?.to_be_bytes());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const MEDIA_DATA: &[u8] = b"audio samples";
+
+    fn atom(kind: AtomKind, content: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        write_atom(&mut output, kind, content).unwrap();
+
+        output
+    }
+
+    fn moov(items: &[IlstItem], chunk_offset: u64, co64: bool) -> Vec<u8> {
+        let mut mvhd = vec![0; 12];
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&2500u32.to_be_bytes());
+
+        let mut sample_entry = vec![0; 16];
+        sample_entry.extend_from_slice(&2u16.to_be_bytes());
+        sample_entry.extend_from_slice(&[0; 6]);
+        sample_entry.extend_from_slice(&(44_100u32 << 16).to_be_bytes());
+        let stsd = [
+            &[0; 4][..],
+            &1u32.to_be_bytes(),
+            &atom(*b"mp4a", &sample_entry),
+        ]
+        .concat();
+        let chunk_offsets = if co64 {
+            atom(
+                *b"co64",
+                &[
+                    &[0; 4][..],
+                    &1u32.to_be_bytes(),
+                    &chunk_offset.to_be_bytes(),
+                ]
+                .concat(),
+            )
+        } else {
+            atom(
+                *b"stco",
+                &[
+                    &[0; 4][..],
+                    &1u32.to_be_bytes(),
+                    &u32::try_from(chunk_offset).unwrap().to_be_bytes(),
+                ]
+                .concat(),
+            )
+        };
+        let stbl = atom(*b"stbl", &[atom(*b"stsd", &stsd), chunk_offsets].concat());
+        let trak = atom(*b"trak", &atom(*b"mdia", &atom(*b"minf", &stbl)));
+
+        let mut content = [atom(*b"mvhd", &mvhd), trak].concat();
+        if !items.is_empty() {
+            let mut ilst = vec![];
+            for item in items {
+                item.write(&mut ilst).unwrap();
+            }
+            let mut meta = vec![0; 4];
+            meta.extend_from_slice(&atom(
+                *b"hdlr",
+                &[&[0; 8][..], b"mdirappl", &[0; 9]].concat(),
+            ));
+            meta.extend_from_slice(&atom(*b"ilst", &ilst));
+            content.extend_from_slice(&atom(*b"udta", &atom(*b"meta", &meta)));
+        }
+
+        atom(*b"moov", &content)
+    }
+
+    /// Writes `ftyp`, `moov` and `mdat`, the chunk offset points at the media data.
+    fn write_file(items: &[IlstItem], co64: bool) -> (TempDir, PathBuf) {
+        let ftyp = atom(*b"ftyp", b"M4A \0\0\0\0");
+        let moov_len = moov(items, 0, co64).len();
+        let chunk_offset = u64::try_from(ftyp.len() + moov_len + 8).unwrap();
+        let data = [
+            ftyp,
+            moov(items, chunk_offset, co64),
+            atom(*b"mdat", MEDIA_DATA),
+        ]
+        .concat();
+
+        let temp_dir = TempDir::new().unwrap();
+        let filepath = temp_dir.path().join("song.m4a");
+        fs::write(&filepath, data).unwrap();
+
+        (temp_dir, filepath)
+    }
+
+    fn text_item(kind: AtomKind, value: &str) -> IlstItem {
+        IlstItem {
+            kind,
+            mean: None,
+            name: None,
+            values: vec![DataValue::text(value)],
+        }
+    }
+
+    /// Returns the media data the first chunk offset of the file points at.
+    fn chunk_data(filepath: &Path) -> Vec<u8> {
+        let data = fs::read(filepath).unwrap();
+        let moov = find_atom(&Atom::parse_all(&data, 0, data.len()).unwrap(), *b"moov").unwrap();
+        let stbl = find_sample_tables(&data, moov).unwrap()[0];
+        let table = Atom::parse_all(&data, stbl.content_start, stbl.end)
+            .unwrap()
+            .into_iter()
+            .find(|atom| &atom.kind == b"stco" || &atom.kind == b"co64")
+            .unwrap();
+        let entries = &data[table.content_start + 8..table.end];
+        let offset = if &table.kind == b"stco" {
+            u64::from(u32::from_be_bytes(entries.try_into().unwrap()))
+        } else {
+            u64::from_be_bytes(entries.try_into().unwrap())
+        };
+        let offset = usize::try_from(offset).unwrap();
+
+        data[offset..offset + MEDIA_DATA.len()].to_vec()
+    }
+
+    #[test]
+    fn reads_items_and_properties() {
+        let (_temp_dir, filepath) = write_file(
+            &[
+                text_item(*b"\xA9ART", "Björk"),
+                IlstItem {
+                    kind: TRACK_NUMBER,
+                    mean: None,
+                    name: None,
+                    values: vec![DataValue {
+                        type_code: DATA_TYPE_IMPLICIT,
+                        locale: 0,
+                        payload: vec![0, 0, 0, 3, 0, 12, 0, 0],
+                    }],
+                },
+                IlstItem {
+                    kind: FREEFORM,
+                    mean: Some(FREEFORM_MEAN.to_string()),
+                    name: Some("Label".to_string()),
+                    values: vec![DataValue::text("One Little Indian")],
+                },
+            ],
+            false,
+        );
+
+        let tag = Mp4Tag::read_from_path(&filepath).unwrap();
+        assert_eq!(tag.get_first("ARTIST").as_deref(), Some("Björk"));
+        assert_eq!(tag.get_first("TRACKNUMBER").as_deref(), Some("3/12"));
+        assert_eq!(tag.get_first("label").as_deref(), Some("One Little Indian"));
+        assert_eq!(tag.properties().duration_secs, Some(2.5));
+        assert_eq!(tag.properties().sample_rate, Some(44_100));
+        assert_eq!(tag.properties().channels, Some(2));
+    }
+
+    #[test]
+    fn writing_shifts_stco_chunk_offsets() {
+        let (_temp_dir, filepath) = write_file(&[text_item(*b"\xA9nam", "Song")], false);
+        assert_eq!(chunk_data(&filepath), MEDIA_DATA);
+
+        let mut tag = Mp4Tag::read_from_path(&filepath).unwrap();
+        tag.set("TITLE", vec!["A much longer title than before".into()])
+            .unwrap();
+        tag.set("TRACKNUMBER", vec!["7".into()]).unwrap();
+        tag.set("COMPILATION", vec!["1".into()]).unwrap();
+        tag.set("MOOD", vec!["Calm".into()]).unwrap();
+        tag.write_to_path(&filepath).unwrap();
+
+        assert_eq!(chunk_data(&filepath), MEDIA_DATA);
+        let tag = Mp4Tag::read_from_path(&filepath).unwrap();
+        assert_eq!(
+            tag.get_first("TITLE").as_deref(),
+            Some("A much longer title than before")
+        );
+        assert_eq!(tag.get_first("TRACKNUMBER").as_deref(), Some("7"));
+        assert_eq!(tag.get_first("COMPILATION").as_deref(), Some("1"));
+        assert_eq!(tag.get_first("MOOD").as_deref(), Some("Calm"));
+        assert_eq!(tag.properties().duration_secs, Some(2.5));
+    }
+
+    #[test]
+    fn writing_shifts_co64_chunk_offsets_when_shrinking() {
+        let (_temp_dir, filepath) = write_file(&[text_item(*b"\xA9nam", "A long title")], true);
+
+        let mut tag = Mp4Tag::read_from_path(&filepath).unwrap();
+        tag.set("TITLE", vec!["T".into()]).unwrap();
+        tag.write_to_path(&filepath).unwrap();
+
+        assert_eq!(chunk_data(&filepath), MEDIA_DATA);
+        let tag = Mp4Tag::read_from_path(&filepath).unwrap();
+        assert_eq!(tag.get_first("TITLE").as_deref(), Some("T"));
+    }
+
+    #[test]
+    fn writing_creates_missing_item_list() {
+        let (_temp_dir, filepath) = write_file(&[], false);
+        assert!(Mp4Tag::read_from_path(&filepath).unwrap().items.is_empty());
+
+        let mut tag = Mp4Tag::read_from_path(&filepath).unwrap();
+        tag.set("ALBUM", vec!["Debut".into()]).unwrap();
+        tag.write_to_path(&filepath).unwrap();
+
+        assert_eq!(chunk_data(&filepath), MEDIA_DATA);
+        let tag = Mp4Tag::read_from_path(&filepath).unwrap();
+        assert_eq!(tag.get_first("ALBUM").as_deref(), Some("Debut"));
+    }
+
+    #[test]
+    fn setting_track_number_keeps_total() {
+        let (_temp_dir, filepath) = write_file(
+            &[IlstItem {
+                kind: TRACK_NUMBER,
+                mean: None,
+                name: None,
+                values: vec![DataValue {
+                    type_code: DATA_TYPE_IMPLICIT,
+                    locale: 0,
+                    payload: vec![0, 0, 0, 3, 0, 12, 0, 0],
+                }],
+            }],
+            false,
+        );
+
+        let mut tag = Mp4Tag::read_from_path(&filepath).unwrap();
+        tag.set("TRACKNUMBER", vec!["04".into()]).unwrap();
+        assert_eq!(tag.get_first("TRACKNUMBER").as_deref(), Some("4/12"));
+    }
+
+    #[test]
+    fn setting_invalid_numbers_fails() {
+        let (_temp_dir, filepath) = write_file(&[], false);
+        let mut tag = Mp4Tag::read_from_path(&filepath).unwrap();
+
+        let err = tag.set("TRACKNUMBER", vec!["70001".into()]).unwrap_err();
+        assert!(err.to_string().contains("TRACKNUMBER '70001'"));
+        assert!(tag.set("DISCNUMBER", vec!["one".into()]).is_err());
+        assert!(tag.set("TRACKNUMBER", vec!["1/x".into()]).is_err());
+        assert_eq!(tag.get("TRACKNUMBER"), None);
+        assert!(tag.set("TRACKNUMBER", vec![" 2 / 9 ".into()]).is_ok());
+        assert_eq!(tag.get_first("TRACKNUMBER").as_deref(), Some("2/9"));
+    }
+}
@@ -0,0 +1,770 @@
+use anyhow::anyhow;
+use std::{
+    fs,
+    io::{self, Read, Seek},
+    path::Path,
+};
+
+use super::{AudioProperties, AudioTag, TagFormat, TagMap};
+
+pub(super) const FORMAT: TagFormat = TagFormat {
+    extensions: &["ogg", "oga", "opus"],
+    matches_magic: |magic| magic.starts_with(CAPTURE_PATTERN),
+    read_from_path: |filepath| Ok(Box::new(OggTag::read_from_path(filepath)?)),
+};
+
+const CAPTURE_PATTERN: &[u8] = b"OggS";
+const PAGE_HEADER_LEN: usize = 27;
+const MAX_SEGMENTS_PER_PAGE: usize = 255;
+const MAX_SEGMENT_LEN: usize = 255;
+
+const FLAG_CONTINUED_PACKET: u8 = 0x01;
+const FLAG_BEGINNING_OF_STREAM: u8 = 0x02;
+
+/// Granule position of a page on which no packet finishes
+const NO_GRANULE_POSITION: u64 = u64::MAX;
+
+/// Largest possible page, the last page of a stream is always within this distance of the end
+const MAX_PAGE_LEN: u64 = (PAGE_HEADER_LEN + MAX_SEGMENTS_PER_PAGE * (1 + MAX_SEGMENT_LEN)) as u64;
+
+/// Opus granule positions always count samples at 48 kHz, regardless of the input sample rate
+const OPUS_GRANULE_RATE: u32 = 48_000;
+
+#[derive(Clone, Copy)]
+enum Codec {
+    Vorbis,
+    Opus,
+}
+
+impl Codec {
+    fn from_identification_packet(packet: &[u8]) -> anyhow::Result<Self> {
+        if packet.starts_with(b"\x01vorbis") {
+            Ok(Self::Vorbis)
+        } else if packet.starts_with(b"OpusHead") {
+            Ok(Self::Opus)
+        } else {
+            Err(anyhow!(
+                "Unsupported Ogg codec, only Vorbis and Opus are supported"
+            ))
+        }
+    }
+
+    const fn header_packet_count(self) -> usize {
+        match self {
+            // Identification, comment and setup header
+            Self::Vorbis => 3,
+            // Identification and comment header
+            Self::Opus => 2,
+        }
+    }
+
+    const fn comment_packet_prefix(self) -> &'static [u8] {
+        match self {
+            Self::Vorbis => b"\x03vorbis",
+            Self::Opus => b"OpusTags",
+        }
+    }
+
+    fn properties(
+        self,
+        identification_packet: &[u8],
+        last_granule: Option<u64>,
+    ) -> AudioProperties {
+        let read_u16 = |offset: usize| {
+            identification_packet
+                .get(offset..offset + 2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        };
+        let read_u32 = |offset: usize| {
+            identification_packet
+                .get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+
+        let (channels, sample_rate, granule_rate, pre_skip) = match self {
+            Self::Vorbis => {
+                let sample_rate = read_u32(12);
+                (
+                    identification_packet.get(11).copied(),
+                    sample_rate,
+                    sample_rate,
+                    0,
+                )
+            }
+            Self::Opus => (
+                identification_packet.get(9).copied(),
+                read_u32(12).filter(|&rate| rate > 0),
+                Some(OPUS_GRANULE_RATE),
+                read_u16(10).unwrap_or(0),
+            ),
+        };
+
+        AudioProperties {
+            #[allow(clippy::cast_precision_loss)]
+            duration_secs: last_granule.zip(granule_rate.filter(|&rate| rate > 0)).map(
+                |(granule, rate)| {
+                    granule.saturating_sub(u64::from(pre_skip)) as f64 / f64::from(rate)
+                },
+            ),
+            sample_rate,
+            bit_depth: None,
+            channels,
+        }
+    }
+}
+
+struct OggPage {
+    flags: u8,
+    granule_position: u64,
+    serial: u32,
+    sequence: u32,
+    segment_table: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl OggPage {
+    fn read_from(reader: &mut impl Read) -> anyhow::Result<Option<Self>> {
+        let mut header = [0; PAGE_HEADER_LEN];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+
+        if !header.starts_with(CAPTURE_PATTERN) {
+            return Err(anyhow!("Invalid Ogg page, capture pattern not found"));
+        }
+
+        let mut segment_table = vec![0; usize::from(header[26])];
+        reader.read_exact(&mut segment_table)?;
+        let mut data = vec![0; segment_table.iter().map(|&len| usize::from(len)).sum()];
+        reader.read_exact(&mut data)?;
+
+        let page = Self {
+            flags: header[5],
+            granule_position: u64::from_le_bytes(header[6..14].try_into()?),
+            serial: u32::from_le_bytes(header[14..18].try_into()?),
+            sequence: u32::from_le_bytes(header[18..22].try_into()?),
+            segment_table,
+            data,
+        };
+
+        let checksum = u32::from_le_bytes(header[22..26].try_into()?);
+        if page.checksum() != checksum {
+            return Err(anyhow!(
+                "Invalid Ogg page {}, checksum mismatch",
+                page.sequence
+            ));
+        }
+
+        Ok(Some(page))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with_checksum(self.checksum())
+    }
+
+    fn to_bytes_with_checksum(&self, checksum: u32) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(PAGE_HEADER_LEN + self.segment_table.len() + self.data.len());
+        bytes.extend_from_slice(CAPTURE_PATTERN);
+        // Stream structure version
+        bytes.push(0);
+        bytes.push(self.flags);
+        bytes.extend_from_slice(&self.granule_position.to_le_bytes());
+        bytes.extend_from_slice(&self.serial.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        #[allow(clippy::cast_possible_truncation)]
+        bytes.push(self.segment_table.len() as u8);
+        bytes.extend_from_slice(&self.segment_table);
+        bytes.extend_from_slice(&self.data);
+
+        bytes
+    }
+
+    fn checksum(&self) -> u32 {
+        crc32(&self.to_bytes_with_checksum(0))
+    }
+}
+
+/// The pages holding the codec header packets of the first logical stream in the file.
+struct OggHeaders {
+    codec: Codec,
+    serial: u32,
+    packets: Vec<Vec<u8>>,
+    page_count: usize,
+}
+
+impl OggHeaders {
+    fn read_from(reader: &mut impl Read) -> anyhow::Result<Self> {
+        let mut serial = None;
+        let mut page_count = 0;
+        let mut packets: Vec<Vec<u8>> = vec![];
+        let mut current_packet = vec![];
+        let mut codec = None;
+
+        loop {
+            let page = OggPage::read_from(reader)?
+                .ok_or_else(|| anyhow!("Unexpected end of file while reading Ogg headers"))?;
+            if *serial.get_or_insert(page.serial) != page.serial {
+                return Err(anyhow!("Multiplexed Ogg streams are not supported"));
+            }
+
+            let mut offset = 0;
+            for &segment_len in &page.segment_table {
+                let segment_len = usize::from(segment_len);
+                current_packet.extend_from_slice(&page.data[offset..offset + segment_len]);
+                offset += segment_len;
+                if segment_len < MAX_SEGMENT_LEN {
+                    packets.push(std::mem::take(&mut current_packet));
+                }
+            }
+            page_count += 1;
+
+            if codec.is_none()
+                && let Some(identification_packet) = packets.first()
+            {
+                codec = Some(Codec::from_identification_packet(identification_packet)?);
+            }
+
+            if let Some(codec) = codec
+                && packets.len() >= codec.header_packet_count()
+            {
+                if packets.len() > codec.header_packet_count() || !current_packet.is_empty() {
+                    return Err(anyhow!("Audio data shares a page with the Ogg headers"));
+                }
+
+                return Ok(Self {
+                    codec,
+                    serial: page.serial,
+                    packets,
+                    page_count,
+                });
+            }
+        }
+    }
+
+    fn comment_packet(&self) -> &[u8] {
+        &self.packets[1]
+    }
+
+    /// Lays out the header packets onto fresh pages, the identification header alone on the
+    /// first page and the remaining headers finishing on a page boundary.
+    fn to_pages(&self) -> Vec<OggPage> {
+        let (identification_packet, other_packets) = self
+            .packets
+            .split_first()
+            .expect("Ogg headers always contain the identification header");
+
+        let mut pages = vec![];
+        for (flags, packets) in [
+            (
+                FLAG_BEGINNING_OF_STREAM,
+                std::slice::from_ref(identification_packet),
+            ),
+            (0, other_packets),
+        ] {
+            let mut segments: Vec<(u8, &[u8], bool)> = vec![];
+            for packet in packets {
+                let chunks: Vec<_> = packet.chunks(MAX_SEGMENT_LEN).collect();
+                let last_chunk_full = chunks
+                    .last()
+                    .is_none_or(|chunk| chunk.len() == MAX_SEGMENT_LEN);
+                for chunk in &chunks {
+                    #[allow(clippy::cast_possible_truncation)]
+                    segments.push((chunk.len() as u8, chunk, false));
+                }
+                if last_chunk_full {
+                    // Packets of a length divisible by 255 are terminated with an empty segment
+                    segments.push((0, &[], false));
+                }
+                if let Some(last) = segments.last_mut() {
+                    last.2 = true;
+                }
+            }
+
+            let mut continued = false;
+            for page_segments in segments.chunks(MAX_SEGMENTS_PER_PAGE) {
+                let finishes_packet = page_segments.iter().any(|(_, _, last)| *last);
+                pages.push(OggPage {
+                    flags: if continued {
+                        flags | FLAG_CONTINUED_PACKET
+                    } else {
+                        flags
+                    },
+                    granule_position: if finishes_packet {
+                        0
+                    } else {
+                        NO_GRANULE_POSITION
+                    },
+                    serial: self.serial,
+                    sequence: 0,
+                    segment_table: page_segments.iter().map(|(len, _, _)| *len).collect(),
+                    data: page_segments
+                        .iter()
+                        .flat_map(|(_, chunk, _)| chunk.iter().copied())
+                        .collect(),
+                });
+                continued = !page_segments.last().is_some_and(|(_, _, last)| *last);
+            }
+        }
+
+        for (sequence, page) in pages.iter_mut().enumerate() {
+            page.sequence = u32::try_from(sequence).unwrap_or(u32::MAX);
+        }
+
+        pages
+    }
+}
+
+struct OggTag {
+    comments: VorbisComments,
+    properties: AudioProperties,
+}
+
+impl OggTag {
+    fn read_from_path(filepath: &Path) -> anyhow::Result<Self> {
+        let mut reader = io::BufReader::new(fs::File::open(filepath)?);
+        let headers = OggHeaders::read_from(&mut reader)?;
+        let comments = VorbisComments::from_packet(
+            headers.comment_packet(),
+            headers.codec.comment_packet_prefix(),
+        )?;
+
+        let last_granule = read_last_granule_position(reader.get_mut(), headers.serial)?;
+        let properties = headers.codec.properties(&headers.packets[0], last_granule);
+
+        Ok(Self {
+            comments,
+            properties,
+        })
+    }
+}
+
+/// Finds the granule position of the last page of the stream, which gives its length.
+fn read_last_granule_position(file: &mut fs::File, serial: u32) -> anyhow::Result<Option<u64>> {
+    let len = file.seek(io::SeekFrom::End(0))?;
+    file.seek(io::SeekFrom::Start(len.saturating_sub(MAX_PAGE_LEN)))?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail)?;
+
+    let granule = tail
+        .windows(PAGE_HEADER_LEN)
+        .rev()
+        .filter(|header| header.starts_with(CAPTURE_PATTERN) && header[4] == 0)
+        .filter(|header| header[14..18] == serial.to_le_bytes())
+        .map(|header| u64::from_le_bytes(header[6..14].try_into().unwrap_or_default()))
+        .find(|&granule| granule != NO_GRANULE_POSITION);
+
+    Ok(granule)
+}
+
+impl AudioTag for OggTag {
+    fn get(&self, key: &str) -> Option<Vec<String>> {
+        let values: Vec<_> = self
+            .comments
+            .entries
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.clone())
+            .collect();
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(values)
+        }
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) -> anyhow::Result<()> {
+        let position = self
+            .comments
+            .entries
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(key))
+            .unwrap_or(self.comments.entries.len());
+        self.comments
+            .entries
+            .retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        let position = position.min(self.comments.entries.len());
+        self.comments.entries.splice(
+            position..position,
+            values.into_iter().map(|value| (key.to_string(), value)),
+        );
+
+        Ok(())
+    }
+
+    fn to_map(&self) -> TagMap {
+        let mut metadata = TagMap::new();
+        for (key, value) in &self.comments.entries {
+            metadata
+                .entry(key.to_uppercase())
+                .or_default()
+                .push(value.clone());
+        }
+
+        metadata
+    }
+
+    fn properties(&self) -> AudioProperties {
+        self.properties
+    }
+
+    fn write_to_path(&mut self, filepath: &Path) -> anyhow::Result<()> {
+        let bytes = fs::read(filepath)?;
+        let mut reader = bytes.as_slice();
+        let mut headers = OggHeaders::read_from(&mut reader)?;
+
+        headers.packets[1] = self
+            .comments
+            .to_packet(headers.codec.comment_packet_prefix());
+        let new_header_pages = headers.to_pages();
+
+        let old_page_count = u32::try_from(headers.page_count)?;
+        let new_page_count = u32::try_from(new_header_pages.len())?;
+
+        let mut output = Vec::with_capacity(bytes.len());
+        for page in &new_header_pages {
+            output.extend_from_slice(&page.to_bytes());
+        }
+
+        let audio_start = bytes.len() - reader.len();
+        if old_page_count == new_page_count {
+            // Audio pages are untouched, including their checksums and granule positions
+            output.extend_from_slice(&bytes[audio_start..]);
+        } else {
+            while let Some(mut page) = OggPage::read_from(&mut reader)? {
+                if page.serial == headers.serial {
+                    page.sequence = page
+                        .sequence
+                        .wrapping_sub(old_page_count)
+                        .wrapping_add(new_page_count);
+                }
+                output.extend_from_slice(&page.to_bytes());
+            }
+        }
+
+        fs::write(filepath, output)?;
+
+        Ok(())
+    }
+}
+
+/// Vorbis comment block as stored in the Vorbis and Opus comment headers.
+struct VorbisComments {
+    vendor: Vec<u8>,
+    entries: Vec<(String, String)>,
+    /// Opus allows arbitrary binary data after the comments, it has to be preserved
+    trailing_data: Vec<u8>,
+}
+
+impl VorbisComments {
+    fn from_packet(packet: &[u8], prefix: &[u8]) -> anyhow::Result<Self> {
+        let mut data = packet
+            .strip_prefix(prefix)
+            .ok_or_else(|| anyhow!("Invalid Ogg comment header"))?;
+
+        let vendor_len = read_u32(&mut data)?;
+        let vendor = read_chunk(&mut data, vendor_len)?.to_vec();
+        let count = read_u32(&mut data)?;
+        // The count is read from the file, every comment takes at least its length field
+        let mut entries = Vec::with_capacity(count.min(data.len() / 4));
+        for _ in 0..count {
+            let len = read_u32(&mut data)?;
+            let comment = String::from_utf8_lossy(read_chunk(&mut data, len)?);
+            let (key, value) = comment.split_once('=').unwrap_or((&comment, ""));
+            entries.push((key.to_string(), value.to_string()));
+        }
+
+        Ok(Self {
+            vendor,
+            entries,
+            trailing_data: data.to_vec(),
+        })
+    }
+
+    fn to_packet(&self, prefix: &[u8]) -> Vec<u8> {
+        let mut packet = prefix.to_vec();
+        let write_u32 = |packet: &mut Vec<u8>, len: usize| {
+            packet.extend_from_slice(&u32::try_from(len).unwrap_or(u32::MAX).to_le_bytes());
+        };
+
+        write_u32(&mut packet, self.vendor.len());
+        packet.extend_from_slice(&self.vendor);
+        write_u32(&mut packet, self.entries.len());
+        for (key, value) in &self.entries {
+            let comment = format!("{key}={value}");
+            write_u32(&mut packet, comment.len());
+            packet.extend_from_slice(comment.as_bytes());
+        }
+        packet.extend_from_slice(&self.trailing_data);
+
+        packet
+    }
+}
+
+fn read_chunk<'a>(data: &mut &'a [u8], len: usize) -> anyhow::Result<&'a [u8]> {
+    let (chunk, rest) = data
+        .split_at_checked(len)
+        .ok_or_else(|| anyhow!("Invalid Ogg comment header"))?;
+    *data = rest;
+
+    Ok(chunk)
+}
+
+fn read_u32(data: &mut &[u8]) -> anyhow::Result<usize> {
+    Ok(u32::from_le_bytes(read_chunk(data, 4)?.try_into()?) as usize)
+}
+
+/// CRC-32 lookup table used by Ogg pages (polynomial 0x04c11db7, no reflection).
+static CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x04c1_1db7
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) ^ u32::from(byte)) as usize]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const SERIAL: u32 = 0x1234_5678;
+    const AUDIO_DATA: &[u8] = b"not really audio, but the pages don't care";
+
+    fn comment_packet(prefix: &[u8], entries: &[(&str, &str)], trailing_data: &[u8]) -> Vec<u8> {
+        VorbisComments {
+            vendor: b"ffery".to_vec(),
+            entries: entries
+                .iter()
+                .map(|(key, value)| ((*key).to_string(), (*value).to_string()))
+                .collect(),
+            trailing_data: trailing_data.to_vec(),
+        }
+        .to_packet(prefix)
+    }
+
+    fn vorbis_packets(entries: &[(&str, &str)]) -> Vec<Vec<u8>> {
+        let mut identification = b"\x01vorbis".to_vec();
+        identification.extend_from_slice(&0u32.to_le_bytes());
+        identification.push(2);
+        identification.extend_from_slice(&44_100u32.to_le_bytes());
+        identification.extend_from_slice(&[0; 12]);
+        identification.extend_from_slice(&[0xB8, 1]);
+
+        vec![
+            identification,
+            comment_packet(b"\x03vorbis", entries, &[1]),
+            b"\x05vorbis setup".to_vec(),
+        ]
+    }
+
+    fn opus_packets(entries: &[(&str, &str)]) -> Vec<Vec<u8>> {
+        let mut identification = b"OpusHead".to_vec();
+        identification.extend_from_slice(&[1, 2]);
+        identification.extend_from_slice(&312u16.to_le_bytes());
+        identification.extend_from_slice(&44_100u32.to_le_bytes());
+        identification.extend_from_slice(&[0; 3]);
+
+        vec![identification, comment_packet(b"OpusTags", entries, &[])]
+    }
+
+    /// Writes the header packets followed by a single audio page ending at `last_granule`.
+    fn write_stream(codec: Codec, packets: Vec<Vec<u8>>, last_granule: u64) -> (TempDir, PathBuf) {
+        let headers = OggHeaders {
+            codec,
+            serial: SERIAL,
+            packets,
+            page_count: 0,
+        };
+        let mut pages = headers.to_pages();
+        pages.push(OggPage {
+            flags: 0x04,
+            granule_position: last_granule,
+            serial: SERIAL,
+            sequence: u32::try_from(pages.len()).unwrap(),
+            segment_table: vec![u8::try_from(AUDIO_DATA.len()).unwrap()],
+            data: AUDIO_DATA.to_vec(),
+        });
+
+        let temp_dir = TempDir::new().unwrap();
+        let filepath = temp_dir.path().join("song.ogg");
+        fs::write(
+            &filepath,
+            pages.iter().flat_map(OggPage::to_bytes).collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        (temp_dir, filepath)
+    }
+
+    fn read_pages(filepath: &Path) -> Vec<OggPage> {
+        let bytes = fs::read(filepath).unwrap();
+        let mut reader = bytes.as_slice();
+        let mut pages = vec![];
+        while let Some(page) = OggPage::read_from(&mut reader).unwrap() {
+            pages.push(page);
+        }
+
+        pages
+    }
+
+    #[test]
+    fn reads_vorbis_comments_and_properties() {
+        let (_temp_dir, filepath) = write_stream(
+            Codec::Vorbis,
+            vorbis_packets(&[("ARTIST", "Björk"), ("genre", "Pop"), ("GENRE", "Rock")]),
+            3 * 44_100,
+        );
+
+        let tag = OggTag::read_from_path(&filepath).unwrap();
+        assert_eq!(tag.get_first("ARTIST").as_deref(), Some("Björk"));
+        assert_eq!(tag.get("Genre"), Some(vec!["Pop".into(), "Rock".into()]));
+        assert_eq!(tag.to_map()["GENRE"].len(), 2);
+        let properties = tag.properties();
+        assert_eq!(properties.duration_secs, Some(3.0));
+        assert_eq!(properties.sample_rate, Some(44_100));
+        assert_eq!(properties.channels, Some(2));
+    }
+
+    #[test]
+    fn reads_opus_duration_without_pre_skip() {
+        let (_temp_dir, filepath) = write_stream(
+            Codec::Opus,
+            opus_packets(&[("TITLE", "Song")]),
+            2 * 48_000 + 312,
+        );
+
+        let tag = OggTag::read_from_path(&filepath).unwrap();
+        assert_eq!(tag.get_first("TITLE").as_deref(), Some("Song"));
+        assert_eq!(tag.properties().duration_secs, Some(2.0));
+        assert_eq!(tag.properties().sample_rate, Some(44_100));
+    }
+
+    #[test]
+    fn small_edit_keeps_audio_pages_untouched() {
+        let (_temp_dir, filepath) = write_stream(
+            Codec::Vorbis,
+            vorbis_packets(&[("ARTIST", "Björk")]),
+            44_100,
+        );
+        let old_audio_page = read_pages(&filepath).pop().unwrap().to_bytes();
+
+        let mut tag = OggTag::read_from_path(&filepath).unwrap();
+        tag.set("artist", vec!["Bjork".into()]).unwrap();
+        tag.set("ALBUM", vec!["Debut".into()]).unwrap();
+        tag.write_to_path(&filepath).unwrap();
+
+        let bytes = fs::read(&filepath).unwrap();
+        assert!(bytes.ends_with(&old_audio_page));
+        let tag = OggTag::read_from_path(&filepath).unwrap();
+        assert_eq!(tag.get_first("ARTIST").as_deref(), Some("Bjork"));
+        assert_eq!(tag.get_first("ALBUM").as_deref(), Some("Debut"));
+    }
+
+    #[test]
+    fn large_edit_repacketizes_and_renumbers_pages() {
+        let (_temp_dir, filepath) =
+            write_stream(Codec::Opus, opus_packets(&[("TITLE", "Song")]), 48_312);
+        assert_eq!(read_pages(&filepath).len(), 3);
+
+        // Longer than the 255 segments of 255 bytes a single page can hold
+        let long_value = "x".repeat(70_000);
+        let mut tag = OggTag::read_from_path(&filepath).unwrap();
+        tag.set("COMMENT", vec![long_value.clone()]).unwrap();
+        tag.write_to_path(&filepath).unwrap();
+
+        let pages = read_pages(&filepath);
+        assert!(pages.len() > 3);
+        for (sequence, page) in pages.iter().enumerate() {
+            assert_eq!(page.sequence, u32::try_from(sequence).unwrap());
+            assert_eq!(page.serial, SERIAL);
+        }
+        // The comment header continues on the pages after the first one
+        assert_eq!(pages[1].flags & FLAG_CONTINUED_PACKET, 0);
+        assert_eq!(
+            pages[2].flags & FLAG_CONTINUED_PACKET,
+            FLAG_CONTINUED_PACKET
+        );
+        assert_eq!(pages[1].granule_position, NO_GRANULE_POSITION);
+        let audio_page = pages.last().unwrap();
+        assert_eq!(audio_page.data, AUDIO_DATA);
+        assert_eq!(audio_page.granule_position, 48_312);
+
+        let tag = OggTag::read_from_path(&filepath).unwrap();
+        assert_eq!(tag.get_first("COMMENT"), Some(long_value));
+        assert_eq!(tag.get_first("TITLE").as_deref(), Some("Song"));
+        assert_eq!(tag.properties().duration_secs, Some(1.0));
+    }
+
+    #[test]
+    fn packet_of_full_segments_ends_with_empty_segment() {
+        let packets = vec![b"OpusHead".to_vec(), vec![0; 2 * MAX_SEGMENT_LEN]];
+        let headers = OggHeaders {
+            codec: Codec::Opus,
+            serial: SERIAL,
+            packets,
+            page_count: 0,
+        };
+
+        let pages = headers.to_pages();
+        assert_eq!(pages[1].segment_table, [255, 255, 0]);
+    }
+
+    #[test]
+    fn rejects_corrupt_page_checksum() {
+        let (_temp_dir, filepath) = write_stream(Codec::Vorbis, vorbis_packets(&[]), 44_100);
+        let mut bytes = fs::read(&filepath).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(&filepath, bytes).unwrap();
+
+        let bytes = fs::read(&filepath).unwrap();
+        let mut reader = bytes.as_slice();
+        let result = (0..4).try_for_each(|_| OggPage::read_from(&mut reader).map(|_| ()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_comment_count_beyond_packet() {
+        let mut packet = b"OpusTags".to_vec();
+        packet.extend_from_slice(&0u32.to_le_bytes());
+        packet.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(VorbisComments::from_packet(&packet, b"OpusTags").is_err());
+    }
+
+    #[test]
+    fn preserves_trailing_data_and_vendor() {
+        let packet = comment_packet(b"OpusTags", &[("A", "1"), ("B", "")], b"\x00binary");
+
+        let comments = VorbisComments::from_packet(&packet, b"OpusTags").unwrap();
+        assert_eq!(comments.vendor, b"ffery");
+        assert_eq!(comments.trailing_data, b"\x00binary");
+        assert_eq!(comments.to_packet(b"OpusTags"), packet);
+    }
+}
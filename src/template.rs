@@ -0,0 +1,825 @@
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+
+/// Variables that are available in the filename and directory templates.
+pub const VARIABLES: &[&str] = &[
+    "artist",
+    "album_artist",
+    "track_artist",
+    "title",
+    "album",
+    "disc_number",
+    "track_number",
+    "src_dir",
+    "year",
+    "total_tracks",
+    "total_discs",
+    "duration",
+    "ext",
+    "filename",
+];
+
+/// Variables that are padded with leading zeros to `--pad-width`, the fallback for the ones
+/// without a `pad` filter.
+const NUMBER_VARIABLES: &[&str] = &["disc_number", "track_number", "total_tracks", "total_discs"];
+
+/// Prefix of the variables of the tag fields, e.g. `tags.GENRE`.
+const TAGS_PREFIX: &str = "tags.";
+
+/// Variables that are always set, the others are missing if the song has no such tag. The
+/// artists fall back to "Various Artists".
+const ALWAYS_SET_VARIABLES: &[&str] = &[
+    "src_dir",
+    "ext",
+    "filename",
+    "artist",
+    "album_artist",
+    "track_artist",
+];
+
+/// Prefix of the variables a filtered tag is replaced with before compiling.
+const FILTERED_PREFIX: &str = "__filtered_";
+
+/// The filters with their usage, e.g. `{{album | default "Unknown Album" | upper}}`.
+const FILTERS: &[&str] = &[
+    "lower",
+    "upper",
+    "first",
+    "trim",
+    "truncate N",
+    "pad N",
+    "default \"TEXT\"",
+    "replace \"FROM\" \"TO\"",
+];
+
+#[derive(PartialEq, Eq)]
+enum TagKind {
+    Variable,
+    Section,
+    InvertedSection,
+    Close,
+    Comment,
+}
+
+/// A `{{...}}` tag of a template. `start` and `end` are the byte offsets of its opening and
+/// after its closing braces.
+struct Tag<'a> {
+    kind: TagKind,
+    name: &'a str,
+    filters: Vec<Filter>,
+    /// The opening braces including `&`, kept when the tag is rewritten
+    opening: &'static str,
+    closing: &'static str,
+    start: usize,
+    end: usize,
+}
+
+#[derive(PartialEq, Eq)]
+enum Filter {
+    Lower,
+    Upper,
+    First,
+    Trim,
+    Truncate(usize),
+    Pad(usize),
+    Default(String),
+    Replace(String, String),
+}
+
+impl Filter {
+    fn parse(name: &str, args: &[String]) -> Result<Self, String> {
+        let number = |arg: &String| {
+            arg.parse::<usize>()
+                .map_err(|_| format!("'{name}' expects a number, got '{arg}'"))
+        };
+
+        match (name, args) {
+            ("lower", []) => Ok(Self::Lower),
+            ("upper", []) => Ok(Self::Upper),
+            ("first", []) => Ok(Self::First),
+            ("trim", []) => Ok(Self::Trim),
+            ("truncate", [len]) => number(len).map(Self::Truncate),
+            ("pad", [width]) => number(width).map(Self::Pad),
+            ("default", [text]) => Ok(Self::Default(text.clone())),
+            ("replace", [from, to]) => Ok(Self::Replace(from.clone(), to.clone())),
+            _ => FILTERS
+                .iter()
+                .find(|usage| usage.split(' ').next() == Some(name))
+                .map_or_else(
+                    || {
+                        Err(format!(
+                            "unknown filter '{name}'. Known filters: {}",
+                            FILTERS.join(", ")
+                        ))
+                    },
+                    |usage| Err(format!("wrong arguments for '{name}', use '{usage}'")),
+                ),
+        }
+    }
+
+    /// Applies the filter, only `default` changes a missing value.
+    fn apply(&self, value: Option<String>) -> Option<String> {
+        if let Self::Default(text) = self {
+            return value
+                .filter(|value| !value.is_empty())
+                .or_else(|| Some(text.clone()));
+        }
+
+        let value = value?;
+        Some(match self {
+            Self::Lower => value.to_lowercase(),
+            Self::Upper => value.to_uppercase(),
+            Self::First => value.chars().take(1).collect(),
+            Self::Trim => value.trim().to_string(),
+            Self::Truncate(len) => value
+                .chars()
+                .take(*len)
+                .collect::<String>()
+                .trim_end()
+                .to_string(),
+            Self::Pad(width) => format!("{value:0>width$}"),
+            Self::Replace(from, to) => value.replace(from.as_str(), to),
+            Self::Default(_) => value,
+        })
+    }
+}
+
+/// A filtered variable of a template, rendered into `{FILTERED_PREFIX}{index}`.
+struct FilteredVariable {
+    name: String,
+    filters: Vec<Filter>,
+}
+
+/// A syntax error or an unknown variable at the byte offset `offset` of a template.
+struct TemplateError {
+    message: String,
+    offset: usize,
+}
+
+impl TemplateError {
+    fn new(message: impl Into<String>, offset: usize) -> Self {
+        Self {
+            message: message.into(),
+            offset,
+        }
+    }
+}
+
+/// The values of the variables of a song, missing values aren't set. Tag fields are set as
+/// `tags.KEY` and numbers without padding.
+#[derive(Default)]
+pub struct Variables(BTreeMap<String, String>);
+
+impl Variables {
+    pub fn insert(&mut self, name: &str, value: Option<impl Into<String>>) {
+        if let Some(value) = value {
+            self.0.insert(name.to_string(), value.into());
+        }
+    }
+}
+
+/// A validated and compiled filename or directory template.
+pub struct Template {
+    compiled: mustache::Template,
+    filtered: Vec<FilteredVariable>,
+    /// Every variable used by the template, in order of appearance
+    variables: Vec<String>,
+}
+
+impl Template {
+    /// Validates `template` of the option `option` (e.g. `--filename-template`) and compiles
+    /// it. Syntax errors, unknown variables and filters are reported with their position,
+    /// before anything is read or written.
+    pub fn compile(option: &str, template: &str) -> anyhow::Result<Self> {
+        let tags = validate(template).map_err(|err| {
+            let (line, column) = line_and_column(template, err.offset);
+            let template_line = template.lines().nth(line - 1).unwrap_or_default();
+            anyhow!(
+                "Invalid {option} at line {line}, column {column}: {}\n{template_line}\n{:>column$}",
+                err.message,
+                "^"
+            )
+        })?;
+
+        // Mustache has no filters, filtered tags are replaced with variables of their own
+        let mut rewritten = String::new();
+        let mut filtered = vec![];
+        let mut variables: Vec<String> = vec![];
+        let mut pos = 0;
+        for tag in tags {
+            if tag.kind != TagKind::Close
+                && tag.kind != TagKind::Comment
+                && tag.name != "."
+                && !variables.iter().any(|variable| variable == tag.name)
+            {
+                variables.push(tag.name.to_string());
+            }
+            if tag.filters.is_empty() {
+                continue;
+            }
+
+            rewritten.push_str(&template[pos..tag.start]);
+            rewritten.push_str(tag.opening);
+            rewritten.push_str(&filtered_name(filtered.len()));
+            rewritten.push_str(tag.closing);
+            pos = tag.end;
+            filtered.push(FilteredVariable {
+                name: tag.name.to_string(),
+                filters: tag.filters,
+            });
+        }
+        rewritten.push_str(&template[pos..]);
+
+        let compiled =
+            mustache::compile_str(&rewritten).map_err(|err| anyhow!("Invalid {option}: {err}"))?;
+
+        Ok(Self {
+            compiled,
+            filtered,
+            variables,
+        })
+    }
+
+    /// Renders the template. `pad_width` is the fallback for numbers without a `pad` filter,
+    /// they are padded once a `default` filter has set them.
+    pub fn render(&self, variables: &Variables, pad_width: usize) -> anyhow::Result<String> {
+        let padded = |name: &str, value: String| {
+            if NUMBER_VARIABLES.contains(&name) && value.bytes().all(|byte| byte.is_ascii_digit()) {
+                format!("{value:0>pad_width$}")
+            } else {
+                value
+            }
+        };
+
+        let mut data = mustache::MapBuilder::new();
+        let mut tags = BTreeMap::new();
+        for (name, value) in &variables.0 {
+            match name.strip_prefix(TAGS_PREFIX) {
+                Some(key) => {
+                    tags.insert(key, value);
+                }
+                None => data = data.insert_str(name, padded(name, value.clone())),
+            }
+        }
+        data = data.insert("tags", &tags)?;
+
+        for (i, filtered) in self.filtered.iter().enumerate() {
+            let has_pad_filter = filtered
+                .filters
+                .iter()
+                .any(|filter| matches!(filter, Filter::Pad(_)));
+            let defaulted_len = filtered
+                .filters
+                .iter()
+                .rposition(|filter| matches!(filter, Filter::Default(_)))
+                .map_or(0, |i| i + 1);
+            let (default_filters, other_filters) = filtered.filters.split_at(defaulted_len);
+
+            let value = default_filters
+                .iter()
+                .fold(variables.0.get(&filtered.name).cloned(), |value, filter| {
+                    filter.apply(value)
+                });
+            let value = if has_pad_filter {
+                value
+            } else {
+                value.map(|value| padded(&filtered.name, value))
+            };
+            let value = other_filters
+                .iter()
+                .fold(value, |value, filter| filter.apply(value));
+            if let Some(value) = value {
+                data = data.insert_str(filtered_name(i), value);
+            }
+        }
+
+        Ok(self.compiled.render_data_to_string(&data.build())?)
+    }
+
+    /// Returns the variables that can be missing and make the template render an empty
+    /// string, or `None` if it always renders something.
+    pub fn empty_render_variables(&self) -> anyhow::Result<Option<Vec<String>>> {
+        let mut variables = Variables::default();
+        for &variable in ALWAYS_SET_VARIABLES {
+            variables.insert(variable, Some(variable));
+        }
+        if !self.render(&variables, 0)?.trim().is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            self.variables
+                .iter()
+                .filter(|variable| !ALWAYS_SET_VARIABLES.contains(&variable.as_str()))
+                .cloned()
+                .collect(),
+        ))
+    }
+}
+
+fn filtered_name(index: usize) -> String {
+    format!("{FILTERED_PREFIX}{index}")
+}
+
+fn validate(template: &str) -> Result<Vec<Tag<'_>>, TemplateError> {
+    let tags = parse_tags(template)?;
+    let mut open_sections: Vec<&Tag> = vec![];
+    for tag in &tags {
+        match tag.kind {
+            TagKind::Section | TagKind::InvertedSection => {
+                validate_variable(tag.name, tag.start)?;
+                open_sections.push(tag);
+            }
+            TagKind::Close => match open_sections.pop() {
+                Some(section) if section.name == tag.name => {}
+                Some(section) => {
+                    return Err(TemplateError::new(
+                        format!(
+                            "'{{{{/{}}}}}' closes the section '{}' instead",
+                            tag.name, section.name
+                        ),
+                        tag.start,
+                    ));
+                }
+                None => {
+                    return Err(TemplateError::new(
+                        format!("'{{{{/{}}}}}' closes a section that isn't open", tag.name),
+                        tag.start,
+                    ));
+                }
+            },
+            // `{{.}}` is the value of the enclosing section
+            TagKind::Variable if tag.name == "." && !open_sections.is_empty() => {}
+            TagKind::Variable => validate_variable(tag.name, tag.start)?,
+            TagKind::Comment => {}
+        }
+    }
+
+    if let Some(section) = open_sections.pop() {
+        return Err(TemplateError::new(
+            format!("the section '{}' is never closed", section.name),
+            section.start,
+        ));
+    }
+
+    Ok(tags)
+}
+
+fn validate_variable(name: &str, offset: usize) -> Result<(), TemplateError> {
+    if VARIABLES.contains(&name) {
+        return Ok(());
+    }
+    if let Some(key) = name.strip_prefix(TAGS_PREFIX) {
+        // Tag fields are keyed by their uppercase name
+        if !key.is_empty() && !key.contains('.') && key == key.to_uppercase() {
+            return Ok(());
+        }
+        let example = if key.is_empty() || key.contains('.') {
+            "GENRE".to_string()
+        } else {
+            key.to_uppercase()
+        };
+        return Err(TemplateError::new(
+            format!(
+                "invalid tag variable '{name}', use the uppercase name of the field, e.g. '{TAGS_PREFIX}{example}'"
+            ),
+            offset,
+        ));
+    }
+
+    let comparable = |name: &str| name.replace('_', "").to_lowercase();
+    let suggestion = VARIABLES
+        .iter()
+        .find(|variable| comparable(variable) == comparable(name))
+        .map(ToString::to_string)
+        .or_else(|| {
+            // Most likely a tag field, e.g. `{{genre}}`
+            name.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                .then(|| format!("{TAGS_PREFIX}{}", name.to_uppercase()))
+        })
+        .map_or_else(
+            || String::from("."),
+            |variable| format!(", did you mean '{variable}'?"),
+        );
+
+    Err(TemplateError::new(
+        format!(
+            "unknown variable '{name}'{suggestion} Known variables: {}, {TAGS_PREFIX}KEY",
+            VARIABLES.join(", ")
+        ),
+        offset,
+    ))
+}
+
+fn parse_tags(template: &str) -> Result<Vec<Tag<'_>>, TemplateError> {
+    let mut tags = vec![];
+
+    let mut pos = 0;
+    while let Some(start) = template[pos..].find("{{").map(|start| pos + start) {
+        let (opening, closing) = if template[start..].starts_with("{{{") {
+            ("{{{", "}}}")
+        } else {
+            ("{{", "}}")
+        };
+        let content_start = start + opening.len();
+        let content_end = template[content_start..]
+            .find(closing)
+            .map(|end| content_start + end)
+            .filter(|end| !template[content_start..*end].contains("{{"))
+            .ok_or_else(|| {
+                TemplateError::new(
+                    format!("'{opening}' is never closed with '{closing}'"),
+                    start,
+                )
+            })?;
+        pos = content_end + closing.len();
+
+        let content = template[content_start..content_end].trim();
+        let (kind, opening, expression) = if opening == "{{{" {
+            (TagKind::Variable, opening, content)
+        } else {
+            match content.chars().next() {
+                Some('#') => (TagKind::Section, opening, &content[1..]),
+                Some('^') => (TagKind::InvertedSection, opening, &content[1..]),
+                Some('/') => (TagKind::Close, opening, &content[1..]),
+                Some('!') => (TagKind::Comment, opening, &content[1..]),
+                Some('&') => (TagKind::Variable, "{{&", &content[1..]),
+                Some('>') => return Err(TemplateError::new("partials aren't supported", start)),
+                Some('=') => {
+                    return Err(TemplateError::new(
+                        "changing the delimiters isn't supported",
+                        start,
+                    ));
+                }
+                _ => (TagKind::Variable, opening, content),
+            }
+        };
+        if kind == TagKind::Comment {
+            continue;
+        }
+
+        let (name, filters) = match expression.split_once('|') {
+            Some((name, filters)) if kind == TagKind::Variable => {
+                let filters =
+                    parse_filters(filters).map_err(|message| TemplateError::new(message, start))?;
+                (name.trim(), filters)
+            }
+            Some(_) => {
+                return Err(TemplateError::new(
+                    "filters can only be used in variables, not in sections",
+                    start,
+                ));
+            }
+            None => (expression.trim(), vec![]),
+        };
+        if name.is_empty() {
+            return Err(TemplateError::new("the tag has no variable name", start));
+        }
+        if name == "." && !filters.is_empty() {
+            return Err(TemplateError::new("'.' can't be filtered", start));
+        }
+
+        tags.push(Tag {
+            kind,
+            name,
+            filters,
+            opening,
+            closing,
+            start,
+            end: pos,
+        });
+    }
+
+    Ok(tags)
+}
+
+/// Parses the filters after the variable name, e.g. `default "Unknown Album" | upper`.
+fn parse_filters(expression: &str) -> Result<Vec<Filter>, String> {
+    let mut filters = vec![];
+    let mut words: Vec<String> = vec![];
+    let mut chars = expression.chars().peekable();
+    loop {
+        match chars.next() {
+            Some(c) if c.is_whitespace() => {}
+            Some('"') => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err(String::from("a quoted argument is never closed")),
+                    }
+                }
+                words.push(word);
+            }
+            Some(c) if c != '|' => {
+                let mut word = String::from(c);
+                while let Some(&c) = chars.peek()
+                    && !c.is_whitespace()
+                    && c != '|'
+                {
+                    word.push(c);
+                    chars.next();
+                }
+                words.push(word);
+            }
+            c => {
+                let Some((name, args)) = words.split_first() else {
+                    return Err(String::from("a filter is missing after '|'"));
+                };
+                filters.push(Filter::parse(name, args)?);
+                words.clear();
+                if c.is_none() {
+                    return Ok(filters);
+                }
+            }
+        }
+    }
+}
+
+/// The 1-based line and column (in characters) of the byte offset `offset` of `template`.
+fn line_and_column(template: &str, offset: usize) -> (usize, usize) {
+    let before = &template[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+
+    (line, template[line_start..offset].chars().count() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_with(template: &str, values: &[(&str, &str)], pad_width: usize) -> String {
+        let mut variables = Variables::default();
+        for (name, value) in values {
+            variables.insert(name, Some(*value));
+        }
+
+        Template::compile("--filename-template", template)
+            .unwrap()
+            .render(&variables, pad_width)
+            .unwrap()
+    }
+
+    fn render(template: &str, values: &[(&str, &str)]) -> String {
+        render_with(template, values, 2)
+    }
+
+    fn compile_error(template: &str) -> String {
+        Template::compile("--filename-template", template)
+            .err()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn applies_every_filter() {
+        let title = [("title", "  Bohemian Rhapsody  ")];
+
+        assert_eq!(render("{{title | lower}}", &title), "  bohemian rhapsody  ");
+        assert_eq!(render("{{title | upper}}", &title), "  BOHEMIAN RHAPSODY  ");
+        assert_eq!(render("{{title | trim}}", &title), "Bohemian Rhapsody");
+        assert_eq!(render("{{title | trim | first}}", &title), "B");
+        assert_eq!(render("{{title | first}}", &[("title", "Élan")]), "É");
+        assert_eq!(render("{{title | trim | truncate 9}}", &title), "Bohemian");
+        assert_eq!(render("{{title | pad 5}}", &[("title", "ab")]), "000ab");
+        assert_eq!(
+            render("{{title | replace \"Rhapsody\" \"Song\" | trim}}", &title),
+            "Bohemian Song"
+        );
+    }
+
+    #[test]
+    fn default_replaces_missing_and_empty_values() {
+        let template = "{{artist | default \"Unknown Artist\"}}";
+
+        assert_eq!(render(template, &[]), "Unknown Artist");
+        assert_eq!(render(template, &[("artist", "")]), "Unknown Artist");
+        assert_eq!(render(template, &[("artist", "Queen")]), "Queen");
+        // The other filters skip missing values
+        assert_eq!(render("{{artist | upper}}", &[]), "");
+        assert_eq!(render("{{artist | upper | default \"none\"}}", &[]), "none");
+    }
+
+    #[test]
+    fn applies_filters_in_order() {
+        let artist = [("artist", "Queen")];
+
+        assert_eq!(render("{{artist | first | lower}}", &artist), "q");
+        assert_eq!(render("{{artist | lower | replace q k}}", &artist), "kueen");
+        assert_eq!(render("{{artist | replace q k | lower}}", &artist), "queen");
+    }
+
+    #[test]
+    fn parses_quoted_arguments() {
+        let title = [("title", "A|B C")];
+
+        assert_eq!(
+            render("{{title | replace \"|\" \" - \"}}", &title),
+            "A - B C"
+        );
+        assert_eq!(render("{{title | replace \" \" \"\"}}", &title), "A|BC");
+        assert_eq!(
+            render("{{title | replace \"C\" \"\\\"C\\\"\"}}", &title),
+            "A|B &quot;C&quot;"
+        );
+        assert_eq!(
+            render("{{{title | replace \"C\" \"\\\\\"}}}", &title),
+            "A|B \\"
+        );
+        assert_eq!(render("{{title|replace B X|lower}}", &title), "a|x c");
+    }
+
+    #[test]
+    fn keeps_the_escaping_of_filtered_tags() {
+        let title = [("title", "Salt & Pepper")];
+
+        assert_eq!(render("{{title | upper}}", &title), "SALT &amp; PEPPER");
+        assert_eq!(render("{{{title | upper}}}", &title), "SALT & PEPPER");
+        assert_eq!(render("{{&title | upper}}", &title), "SALT & PEPPER");
+        assert_eq!(
+            render(
+                "{{{ title }}} / {{{ title | lower }}} / {{{ title | upper }}}",
+                &title
+            ),
+            "Salt & Pepper / salt & pepper / SALT & PEPPER"
+        );
+    }
+
+    #[test]
+    fn renders_filtered_tags_in_sections() {
+        let template = "{{#disc_number}}{{disc_number | pad 3}}-{{/disc_number}}{{track_number}}";
+
+        assert_eq!(
+            render(template, &[("disc_number", "1"), ("track_number", "7")]),
+            "001-07"
+        );
+        assert_eq!(render(template, &[("track_number", "7")]), "07");
+    }
+
+    #[test]
+    fn pad_filter_takes_precedence_over_pad_width() {
+        let track = [("track_number", "7")];
+
+        assert_eq!(render_with("{{track_number}}", &track, 3), "007");
+        assert_eq!(render_with("{{track_number | pad 1}}", &track, 3), "7");
+        assert_eq!(render_with("{{track_number | pad 4}}", &track, 3), "0007");
+        // Other filters keep the padding of the number
+        assert_eq!(render_with("{{track_number | trim}}", &track, 3), "007");
+        // Only numbers are padded to the pad width
+        assert_eq!(render_with("{{title}}", &[("title", "7")], 3), "7");
+    }
+
+    #[test]
+    fn pads_numbers_set_by_default() {
+        let template = "{{disc_number | default \"1\"}}";
+
+        assert_eq!(render(template, &[]), "01");
+        assert_eq!(render(template, &[("disc_number", "2")]), "02");
+        assert_eq!(
+            render("{{disc_number | default \"1\" | pad 3}}", &[]),
+            "001"
+        );
+        assert_eq!(render("{{disc_number | upper | default \"1\"}}", &[]), "01");
+        // Text isn't padded
+        assert_eq!(render("{{disc_number | default \"none\"}}", &[]), "none");
+    }
+
+    #[test]
+    fn renders_tag_fields() {
+        assert_eq!(
+            render(
+                "{{tags.GENRE | lower}}/{{tags.GENRE}}",
+                &[("tags.GENRE", "Rock")]
+            ),
+            "rock/Rock"
+        );
+    }
+
+    #[test]
+    fn reports_filter_errors() {
+        assert!(
+            compile_error("{{title | shout}}")
+                .contains("unknown filter 'shout'. Known filters: lower")
+        );
+        assert!(
+            compile_error("{{title | truncate}}")
+                .contains("wrong arguments for 'truncate', use 'truncate N'")
+        );
+        assert!(compile_error("{{title | pad x}}").contains("'pad' expects a number, got 'x'"));
+        assert!(
+            compile_error("{{title | upper 2}}")
+                .contains("wrong arguments for 'upper', use 'upper'")
+        );
+        assert!(
+            compile_error("{{title | replace \"a\"}}").contains("use 'replace \"FROM\" \"TO\"'")
+        );
+        assert!(
+            compile_error("{{title | default \"x}}").contains("a quoted argument is never closed")
+        );
+        assert!(compile_error("{{title | }}").contains("a filter is missing after '|'"));
+        assert!(compile_error("{{title | upper | }}").contains("a filter is missing after '|'"));
+        assert!(
+            compile_error("{{#title | upper}}{{/title}}")
+                .contains("filters can only be used in variables")
+        );
+        assert!(
+            compile_error("{{#title}}{{. | upper}}{{/title}}").contains("'.' can't be filtered")
+        );
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        assert!(compile_error("{{title").contains("'{{' is never closed with '}}'"));
+        assert!(compile_error("{{{title}}").contains("'{{{' is never closed with '}}}'"));
+        assert!(compile_error("{{title {{album}}").contains("'{{' is never closed"));
+        assert!(compile_error("{{}}").contains("the tag has no variable name"));
+        assert!(compile_error("{{> part}}").contains("partials aren't supported"));
+        assert!(compile_error("{{=<% %>=}}").contains("changing the delimiters isn't supported"));
+        assert!(
+            compile_error("{{#album}}{{/title}}")
+                .contains("'{{/title}}' closes the section 'album' instead")
+        );
+        assert!(compile_error("{{/album}}").contains("closes a section that isn't open"));
+        assert!(compile_error("{{#album}}").contains("the section 'album' is never closed"));
+        assert!(compile_error("{{.}}").contains("unknown variable '.'"));
+    }
+
+    #[test]
+    fn reports_unknown_variables() {
+        assert!(compile_error("{{trackNumber}}").contains("did you mean 'track_number'?"));
+        assert!(compile_error("{{genre}}").contains("did you mean 'tags.GENRE'?"));
+        assert!(compile_error("{{tags.genre}}").contains("e.g. 'tags.GENRE'"));
+        assert!(compile_error("{{tags.}}").contains("invalid tag variable 'tags.'"));
+        assert!(compile_error("{{a-b}}").contains("unknown variable 'a-b'. Known variables"));
+        assert!(Template::compile("--dir-template", "{{! a comment }}{{src_dir}}").is_ok());
+    }
+
+    #[test]
+    fn points_at_the_error() {
+        let error = compile_error("{{artist}}\nÄ {{titel}}");
+
+        assert!(error.starts_with("Invalid --filename-template at line 2, column 3:"));
+        assert!(error.ends_with("Ä {{titel}}\n  ^"));
+    }
+
+    #[test]
+    fn finds_lines_and_columns() {
+        let template = "ab\nÄö{{x}}\n\n{{y}}";
+
+        assert_eq!(line_and_column(template, 0), (1, 1));
+        assert_eq!(line_and_column(template, 2), (1, 3));
+        assert_eq!(line_and_column(template, 3), (2, 1));
+        assert_eq!(
+            line_and_column(template, template.find("{{x").unwrap()),
+            (2, 3)
+        );
+        assert_eq!(
+            line_and_column(template, template.find("{{y").unwrap()),
+            (4, 1)
+        );
+    }
+
+    #[test]
+    fn parses_tag_kinds_and_positions() {
+        let template = "{{#album}}{{{title | upper}}}{{/album}}{{^year}}{{&ext}}{{/year}}";
+        let Ok(tags) = parse_tags(template) else {
+            panic!("'{template}' is a valid template");
+        };
+
+        let summary: Vec<_> = tags
+            .iter()
+            .map(|tag| (tag.name, tag.opening, &template[tag.start..tag.end]))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("album", "{{", "{{#album}}"),
+                ("title", "{{{", "{{{title | upper}}}"),
+                ("album", "{{", "{{/album}}"),
+                ("year", "{{", "{{^year}}"),
+                ("ext", "{{&", "{{&ext}}"),
+                ("year", "{{", "{{/year}}"),
+            ]
+        );
+        assert!(tags[1].filters == [Filter::Upper]);
+    }
+
+    #[test]
+    fn finds_variables_that_render_empty() {
+        let template =
+            Template::compile("--filename-template", "{{track_number}} {{title}}").unwrap();
+        assert_eq!(
+            template.empty_render_variables().unwrap(),
+            Some(vec![String::from("track_number"), String::from("title")])
+        );
+
+        let template = Template::compile("--filename-template", "{{filename}}").unwrap();
+        assert_eq!(template.empty_render_variables().unwrap(), None);
+        let template = Template::compile("--filename-template", "{{artist}}").unwrap();
+        assert_eq!(template.empty_render_variables().unwrap(), None);
+    }
+}
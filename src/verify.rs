@@ -0,0 +1,346 @@
+use indicatif::ProgressBar;
+use serde::Serialize;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{file_utils, progress};
+
+const FLAC_EXTENSIONS: &[&str] = &["flac"];
+
+#[derive(Serialize, Default)]
+struct VerificationReport {
+    checked_count: usize,
+    ok_count: usize,
+    corrupt: Vec<FileIssue>,
+    truncated: Vec<FileIssue>,
+    unsigned: Vec<FileIssue>,
+}
+
+impl VerificationReport {
+    fn from_results(results: Vec<(PathBuf, Verification)>) -> Self {
+        let mut report = Self {
+            checked_count: results.len(),
+            ..Default::default()
+        };
+
+        for (filepath, verification) in results {
+            let (issues, reason) = match verification {
+                Verification::Ok => {
+                    report.ok_count += 1;
+                    continue;
+                }
+                Verification::Corrupt(reason) => (&mut report.corrupt, reason),
+                Verification::Truncated(reason) => (&mut report.truncated, reason),
+                Verification::Unsigned => (
+                    &mut report.unsigned,
+                    String::from("STREAMINFO has no MD5 signature"),
+                ),
+            };
+            issues.push(FileIssue { filepath, reason });
+        }
+
+        report
+    }
+}
+
+#[derive(Serialize)]
+struct FileIssue {
+    filepath: PathBuf,
+    reason: String,
+}
+
+enum Verification {
+    Ok,
+    Corrupt(String),
+    Truncated(String),
+    /// The stream decodes cleanly but there is no MD5 signature to compare against.
+    Unsigned,
+}
+
+impl Verification {
+    fn from_decode_error(error: claxon::Error) -> Self {
+        match error {
+            claxon::Error::IoError(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Self::Truncated(String::from("Unexpected end of stream"))
+            }
+            claxon::Error::IoError(e) => Self::Corrupt(e.to_string()),
+            claxon::Error::FormatError(reason) | claxon::Error::Unsupported(reason) => {
+                Self::Corrupt(String::from(reason))
+            }
+        }
+    }
+}
+
+/// Fully decodes a FLAC file, relying on the decoder to check the header CRC-8 and
+/// footer CRC-16 of every frame, and compares the decoded audio with the MD5 signature
+/// stored in STREAMINFO.
+fn verify_file(filepath: &Path) -> Verification {
+    let mut reader = match claxon::FlacReader::open(filepath) {
+        Ok(reader) => reader,
+        Err(e) => return Verification::from_decode_error(e),
+    };
+
+    let streaminfo = reader.streaminfo();
+    let bytes_per_sample = streaminfo.bits_per_sample.div_ceil(8) as usize;
+    let mut md5_context = md5::Context::new();
+    let mut decoded_samples = 0_u64;
+
+    let mut frame_reader = reader.blocks();
+    let mut buffer = vec![];
+    let mut sample_bytes = vec![];
+    loop {
+        let block = match frame_reader.read_next_or_eof(buffer) {
+            Ok(Some(block)) => block,
+            Ok(None) => break,
+            Err(e) => return Verification::from_decode_error(e),
+        };
+
+        // The signature covers the interleaved samples, little-endian and sign-extended
+        // to a whole number of bytes.
+        sample_bytes.clear();
+        for i in 0..block.duration() {
+            for ch in 0..block.channels() {
+                let sample = block.sample(ch, i).to_le_bytes();
+                sample_bytes.extend_from_slice(&sample[..bytes_per_sample]);
+            }
+        }
+        md5_context.consume(&sample_bytes);
+        decoded_samples += u64::from(block.duration());
+
+        buffer = block.into_buffer();
+    }
+
+    if let Some(expected_samples) = streaminfo.samples {
+        if decoded_samples < expected_samples {
+            return Verification::Truncated(format!(
+                "Decoded {decoded_samples} of {expected_samples} samples"
+            ));
+        }
+        if decoded_samples > expected_samples {
+            return Verification::Corrupt(format!(
+                "Decoded {decoded_samples} samples, STREAMINFO only has {expected_samples}"
+            ));
+        }
+    }
+
+    if streaminfo.md5sum == [0; 16] {
+        return Verification::Unsigned;
+    }
+
+    if md5_context.finalize().0 != streaminfo.md5sum {
+        return Verification::Corrupt(String::from("MD5 signature mismatch"));
+    }
+
+    Verification::Ok
+}
+
+pub fn start_verify_music(src: &Path, output: &Path) -> anyhow::Result<()> {
+    if src.is_file() {
+        let verification = verify_file(src);
+        store_verification_report(vec![(src.to_path_buf(), verification)], output)?;
+        return Ok(());
+    }
+
+    let file_count = file_utils::count_files_by_extension(src, FLAC_EXTENSIONS)?;
+    let bar = progress::get_progress_bar(file_count);
+
+    let results = verify_music(src, &bar)?;
+    bar.finish();
+    store_verification_report(results, output)?;
+
+    Ok(())
+}
+
+fn verify_music(dir: &Path, bar: &ProgressBar) -> anyhow::Result<Vec<(PathBuf, Verification)>> {
+    let mut results = vec![];
+
+    let (flac_files, dirs) = file_utils::walk_directory(dir, FLAC_EXTENSIONS)?;
+
+    for f in flac_files {
+        let verification = verify_file(&f);
+        results.push((f, verification));
+        bar.inc(1);
+    }
+
+    for d in &dirs {
+        let dir_results = verify_music(d, bar)?;
+        results.extend(dir_results);
+    }
+
+    Ok(results)
+}
+
+fn store_verification_report(
+    results: Vec<(PathBuf, Verification)>,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let report = VerificationReport::from_results(results);
+    let json_data = serde_json::to_string(&report)?;
+
+    file_utils::store_data(output, &json_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const BLOCK_SIZE: usize = 64;
+
+    fn crc8(data: &[u8]) -> u8 {
+        data.iter().fold(0, |crc, &byte| {
+            (0..8).fold(crc ^ byte, |crc, _| {
+                if crc & 0x80 == 0 {
+                    crc << 1
+                } else {
+                    (crc << 1) ^ 0x07
+                }
+            })
+        })
+    }
+
+    fn crc16(data: &[u8]) -> u16 {
+        data.iter().fold(0, |crc, &byte| {
+            (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+                if crc & 0x8000 == 0 {
+                    crc << 1
+                } else {
+                    (crc << 1) ^ 0x8005
+                }
+            })
+        })
+    }
+
+    fn samples(frame_count: usize) -> Vec<i16> {
+        (0..frame_count * BLOCK_SIZE)
+            .map(|i| i16::try_from(i * 37 % 2000).unwrap() - 1000)
+            .collect()
+    }
+
+    /// A mono, 16-bit, 44.1 kHz FLAC file with `frame_count` frames of verbatim subframes.
+    /// `total_samples` is written to STREAMINFO, the MD5 signature only when `signed`.
+    fn flac_file(frame_count: usize, total_samples: u64, signed: bool) -> Vec<u8> {
+        let samples = samples(frame_count);
+        let md5sum = if signed {
+            let bytes: Vec<u8> = samples
+                .iter()
+                .flat_map(|sample| sample.to_le_bytes())
+                .collect();
+            md5::compute(bytes).0
+        } else {
+            [0; 16]
+        };
+
+        let mut data = b"fLaC".to_vec();
+        // Last metadata block, STREAMINFO, 34 bytes
+        data.extend_from_slice(&[0x80, 0, 0, 34]);
+        let block_size = u16::try_from(BLOCK_SIZE).unwrap();
+        data.extend_from_slice(&block_size.to_be_bytes());
+        data.extend_from_slice(&block_size.to_be_bytes());
+        data.extend_from_slice(&[0; 6]);
+        // 20 bits sample rate, 3 bits channels - 1, 5 bits bits per sample - 1, 36 bits samples
+        let packed = (44_100_u64 << 44) | (15 << 36) | total_samples;
+        data.extend_from_slice(&packed.to_be_bytes());
+        data.extend_from_slice(&md5sum);
+
+        for (i, block) in samples.chunks(BLOCK_SIZE).enumerate() {
+            // Fixed block size, 8-bit block size at the end, sample rate from STREAMINFO,
+            // mono, 16 bits per sample
+            let mut frame = vec![0xFF, 0xF8, 0x60, 0x08, u8::try_from(i).unwrap()];
+            frame.push(u8::try_from(BLOCK_SIZE - 1).unwrap());
+            frame.push(crc8(&frame));
+            // Verbatim subframe without wasted bits
+            frame.push(0x02);
+            for sample in block {
+                frame.extend_from_slice(&sample.to_be_bytes());
+            }
+            frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+            data.extend(frame);
+        }
+
+        data
+    }
+
+    fn verify(data: &[u8]) -> Verification {
+        let temp_dir = TempDir::new().unwrap();
+        let filepath = temp_dir.path().join("song.flac");
+        fs::write(&filepath, data).unwrap();
+
+        verify_file(&filepath)
+    }
+
+    fn reason(verification: Verification) -> (&'static str, String) {
+        match verification {
+            Verification::Ok => ("ok", String::new()),
+            Verification::Corrupt(reason) => ("corrupt", reason),
+            Verification::Truncated(reason) => ("truncated", reason),
+            Verification::Unsigned => ("unsigned", String::new()),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_file() {
+        assert_eq!(
+            reason(verify(&flac_file(3, 192, true))),
+            ("ok", String::new())
+        );
+    }
+
+    #[test]
+    fn reports_unsigned_file() {
+        assert_eq!(
+            reason(verify(&flac_file(3, 192, false))),
+            ("unsigned", String::new())
+        );
+    }
+
+    #[test]
+    fn reports_truncated_file() {
+        let data = flac_file(3, 192, true);
+
+        let (kind, _) = reason(verify(&data[..data.len() - 50]));
+        assert_eq!(kind, "truncated");
+    }
+
+    #[test]
+    fn reports_corrupted_frame() {
+        let mut data = flac_file(3, 192, true);
+        let len = data.len();
+        data[len - 40] ^= 0x10;
+
+        let (kind, _) = reason(verify(&data));
+        assert_eq!(kind, "corrupt");
+    }
+
+    #[test]
+    fn reports_md5_mismatch() {
+        let mut data = flac_file(3, 192, true);
+        // The last byte of the MD5 signature in STREAMINFO
+        data[41] ^= 0x01;
+
+        assert_eq!(
+            reason(verify(&data)),
+            ("corrupt", String::from("MD5 signature mismatch"))
+        );
+    }
+
+    #[test]
+    fn reports_sample_count_mismatches() {
+        assert_eq!(
+            reason(verify(&flac_file(2, 192, true))),
+            ("truncated", String::from("Decoded 128 of 192 samples"))
+        );
+        assert_eq!(
+            reason(verify(&flac_file(3, 100, true))),
+            (
+                "corrupt",
+                String::from("Decoded 192 samples, STREAMINFO only has 100")
+            )
+        );
+    }
+}